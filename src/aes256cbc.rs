@@ -5,9 +5,10 @@ This library provides user-friendly utilities for performing AES-256-CBC operati
 
 Currently, supports:
 
-- key derivation with password
-- encryption
-- decryption
+- key derivation with password (PBKDF2-HMAC-SHA256, Argon2id or scrypt)
+- authenticated encryption (encrypt-then-MAC) with a random IV per operation
+- decryption of the legacy (password-derived IV) format, which has no
+  authentication tag, only when explicitly requested with `Key::decrypt_untagged`
 
 # Example
 
@@ -19,14 +20,14 @@ use tomb::aes256cbc::{Key, Config};
 let config = Config::from_vec(&[100, 200, 300]);
 
 let password = String::from("I <3 Nickelback");
-let key = Key::from_password(&password, &config);
+let key = Key::from_password(&password, &config).expect("key derivation failed");
 
 let plaintext = b"Some secret information";
 let cyphertext = key.encrypt(plaintext).ok().expect("encryption failed");
 
 let decrypted = key.decrypt(&cyphertext).ok().expect("decryption failed");
 
-assert_eq!((*plaintext).to_vec(), decrypted);
+assert_eq!((*plaintext).to_vec(), *decrypted);
 ```
 */
 
@@ -37,25 +38,53 @@ use crate::{
     config::{YamlFile, YamlFileError},
     ioutils::{b64decode, b64encode},
     logger,
+    migrations::{
+        identity_format_version, key_format_version, migrate_yaml, DocumentKind,
+        IDENTITY_FORMAT_VERSION, KEY_FORMAT_VERSION,
+    },
+    sensitive::{Sensitive, SensitiveBytes},
 };
 
 use console::style;
 use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::sha2::Sha256;
-use crypto::{aes, blockmodes, buffer, pbkdf2};
+use crypto::{aes, blockmodes, buffer, pbkdf2, scrypt};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use shellexpand;
 
 use std::borrow::Borrow;
 use std::io::Read;
+use std::rc::Rc;
 use std::{fmt, fs::File};
+use zeroize::Zeroize;
 
 const ALGO: &'static str = "aes-256-cbc";
 const DIGEST_SIZE: usize = 32;
 
+/// Prefix of every versioned ciphertext, followed by a single version byte.
+///
+/// Ciphertexts without this prefix are treated as legacy: the digest
+/// followed by data encrypted with the IV stored in the key, which are
+/// only decrypted by `Key::decrypt_untagged`.
+pub const CIPHERTEXT_MAGIC: &[u8; 3] = b"TMB";
+/// Ciphertext format: `magic | version | digest | iv | aes-256-cbc(data) | tag`
+///
+/// The tag is a HMAC-SHA256 of everything that precedes it, computed
+/// with the mac key (encrypt-then-MAC). It is the only versioned format,
+/// any other version is refused as tampered with.
+pub const CIPHERTEXT_V2: u8 = 2;
+/// Context of the X25519 secret derived from the mac key of a key
+const AGREEMENT_SECRET_INFO: &[u8] = b"tomb x25519 secret";
+/// Context of the HKDF expansion of an X25519 shared secret into a key
+const AGREEMENT_KEY_INFO: &[u8] = b"tomb x25519 key";
+const HEADER_SIZE: usize = CIPHERTEXT_MAGIC.len() + 1 + DIGEST_SIZE + IV_SIZE;
+const TAG_SIZE: usize = 32;
+
 ///The path used by `Key::default()` and `Config::default()`
 pub const TOMB_KEY: &'static str = "~/.tomb.key";
 
@@ -66,6 +95,16 @@ pub fn default_key_filename() -> String {
     }
 }
 
+///The path of the `Identity` attached to keys loaded by the cli
+pub const TOMB_IDENTITY: &str = "~/.tomb.identity";
+
+pub fn default_identity_filename() -> String {
+    match std::env::var("TOMB_IDENTITY") {
+        Ok(filename) => String::from(shellexpand::tilde(&filename)),
+        Err(_err) => String::from(TOMB_IDENTITY),
+    }
+}
+
 ///The builtin number of cycles for a key derivation
 pub const KEY_CYCLES: u32 = 16000;
 ///The builtin number of cycles for a salt derivation
@@ -73,25 +112,76 @@ pub const SALT_CYCLES: u32 = 16000;
 ///The builtin number of cycles for an iv derivation
 pub const IV_CYCLES: u32 = 16000;
 
-const KEY_SIZE: usize = 0xff;
+///The builtin memory cost of Argon2id in KiB
+pub const ARGON2_MEMORY: u32 = 65536;
+///The builtin number of Argon2id iterations
+pub const ARGON2_ITERATIONS: u32 = 3;
+///The builtin degree of Argon2id parallelism
+pub const ARGON2_PARALLELISM: u32 = 4;
+
+///The builtin scrypt cost parameter, as log2(N)
+pub const SCRYPT_LOG_N: u8 = 15;
+///The builtin scrypt block size
+pub const SCRYPT_R: u32 = 8;
+///The builtin scrypt parallelization parameter
+pub const SCRYPT_P: u32 = 1;
+
+/// Size of the AES-256 encryption key
+pub const AES_KEY_SIZE: usize = 32;
+/// Size of the HMAC-SHA256 key
+pub const MAC_KEY_SIZE: usize = 32;
+/// Size of the key material derived for configs with a random salt
+const KEY_MATERIAL_SIZE: usize = AES_KEY_SIZE + MAC_KEY_SIZE;
+/// Size of the key material derived for configs without salt, and of the
+/// `key` and `mac` fields of keys created before they were fixed-size.
+const LEGACY_KEY_MATERIAL_SIZE: usize = 0xff;
+const LEGACY_KEY_FIELD_SIZE: usize = 127;
+/// Legacy keys used only the first 16 bytes of the mac field
+const LEGACY_MAC_KEY_SIZE: usize = 16;
 const IV_SIZE: usize = 16;
+/// Size of X25519 secrets and public keys
+const X25519_SIZE: usize = 32;
+const SALT_SIZE: usize = 32;
 const BLOCK_SIZE: usize = 4096;
 
 pub type Digest = [u8; DIGEST_SIZE];
 
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum ErrorKind {
+    #[default]
+    Generic,
+    /// The authentication tag of a cyphertext is missing or does not match
+    /// its contents
+    Tampered,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Error {
     pub message: String,
+    #[serde(default)]
+    pub kind: ErrorKind,
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.message)
     }
 }
+impl Error {
+    pub fn tampered(message: String) -> Error {
+        Error {
+            message: logger::paint::error(format!("{}", message)),
+            kind: ErrorKind::Tampered,
+        }
+    }
+    pub fn is_tampered(&self) -> bool {
+        self.kind == ErrorKind::Tampered
+    }
+}
 impl YamlFileError for Error {
     fn with_message(message: String) -> Error {
         Error {
             message: logger::paint::error(format!("{}", message)),
+            kind: ErrorKind::Generic,
         }
     }
 }
@@ -122,12 +212,26 @@ pub fn hmac_256_digest(mac_key: &[u8], iv: &[u8]) -> Result<Digest, Error> {
 }
 
 /// Generates a random KEY;
-pub fn generate_key() -> [u8; KEY_SIZE] {
+pub fn generate_key() -> [u8; AES_KEY_SIZE] {
     let mut rng = rand::thread_rng();
-    let mut key: [u8; KEY_SIZE] = [0; KEY_SIZE];
+    let mut key: [u8; AES_KEY_SIZE] = [0; AES_KEY_SIZE];
     rng.fill_bytes(&mut key);
     key
 }
+/// Generates a random MAC key;
+pub fn generate_mac_key() -> [u8; MAC_KEY_SIZE] {
+    let mut rng = rand::thread_rng();
+    let mut key: [u8; MAC_KEY_SIZE] = [0; MAC_KEY_SIZE];
+    rng.fill_bytes(&mut key);
+    key
+}
+/// Generates a random salt for key derivation;
+pub fn generate_salt() -> [u8; SALT_SIZE] {
+    let mut rng = rand::thread_rng();
+    let mut salt: [u8; SALT_SIZE] = [0; SALT_SIZE];
+    rng.fill_bytes(&mut salt);
+    salt
+}
 /// Generates a random IV;
 pub fn generate_iv() -> [u8; IV_SIZE] {
     let mut rng = rand::thread_rng();
@@ -159,13 +263,96 @@ impl CyclesConfig {
     }
 }
 
+/// The key derivation function used by `Key::from_password`.
+///
+/// `Pbkdf2` uses the cycles of the `Config`, the memory-hard functions
+/// carry their own parameters.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum Kdf {
+    #[default]
+    Pbkdf2,
+    Argon2id {
+        /// memory cost in KiB
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+impl Kdf {
+    /// Argon2id with the builtin parameters
+    pub fn argon2id() -> Kdf {
+        Kdf::Argon2id {
+            memory: ARGON2_MEMORY,
+            iterations: ARGON2_ITERATIONS,
+            parallelism: ARGON2_PARALLELISM,
+        }
+    }
+    /// scrypt with the builtin parameters
+    pub fn scrypt() -> Kdf {
+        Kdf::Scrypt {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kdf::Pbkdf2 => "pbkdf2",
+            Kdf::Argon2id { .. } => "argon2id",
+            Kdf::Scrypt { .. } => "scrypt",
+        }
+    }
+}
+
+/// Serializes optional bytes as base64, rejecting invalid base64 on import
+mod base64_option {
+    use crate::ioutils::{b64decode, b64encode};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&b64encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(encoded) => match b64decode(encoded.as_bytes()) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(error) => Err(D::Error::custom(error)),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
 /// The configuration for the Key.
 ///
-/// It contains the cycles for key, salt and iv used in key derivation.
+/// It contains the key derivation function, the cycles for key, salt
+/// and iv used in PBKDF2 derivation and the random salt generated when
+/// the tomb was initialized.
+///
+/// Configs without a salt belong to tombs created before random salts
+/// existed, their salt is derived from the password itself.
 #[derive(PartialEq, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub cycles: CyclesConfig,
     pub default_key_path: Option<String>,
+    #[serde(default, with = "base64_option")]
+    pub salt: Option<Vec<u8>>,
+    #[serde(default)]
+    pub kdf: Kdf,
 }
 
 impl YamlFile<Error> for Config {
@@ -179,6 +366,8 @@ impl Config {
         Config {
             cycles: CyclesConfig::from_vec(vec),
             default_key_path: None,
+            salt: None,
+            kdf: Kdf::Pbkdf2,
         }
     }
     /// Creates a new builtin config
@@ -190,8 +379,27 @@ impl Config {
                 salt: SALT_CYCLES,
                 iv: IV_CYCLES,
             },
+            salt: None,
+            kdf: Kdf::Pbkdf2,
         }
     }
+    /// Returns a copy of this config using the given key derivation function
+    pub fn with_kdf(&self, kdf: Kdf) -> Config {
+        let mut config = self.clone();
+        config.kdf = kdf;
+        config
+    }
+    /// Returns a copy of this config with a freshly generated random salt
+    pub fn with_random_salt(&self) -> Config {
+        let mut config = self.clone();
+        config.salt = Some(generate_salt().to_vec());
+        config
+    }
+    /// Whether the salt is derived from the password (tombs created
+    /// before random salts existed)
+    pub fn has_legacy_salt(&self) -> bool {
+        self.salt.is_none()
+    }
 
     pub fn iv_cycles(&self) -> u32 {
         self.cycles.iv
@@ -205,15 +413,63 @@ impl Config {
         self.cycles.salt
     }
 
-    pub fn derive_key(&self, password: &str, salt: &[u8]) -> [u8; KEY_SIZE] {
-        let mut dk = [0u8; KEY_SIZE]; // derived key
-        let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
-        pbkdf2::pbkdf2(&mut mac, salt, self.key_cycles(), &mut dk);
-        dk
+    /// Fills `dk` with key material derived from a password with the configured `Kdf`
+    pub fn derive_key(&self, password: &str, salt: &[u8], dk: &mut [u8]) -> Result<(), Error> {
+        match self.kdf {
+            Kdf::Pbkdf2 => {
+                let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+                pbkdf2::pbkdf2(&mut mac, salt, self.key_cycles(), dk);
+            }
+            Kdf::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                let params = match argon2::Params::new(memory, iterations, parallelism, None) {
+                    Ok(params) => params,
+                    Err(error) => {
+                        return Err(Error::with_message(format!(
+                            "invalid argon2id parameters: {}",
+                            error
+                        )))
+                    }
+                };
+                let argon2 = argon2::Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    params,
+                );
+                if let Err(error) = argon2.hash_password_into(password.as_bytes(), salt, dk) {
+                    return Err(Error::with_message(format!(
+                        "argon2id key derivation failed: {}",
+                        error
+                    )));
+                }
+            }
+            Kdf::Scrypt { log_n, r, p } => {
+                // rust-crypto panics on parameters outside of these bounds
+                let (cost, r64, p64) = (log_n as u64, r as u64, p as u64);
+                if cost == 0
+                    || cost > 31
+                    || r64 == 0
+                    || p64 == 0
+                    || cost >= r64 * 16
+                    || r64 * p64 >= 0x40000000
+                {
+                    return Err(Error::with_message(format!(
+                        "invalid scrypt parameters: log_n={} r={} p={}",
+                        log_n, r, p
+                    )));
+                }
+                let params = scrypt::ScryptParams::new(log_n, r, p);
+                scrypt::scrypt(password.as_bytes(), salt, &params, dk);
+            }
+        }
+        Ok(())
     }
 
-    pub fn derive_salt(&self, password: &str) -> [u8; KEY_SIZE] {
-        let mut dk = [0u8; KEY_SIZE]; // derived key
+    pub fn derive_salt(&self, password: &str) -> [u8; LEGACY_KEY_MATERIAL_SIZE] {
+        let mut dk = [0u8; LEGACY_KEY_MATERIAL_SIZE]; // derived key
         let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
         pbkdf2::pbkdf2(&mut mac, password.as_bytes(), self.salt_cycles(), &mut dk);
         dk
@@ -227,114 +483,718 @@ impl Config {
     }
 }
 
-/// AES-256 Key data
+/// Derives a key from the X25519 shared secret of `secret` and
+/// `public_key`
+fn x25519_agree(secret: &[u8], public_key: &str) -> Result<Key, Error> {
+    let public = match b64decode(public_key.as_bytes()) {
+        Ok(public) if public.len() == X25519_SIZE => public,
+        _ => {
+            return Err(Error::with_message(format!(
+                "{}{}",
+                style("invalid public key: ").color256(colors::ERR_MSG),
+                style(public_key).color256(colors::ERR_VAR),
+            )))
+        }
+    };
+    let shared = Sensitive::new(curve25519(secret, &public));
+    if shared.iter().all(|byte| *byte == 0) {
+        return Err(Error::with_message(format!(
+            "{}{}",
+            style("public key of low order: ").color256(colors::ERR_MSG),
+            style(public_key).color256(colors::ERR_VAR),
+        )));
+    }
+    let mut prk = Sensitive::new([0u8; DIGEST_SIZE]);
+    hkdf_extract(Sha256::new(), &[], &*shared, &mut *prk);
+    let mut material = Sensitive::new([0u8; KEY_MATERIAL_SIZE + IV_SIZE]);
+    hkdf_expand(Sha256::new(), &*prk, AGREEMENT_KEY_INFO, &mut *material);
+    Ok(Key {
+        format_version: KEY_FORMAT_VERSION,
+        key: Some(AesKey::from_slice(&material[..AES_KEY_SIZE], "key")?),
+        mac: Some(MacKey::from_slice(
+            &material[AES_KEY_SIZE..KEY_MATERIAL_SIZE],
+            "mac",
+        )?),
+        iv: Some(Iv::from_slice(&material[KEY_MATERIAL_SIZE..], "iv")?),
+        algo: String::from(ALGO),
+        magic: None,
+        kdf: None,
+        wrapped: None,
+        identity: None,
+    })
+}
+
+/// An X25519 keypair identifying its holder as a recipient of tombs,
+/// independently of their password and `Key`
+#[derive(PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(default = "identity_format_version")]
+    pub format_version: u32,
+    /// base64 X25519 public key, shared with the members of tombs
+    pub recipient: String,
+    #[serde(deserialize_with = "key_material::x25519_secret")]
+    pub secret: X25519Secret,
+}
+
+impl YamlFile<Error> for Identity {
+    fn document_kind() -> Option<DocumentKind> {
+        Some(DocumentKind::Identity)
+    }
+    fn is_private() -> bool {
+        true
+    }
+    /// Deserializes an identity and checks that its recipient matches
+    /// its secret
+    fn from_yaml<'a>(data: String) -> Result<Identity, Error> {
+        let data = Sensitive::new(data);
+        let migrated = match migrate_yaml(DocumentKind::Identity, &data) {
+            Ok(migrated) => migrated.map(Sensitive::new),
+            Err(error) => return Err(Error::with_message(format!("{}", error))),
+        };
+        let data = match &migrated {
+            Some(migrated) => migrated.as_str(),
+            None => data.as_str(),
+        };
+        let identity = match serde_yaml::from_str::<Identity>(data) {
+            Ok(identity) => identity,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "failed to deserialize yaml identity: {}",
+                    error
+                )))
+            }
+        };
+        if identity.recipient != identity.public_key() {
+            return Err(Error::with_message(String::from(
+                "the recipient of the identity does not match its secret",
+            )));
+        }
+        Ok(identity)
+    }
+    fn default() -> Result<Identity, Error> {
+        Identity::import(&default_identity_filename())
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("recipient", &self.recipient)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Identity {
+    /// Generates a new keypair
+    pub fn generate() -> Identity {
+        let mut rng = rand::thread_rng();
+        let mut secret = X25519Secret::new([0u8; X25519_SIZE]);
+        rng.fill_bytes(&mut secret.0);
+        let recipient = b64encode(&curve25519_base(secret.as_bytes()));
+        Identity {
+            format_version: IDENTITY_FORMAT_VERSION,
+            recipient,
+            secret,
+        }
+    }
+    /// The public key computed from the secret
+    pub fn public_key(&self) -> String {
+        b64encode(&curve25519_base(self.secret.as_bytes()))
+    }
+    /// Derives the key shared with the holder of `public_key`, see
+    /// `Key::agree`
+    pub fn agree(&self, public_key: &str) -> Result<Key, Error> {
+        x25519_agree(self.secret.as_bytes(), public_key)
+    }
+}
+
+/// Fixed-size key material, serialized as base64 and wiped from
+/// memory when dropped
+#[derive(PartialEq)]
+pub struct KeyMaterial<const N: usize>([u8; N]);
+
+/// The AES-256 encryption key
+pub type AesKey = KeyMaterial<AES_KEY_SIZE>;
+/// The HMAC-SHA256 key used for digests and authentication tags
+pub type MacKey = KeyMaterial<MAC_KEY_SIZE>;
+/// The IV used for legacy cyphertexts and key digests
+pub type Iv = KeyMaterial<IV_SIZE>;
+/// The secret half of an X25519 `Identity`
+pub type X25519Secret = KeyMaterial<X25519_SIZE>;
+
+impl<const N: usize> KeyMaterial<N> {
+    pub fn new(bytes: [u8; N]) -> KeyMaterial<N> {
+        KeyMaterial(bytes)
+    }
+    /// Copies exactly `N` bytes, or fails with a descriptive error
+    pub fn from_slice(bytes: &[u8], name: &str) -> Result<KeyMaterial<N>, Error> {
+        match bytes.try_into() {
+            Ok(bytes) => Ok(KeyMaterial(bytes)),
+            Err(_) => Err(Error::with_message(format!(
+                "invalid {} size: expected {} bytes, got {}",
+                name,
+                N,
+                bytes.len()
+            ))),
+        }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+impl<const N: usize> Serialize for KeyMaterial<N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&Sensitive::new(b64encode(&self.0)))
+    }
+}
+impl<const N: usize> Drop for KeyMaterial<N> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+impl<const N: usize> fmt::Debug for KeyMaterial<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyMaterial<{}>([REDACTED])", N)
+    }
+}
+
+/// Validates the size of `key`, `mac` and `iv` when importing a key.
+///
+/// Empty strings are accepted as `None` for wrapped keys, and the 127
+/// byte fields of legacy keys are reduced to the bytes they actually
+/// used: the first 32 of `key` and the first 16 of `mac`. The latter
+/// is zero-padded, which yields the same HMAC as the 16 byte key.
+mod key_material {
+    use super::{Iv, KeyMaterial, LEGACY_KEY_FIELD_SIZE, LEGACY_MAC_KEY_SIZE};
+    use crate::ioutils::b64decode;
+    use crate::sensitive::{Sensitive, SensitiveBytes};
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    fn decode<'de, D: Deserializer<'de>>(
+        deserializer: D,
+        name: &str,
+    ) -> Result<Option<SensitiveBytes>, D::Error> {
+        let encoded = Option::<String>::deserialize(deserializer)?.map(Sensitive::new);
+        match encoded {
+            Some(encoded) if !encoded.is_empty() => match b64decode(encoded.as_bytes()) {
+                Ok(bytes) => Ok(Some(Sensitive::new(bytes))),
+                Err(error) => Err(D::Error::custom(format!(
+                    "parse base64 {}: {}",
+                    name, error
+                ))),
+            },
+            _ => Ok(None),
+        }
+    }
+    fn convert<const N: usize, E: Error>(bytes: &[u8], name: &str) -> Result<KeyMaterial<N>, E> {
+        KeyMaterial::from_slice(bytes, name).map_err(|error| E::custom(error.message))
+    }
+
+    pub fn aes_key<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<super::AesKey>, D::Error> {
+        match decode(deserializer, "key")? {
+            Some(bytes) if bytes.len() == LEGACY_KEY_FIELD_SIZE => {
+                Ok(Some(convert(&bytes[..super::AES_KEY_SIZE], "key")?))
+            }
+            Some(bytes) => Ok(Some(convert(&bytes, "key")?)),
+            None => Ok(None),
+        }
+    }
+    pub fn mac_key<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<super::MacKey>, D::Error> {
+        match decode(deserializer, "mac")? {
+            Some(bytes) if bytes.len() == LEGACY_KEY_FIELD_SIZE => {
+                let mut padded = [0u8; super::MAC_KEY_SIZE];
+                padded[..LEGACY_MAC_KEY_SIZE].copy_from_slice(&bytes[..LEGACY_MAC_KEY_SIZE]);
+                Ok(Some(KeyMaterial::new(padded)))
+            }
+            Some(bytes) => Ok(Some(convert(&bytes, "mac")?)),
+            None => Ok(None),
+        }
+    }
+    pub fn x25519_secret<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<super::X25519Secret, D::Error> {
+        match decode(deserializer, "secret")? {
+            Some(bytes) => convert(&bytes, "secret"),
+            None => Err(D::Error::custom("missing identity secret")),
+        }
+    }
+    pub fn iv<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Iv>, D::Error> {
+        match decode(deserializer, "iv")? {
+            Some(bytes) => Ok(Some(convert(&bytes, "iv")?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Key material encrypted under a password-derived key-encryption key
 #[derive(PartialEq, Serialize, Deserialize, Clone)]
+pub struct WrappedKey {
+    /// kdf, cycles and salt used to derive the key-encryption key
+    pub config: Config,
+    /// base64 of the encrypted yaml of the plain key
+    pub data: String,
+}
+
+/// Key shared by reference-counting between the components that need it
+pub type SharedKey = Rc<Key>;
+
+/// AES-256 Key data
+///
+/// A wrapped key carries no `key`, `mac` and `iv` until it is
+/// unwrapped with `Key::unwrap_with_password`.
+///
+/// The key material is wiped from memory on drop, so `Key` is not
+/// `Clone`: share it by reference, or behind an `Rc`.
+#[derive(PartialEq, Serialize, Deserialize)]
 pub struct Key {
+    #[serde(default = "key_format_version")]
+    pub format_version: u32,
     pub algo: String,
-    pub key: String,
-    pub mac: String,
-    pub iv: String,
+    #[serde(default, deserialize_with = "key_material::aes_key")]
+    pub key: Option<AesKey>,
+    #[serde(default, deserialize_with = "key_material::mac_key")]
+    pub mac: Option<MacKey>,
+    #[serde(default, deserialize_with = "key_material::iv")]
+    pub iv: Option<Iv>,
     pub magic: Option<Vec<u32>>,
+    #[serde(default)]
+    pub kdf: Option<Kdf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped: Option<WrappedKey>,
+    /// Attached by `Key::with_identity`, identifies the holder of the
+    /// key as a recipient of tombs instead of `Key::public_key`
+    #[serde(skip)]
+    pub identity: Option<Identity>,
 }
 
 impl YamlFile<Error> for Key {
+    fn document_kind() -> Option<DocumentKind> {
+        Some(DocumentKind::Key)
+    }
+    fn is_private() -> bool {
+        true
+    }
+    /// Deserializes a key and validates the size of its key material
+    fn from_yaml<'a>(data: String) -> Result<Key, Error> {
+        Key::parse_yaml(&Sensitive::new(data))
+    }
     fn default() -> Result<Key, Error> {
         let filename = default_key_filename();
         Key::import(filename.borrow())
     }
 }
 
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("algo", &self.algo)
+            .field("kdf", &self.kdf)
+            .field("wrapped", &self.is_wrapped())
+            .finish_non_exhaustive()
+    }
+}
+
 impl Key {
-    /// Derive a key from a password using the cycles from the given config
-    pub fn from_password(password: &str, config: &Config) -> Key {
+    fn parse_yaml(data: &str) -> Result<Key, Error> {
+        let migrated = match migrate_yaml(DocumentKind::Key, data) {
+            Ok(migrated) => migrated.map(Sensitive::new),
+            Err(error) => return Err(Error::with_message(format!("{}", error))),
+        };
+        let data = match &migrated {
+            Some(migrated) => migrated.as_str(),
+            None => data,
+        };
+        match serde_yaml::from_str::<Key>(data) {
+            Ok(key) => key.validate(),
+            Err(error) => Err(Error::with_message(format!(
+                "failed to deserialize yaml key: {}",
+                error
+            ))),
+        }
+    }
+    /// Derive a key from a password using the kdf, cycles and salt from the given config
+    ///
+    /// Configs without a random salt keep the legacy layout: the
+    /// encryption key and the mac key are taken from 255 bytes of
+    /// derived material, so that existing tombs remain readable.
+    pub fn from_password(password: &str, config: &Config) -> Result<Key, Error> {
         let iv = config.derive_iv(password);
-        let salt = config.derive_salt(password);
-        //let salt = generate_iv();
-        let key_material = config.derive_key(password, &salt);
-
-        let enc_key = &key_material[0..127];
-        let mac_key = &key_material[128..255];
+        let (key, mac) = match &config.salt {
+            Some(salt) => {
+                let mut material = Sensitive::new([0u8; KEY_MATERIAL_SIZE]);
+                config.derive_key(password, salt, &mut *material)?;
+                (
+                    AesKey::from_slice(&material[..AES_KEY_SIZE], "key")?,
+                    MacKey::from_slice(&material[AES_KEY_SIZE..], "mac")?,
+                )
+            }
+            None => {
+                let mut material = Sensitive::new([0u8; LEGACY_KEY_MATERIAL_SIZE]);
+                let salt = Sensitive::new(config.derive_salt(password));
+                config.derive_key(password, &*salt, &mut *material)?;
+                let offset = LEGACY_KEY_FIELD_SIZE + 1;
+                let mut mac = MacKey::new([0u8; MAC_KEY_SIZE]);
+                mac.0[..LEGACY_MAC_KEY_SIZE]
+                    .copy_from_slice(&material[offset..offset + LEGACY_MAC_KEY_SIZE]);
+                (AesKey::from_slice(&material[..AES_KEY_SIZE], "key")?, mac)
+            }
+        };
 
-        Key {
-            key: b64encode(&enc_key),
-            mac: b64encode(&mac_key),
-            iv: b64encode(&iv),
+        Ok(Key {
+            format_version: KEY_FORMAT_VERSION,
+            key: Some(key),
+            mac: Some(mac),
+            iv: Some(Iv::new(iv)),
             algo: String::from(ALGO),
             magic: Some(config.cycles.to_vec()),
-        }
+            kdf: Some(config.kdf),
+            wrapped: None,
+            identity: None,
+        })
     }
     /// Generate a new key
     pub fn generate() -> Key {
-        let iv = generate_iv();
-        let key_material = generate_key();
-        let enc_key = &key_material[0..127];
-        let mac_key = &key_material[128..255];
-
         Key {
-            key: b64encode(&enc_key),
-            mac: b64encode(&mac_key),
-            iv: b64encode(&iv),
+            format_version: KEY_FORMAT_VERSION,
+            key: Some(AesKey::new(generate_key())),
+            mac: Some(MacKey::new(generate_mac_key())),
+            iv: Some(Iv::new(generate_iv())),
             algo: String::from(ALGO),
             magic: None,
+            kdf: None,
+            wrapped: None,
+            identity: None,
         }
     }
     /// Checks if a file is encrypted with this key
     pub fn owns_file(&self, filename: &str) -> Result<bool, Error> {
-        let mut fd =
-            File::open(filename).expect(format!("failed to open file {}", filename).as_str());
-        let mut buffer = [0; DIGEST_SIZE];
-        match fd.read(&mut buffer) {
-            Ok(_) => {}
+        let mut fd = match File::open(filename) {
+            Ok(fd) => fd,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "{}{}{}",
+                    style("failed to open file ").color256(colors::ERR_MSG),
+                    style(filename).color256(colors::ERR_VAR),
+                    style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
+                )))
+            }
+        };
+        let mut buffer = [0; HEADER_SIZE];
+        let count = match fd.read(&mut buffer) {
+            Ok(count) => count,
             Err(error) => {
                 return Err(Error::with_message(format!(
                     "{}{}{}",
-                    style("reading the first {:?} bytes from file ").color256(colors::ERR_MSG),
+                    style(format!(
+                        "reading the first {:?} bytes from file ",
+                        HEADER_SIZE
+                    ))
+                    .color256(colors::ERR_MSG),
                     style(filename).color256(colors::ERR_VAR),
                     style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
                 )))
             }
         };
 
-        Ok(self.check_digest(&buffer))
+        Ok(self.owns(&buffer[..count]))
+    }
+    /// Checks if a ciphertext was produced by this key, in either format
+    pub fn owns(&self, cyphertext: &[u8]) -> bool {
+        match parse_header(cyphertext, &self.digest()) {
+            Ok(header) => self.check_digest(&header.digest),
+            Err(_) => false,
+        }
     }
     /// Checks the digest of the given bytes
     pub fn check_digest(&self, buffer: &Digest) -> bool {
-        let digest = self.digest();
-        bytes_match(buffer, &digest)
+        match self.ensure_unwrapped() {
+            Ok(_) => bytes_match(buffer, &self.digest()),
+            Err(_) => false,
+        }
     }
+    /// The HMAC of the IV with the mac key, identifies data encrypted
+    /// with this key. A wrapped key has an all-zero digest.
     pub fn digest(&self) -> Digest {
-        let mac = self.mac_bytes().unwrap();
-        let iv = self.iv_bytes().unwrap();
-        hmac_256_digest(&mac, &iv).unwrap()
+        match (&self.mac, &self.iv) {
+            (Some(mac), Some(iv)) => {
+                hmac_256_digest(mac.as_bytes(), iv.as_bytes()).unwrap_or([0; DIGEST_SIZE])
+            }
+            _ => [0; DIGEST_SIZE],
+        }
+    }
+    /// Whether the key material is encrypted under a password
+    pub fn is_wrapped(&self) -> bool {
+        self.wrapped.is_some()
+    }
+    /// Encrypts this key under a key-encryption key derived from the
+    /// password with the kdf and salt of the given config
+    pub fn wrap(&self, password: &str, config: &Config) -> Result<Key, Error> {
+        self.ensure_unwrapped()?;
+        let config = config.with_random_salt();
+        let kek = Key::from_password(password, &config)?;
+        let data = kek.encrypt(Sensitive::new(self.to_yaml()?).as_bytes())?;
+        Ok(Key {
+            format_version: KEY_FORMAT_VERSION,
+            algo: self.algo.clone(),
+            key: None,
+            mac: None,
+            iv: None,
+            magic: None,
+            kdf: None,
+            wrapped: Some(WrappedKey {
+                config,
+                data: b64encode(&data),
+            }),
+            identity: None,
+        })
+    }
+    /// Decrypts the key material of a wrapped key
+    pub fn unwrap_with_password(&self, password: &str) -> Result<Key, Error> {
+        let wrapped = match &self.wrapped {
+            Some(wrapped) => wrapped,
+            None => {
+                return Err(Error::with_message(String::from(
+                    "key is not wrapped, there is nothing to unwrap",
+                )))
+            }
+        };
+        let kek = Key::from_password(password, &wrapped.config)?;
+        let data = match b64decode(wrapped.data.as_bytes()) {
+            Ok(data) => data,
+            Err(e) => {
+                return Err(Error::with_message(format!(
+                    "parse base64 wrapped key: {}",
+                    e
+                )))
+            }
+        };
+        let yaml = match kek.decrypt(&data) {
+            Ok(yaml) => yaml,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "cannot unwrap key, wrong password? {}",
+                    error
+                )))
+            }
+        };
+        match yaml.into_string() {
+            Ok(yaml) => Key::parse_yaml(&yaml),
+            Err(error) => Err(Error::with_message(format!(
+                "wrapped key is not valid utf-8: {}",
+                error
+            ))),
+        }
+    }
+    /// The X25519 secret of this key, derived from its mac key
+    fn agreement_secret(&self) -> Result<Sensitive<Digest>, Error> {
+        Ok(Sensitive::new(hmac_256_digest(
+            self.mac_bytes()?,
+            AGREEMENT_SECRET_INFO,
+        )?))
+    }
+    /// The base64 X25519 public key of this key, which can be shared
+    /// for others to derive a key with `Key::agree`
+    pub fn public_key(&self) -> Result<String, Error> {
+        let secret = self.agreement_secret()?;
+        Ok(b64encode(&curve25519_base(&*secret)))
+    }
+    /// Derives the key shared between this key and the holder of
+    /// `public_key`, who derives the same key from their own key and
+    /// the `public_key` of this one
+    pub fn agree(&self, public_key: &str) -> Result<Key, Error> {
+        let secret = self.agreement_secret()?;
+        x25519_agree(&*secret, public_key)
+    }
+    pub fn with_identity(mut self, identity: Identity) -> Key {
+        self.identity = Some(identity);
+        self
+    }
+    /// The public key others wrap data for this key with: the one of
+    /// its identity if any, `Key::public_key` otherwise
+    pub fn recipient(&self) -> Result<String, Error> {
+        match &self.identity {
+            Some(identity) => Ok(identity.recipient.clone()),
+            None => self.public_key(),
+        }
+    }
+    /// Ensures that a plain key carries all of its key material
+    pub fn validate(self) -> Result<Key, Error> {
+        if !self.is_wrapped() {
+            self.key_bytes()?;
+            self.mac_bytes()?;
+            self.iv_bytes()?;
+        }
+        Ok(self)
     }
-    pub fn iv_bytes(&self) -> Result<Vec<u8>, Error> {
-        match b64decode(self.iv.as_bytes()) {
-            Ok(v) => Ok(v[..16].to_vec()),
-            Err(e) => Err(Error::with_message(format!("parse base64 iv: {}", e))),
+    fn ensure_unwrapped(&self) -> Result<(), Error> {
+        match self.is_wrapped() {
+            true => Err(Error::with_message(String::from(
+                "key material is wrapped, unwrap it with the password first",
+            ))),
+            false => Ok(()),
         }
     }
-    pub fn key_bytes(&self) -> Result<Vec<u8>, Error> {
-        match b64decode(self.key.as_bytes()) {
-            Ok(v) => Ok(v[..32].to_vec()),
-            Err(e) => Err(Error::with_message(format!("parse base64 key: {}", e))),
+    pub fn iv_bytes(&self) -> Result<&[u8], Error> {
+        self.ensure_unwrapped()?;
+        match &self.iv {
+            Some(iv) => Ok(iv.as_bytes()),
+            None => Err(Error::with_message(String::from("key has no iv"))),
         }
     }
-    pub fn mac_bytes(&self) -> Result<Vec<u8>, Error> {
-        match b64decode(self.mac.as_bytes()) {
-            Ok(v) => Ok(v[..16].to_vec()),
-            Err(e) => Err(Error::with_message(format!("parse base64 mac: {}", e))),
+    pub fn key_bytes(&self) -> Result<&[u8], Error> {
+        self.ensure_unwrapped()?;
+        match &self.key {
+            Some(key) => Ok(key.as_bytes()),
+            None => Err(Error::with_message(String::from(
+                "key has no encryption key",
+            ))),
+        }
+    }
+    pub fn mac_bytes(&self) -> Result<&[u8], Error> {
+        self.ensure_unwrapped()?;
+        match &self.mac {
+            Some(mac) => Ok(mac.as_bytes()),
+            None => Err(Error::with_message(String::from("key has no mac key"))),
         }
     }
 
     /// Encrypt a buffer with the key
-    /// AES-256/CBC/Pkcs encryption.
+    /// AES-256/CBC/Pkcs encryption with a freshly generated IV,
+    /// followed by a HMAC-SHA256 tag over IV and cyphertext.
+    ///
+    /// The IV is stored in the versioned header in front of the
+    /// cyphertext, so encrypting the same plaintext twice never
+    /// produces the same output.
     pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
-        let enc_key = self.key_bytes().unwrap();
-        let iv = self.iv_bytes().unwrap();
+        self.ensure_unwrapped()?;
+        let iv = generate_iv();
+        let mut cyphertext = Vec::<u8>::new();
+        cyphertext.extend_from_slice(CIPHERTEXT_MAGIC);
+        cyphertext.push(CIPHERTEXT_V2);
+        // the digest allows for checking if data was encrypted with
+        // the correct key and take the appropriate action.
+        cyphertext.extend_from_slice(&self.digest());
+        cyphertext.extend_from_slice(&iv);
+        cyphertext.extend(self.cbc_encrypt(data, &iv)?);
+        let tag = hmac_256_digest(&self.mac_bytes()?, &cyphertext)?;
+        cyphertext.extend_from_slice(&tag);
+        Ok(cyphertext)
+    }
+
+    /// Decrypts a buffer with the key
+    /// AES-256/CBC/Pkcs decryption into a buffer that is wiped when
+    /// dropped.
+    ///
+    /// The authentication tag is verified before decrypting, a
+    /// mismatch or a cyphertext without a tag is reported as an error
+    /// of kind `ErrorKind::Tampered`.
+    pub fn decrypt(&self, cyphertext: &[u8]) -> Result<SensitiveBytes, Error> {
+        self.decrypt_header(cyphertext, false)
+    }
+    /// Decrypts like `decrypt`, also accepting cyphertexts produced
+    /// before the versioned header existed, which are decrypted with
+    /// the IV stored in the key and cannot be authenticated. Only for
+    /// values of tombs that predate authentication tags.
+    pub fn decrypt_untagged(&self, cyphertext: &[u8]) -> Result<SensitiveBytes, Error> {
+        self.decrypt_header(cyphertext, true)
+    }
+    /// Whether a cyphertext of this key carries an authentication tag,
+    /// rather than predating the versioned header
+    pub fn is_tagged(&self, cyphertext: &[u8]) -> bool {
+        match parse_header(cyphertext, &self.digest()) {
+            Ok(header) => header.version.is_some(),
+            Err(_) => false,
+        }
+    }
+    /// Encrypts in the legacy layout, `digest | aes-256-cbc(data)` with
+    /// the IV of the key, as values were before authentication tags
+    #[cfg(test)]
+    pub fn encrypt_untagged(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut cyphertext = self.digest().to_vec();
+        cyphertext.extend(self.cbc_encrypt(data, self.iv_bytes()?)?);
+        Ok(cyphertext)
+    }
+    fn decrypt_header(&self, cyphertext: &[u8], untagged: bool) -> Result<SensitiveBytes, Error> {
+        self.ensure_unwrapped()?;
+        let header = parse_header(cyphertext, &self.digest())?;
+        if !self.check_digest(&header.digest) {
+            return Err(Error::with_message(String::from(
+                "Cannot decrypt: data was not encrypted with the provided key. Leaving file as is.",
+            )));
+        }
+        let end =
+            match header.version {
+                Some(_) => {
+                    if cyphertext.len() < header.size + TAG_SIZE {
+                        return Err(Error::tampered(String::from(
+                            "cyphertext too short to contain an authentication tag",
+                        )));
+                    }
+                    let end = cyphertext.len() - TAG_SIZE;
+                    let expected = hmac_256_digest(self.mac_bytes()?, &cyphertext[..end])?;
+                    if !bytes_match(&expected, &cyphertext[end..]) {
+                        return Err(Error::tampered(String::from(
+                            "Cannot decrypt: authentication tag mismatch, data was tampered with.",
+                        )));
+                    }
+                    end
+                }
+                None if untagged => cyphertext.len(),
+                None => return Err(Error::tampered(String::from(
+                    "Cannot decrypt: cyphertext has no authentication tag, data was tampered with.",
+                ))),
+            };
+        let iv = match header.iv {
+            Some(iv) => iv,
+            None => self.iv_bytes()?,
+        };
+        self.cbc_decrypt(&cyphertext[header.size..end], iv)
+    }
+
+    /// Encrypts data with a random IV, authenticating it along with a
+    /// header that the caller stores in the clear.
+    ///
+    /// Returns `iv | cbc(data) | hmac_256_digest(mac, header | iv | cbc(data))`
+    pub fn encrypt_with_header(&self, header: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.ensure_unwrapped()?;
+        let iv = generate_iv();
+        let mut body = iv.to_vec();
+        body.extend(self.cbc_encrypt(data, &iv)?);
+        let mut authenticated = header.to_vec();
+        authenticated.extend_from_slice(&body);
+        body.extend_from_slice(&hmac_256_digest(self.mac_bytes()?, &authenticated)?);
+        Ok(body)
+    }
+    /// Decrypts the output of `encrypt_with_header`, failing with a
+    /// tampering error if either the header or the body were modified
+    pub fn decrypt_with_header(&self, header: &[u8], body: &[u8]) -> Result<SensitiveBytes, Error> {
+        self.ensure_unwrapped()?;
+        if body.len() < IV_SIZE + TAG_SIZE {
+            return Err(Error::tampered(String::from(
+                "cyphertext too short to contain an iv and an authentication tag",
+            )));
+        }
+        let end = body.len() - TAG_SIZE;
+        let mut authenticated = header.to_vec();
+        authenticated.extend_from_slice(&body[..end]);
+        let expected = hmac_256_digest(self.mac_bytes()?, &authenticated)?;
+        if !bytes_match(&expected, &body[end..]) {
+            return Err(Error::tampered(String::from(
+                "Cannot decrypt: authentication tag mismatch, data was tampered with or encrypted with another key.",
+            )));
+        }
+        self.cbc_decrypt(&body[IV_SIZE..end], &body[..IV_SIZE])
+    }
+    fn cbc_encrypt(&self, data: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
+        let enc_key = self.key_bytes()?;
         let mut encryptor = aes::cbc_encryptor(
             aes::KeySize::KeySize256,
             &enc_key,
-            &iv,
+            iv,
             blockmodes::PkcsPadding,
         );
 
@@ -343,12 +1203,6 @@ impl Key {
         let mut buffer = [0; BLOCK_SIZE];
         let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
 
-        // The first 32 bytes of the cyphertext are always the digest,
-        // this allows for checking if a file was encrypted with the
-        // correct key and take the appropriate action.
-        let digest = self.digest();
-        cyphertext.extend_from_slice(&digest);
-
         loop {
             let result = match encryptor.encrypt(&mut read_buffer, &mut write_buffer, true) {
                 Ok(result) => result,
@@ -375,42 +1229,20 @@ impl Key {
         Ok(cyphertext)
     }
 
-    /// Decrypts a buffer with the key
-    /// AES-256/CBC/Pkcs decryption.
-    pub fn decrypt(&self, cyphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    fn cbc_decrypt(&self, ciphertext: &[u8], iv: &[u8]) -> Result<SensitiveBytes, Error> {
+        let dec_key = self.key_bytes()?;
         let mut decryptor = aes::cbc_decryptor(
             aes::KeySize::KeySize256,
-            &match self.key_bytes() {
-                Ok(bytes) => bytes,
-                Err(e) => return Err(e),
-            },
-            &match self.iv_bytes() {
-                Ok(bytes) => bytes,
-                Err(e) => return Err(e),
-            },
+            &dec_key,
+            iv,
             blockmodes::PkcsPadding,
         );
 
-        let mut plaintext = Vec::<u8>::new();
-        let hmac_bytes: Digest = match cyphertext[..DIGEST_SIZE].try_into() {
-            Ok(digest) => digest,
-            Err(error) => {
-                return Err(Error::with_message(format!(
-                    "failed to convert digest to u8: {}",
-                    error
-                )))
-            }
-        };
-        if !self.check_digest(&hmac_bytes) {
-            return Err(Error::with_message(format!(
-                "Cannot decrypt: data was not encrypted with the provided key. Leaving file as is."
-            )));
-        }
-
-        let ciphertext = &cyphertext[DIGEST_SIZE..];
+        // reserved upfront so that growing never leaves copies of the plaintext behind
+        let mut plaintext = Sensitive::new(Vec::<u8>::with_capacity(ciphertext.len()));
+        let mut buffer = Sensitive::new([0; BLOCK_SIZE]);
         let mut read_buffer = buffer::RefReadBuffer::new(&ciphertext);
-        let mut buffer = [0; BLOCK_SIZE];
-        let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
+        let mut write_buffer = buffer::RefWriteBuffer::new(&mut *buffer);
 
         loop {
             let result = match decryptor.decrypt(&mut read_buffer, &mut write_buffer, true) {
@@ -439,22 +1271,345 @@ impl Key {
         Ok(plaintext)
     }
 }
+
+/// The parsed header of a cyphertext
+struct Header<'a> {
+    /// `None` for legacy cyphertexts
+    version: Option<u8>,
+    digest: Digest,
+    /// `None` for legacy cyphertexts, which use the IV of the key
+    iv: Option<&'a [u8]>,
+    /// the number of bytes preceding the encrypted data
+    size: usize,
+}
+
+/// Parses the header of a cyphertext, in the legacy layout unless it
+/// starts with `CIPHERTEXT_MAGIC` followed by the `expected` digest:
+/// legacy digests may happen to start with the magic too
+fn parse_header<'a>(cyphertext: &'a [u8], expected: &Digest) -> Result<Header<'a>, Error> {
+    if !cyphertext.starts_with(CIPHERTEXT_MAGIC) {
+        return parse_legacy_header(cyphertext);
+    }
+    let versioned = parse_versioned_header(cyphertext);
+    match &versioned {
+        Ok(header) if bytes_match(&header.digest, expected) => versioned,
+        _ => match parse_legacy_header(cyphertext) {
+            Ok(header) if bytes_match(&header.digest, expected) => Ok(header),
+            _ => versioned,
+        },
+    }
+}
+fn parse_versioned_header(cyphertext: &[u8]) -> Result<Header<'_>, Error> {
+    let version = match cyphertext.get(CIPHERTEXT_MAGIC.len()) {
+        Some(&CIPHERTEXT_V2) => CIPHERTEXT_V2,
+        Some(version) => {
+            return Err(Error::tampered(format!(
+                "Cannot decrypt: unsupported cyphertext version {}, data was tampered with.",
+                version
+            )))
+        }
+        None => return parse_legacy_header(cyphertext),
+    };
+    check_header_size(cyphertext, HEADER_SIZE)?;
+    let offset = CIPHERTEXT_MAGIC.len() + 1;
+    Ok(Header {
+        version: Some(version),
+        digest: read_digest(&cyphertext[offset..offset + DIGEST_SIZE])?,
+        iv: Some(&cyphertext[offset + DIGEST_SIZE..HEADER_SIZE]),
+        size: HEADER_SIZE,
+    })
+}
+fn parse_legacy_header(cyphertext: &[u8]) -> Result<Header<'_>, Error> {
+    check_header_size(cyphertext, DIGEST_SIZE)?;
+    Ok(Header {
+        version: None,
+        digest: read_digest(&cyphertext[..DIGEST_SIZE])?,
+        iv: None,
+        size: DIGEST_SIZE,
+    })
+}
+fn check_header_size(cyphertext: &[u8], size: usize) -> Result<(), Error> {
+    if cyphertext.len() < size {
+        return Err(Error::with_message(format!(
+            "cyphertext too short: expected at least {} bytes, got {}",
+            size,
+            cyphertext.len()
+        )));
+    }
+    Ok(())
+}
+fn read_digest(bytes: &[u8]) -> Result<Digest, Error> {
+    match bytes.try_into() {
+        Ok(digest) => Ok(digest),
+        Err(error) => Err(Error::with_message(format!(
+            "failed to convert digest to u8: {}",
+            error
+        ))),
+    }
+}
 #[cfg(test)]
 mod tests {
     use crate::aes256cbc::Config;
+    use crate::aes256cbc::Identity;
+    use crate::aes256cbc::Kdf;
     use crate::aes256cbc::Key;
+    use crate::aes256cbc::{parse_header, ErrorKind, CIPHERTEXT_MAGIC};
+    use crate::config::YamlFile;
+    use crate::ioutils::b64encode;
     use k9::assert_equal;
 
     #[test]
     fn test_encrypt_and_decrypt() {
         let config = Config::builtin(None);
         let password = "123456";
-        let key = Key::from_password(password, &config);
+        let key = Key::from_password(password, &config).unwrap();
 
         let plaintext = b"This is a secret";
         let ciphertext = key.encrypt(plaintext).unwrap();
 
         let decrypted = key.decrypt(&ciphertext).unwrap();
-        assert_equal!(decrypted, b"This is a secret");
+        assert_equal!(*decrypted, b"This is a secret");
+    }
+
+    #[test]
+    fn test_encrypt_uses_random_iv() {
+        let config = Config::builtin(None);
+        let key = Key::from_password("123456", &config).unwrap();
+
+        let first = key.encrypt(b"same plaintext").unwrap();
+        let second = key.encrypt(b"same plaintext").unwrap();
+        assert_ne!(first, second);
+
+        assert_equal!(*key.decrypt(&first).unwrap(), b"same plaintext");
+        assert_equal!(*key.decrypt(&second).unwrap(), b"same plaintext");
+    }
+
+    #[test]
+    fn test_decrypt_legacy_cyphertext() {
+        let config = Config::builtin(None);
+        let key = Key::from_password("123456", &config).unwrap();
+
+        // legacy format: digest followed by data encrypted with the IV of the key
+        let legacy = key.encrypt_untagged(b"legacy secret").unwrap();
+
+        assert_equal!(*key.decrypt_untagged(&legacy).unwrap(), b"legacy secret");
+        assert_equal!(key.is_tagged(&legacy), false);
+        assert_equal!(key.decrypt(&legacy).unwrap_err().kind, ErrorKind::Tampered);
+    }
+    #[test]
+    fn test_parse_legacy_header_starting_with_magic() {
+        let mut digest = [7; 32];
+        digest[..3].copy_from_slice(CIPHERTEXT_MAGIC);
+        let mut legacy = digest.to_vec();
+        legacy.extend_from_slice(&[0; 16]);
+
+        let header = parse_header(&legacy, &digest).unwrap();
+        assert_equal!(header.version, None);
+        assert_equal!(header.size, 32);
+        assert_equal!(parse_header(&legacy, &[0; 32]).is_err(), true);
+    }
+
+    #[test]
+    fn test_decrypt_detects_tampering() {
+        let config = Config::builtin(None);
+        let key = Key::from_password("123456", &config).unwrap();
+
+        let mut cyphertext = key.encrypt(b"This is a secret").unwrap();
+        let last = cyphertext.len() - 40;
+        cyphertext[last] ^= 0x01;
+
+        let error = key.decrypt(&cyphertext).unwrap_err();
+        assert_equal!(error.is_tampered(), true);
+    }
+    #[test]
+    fn test_decrypt_rejects_untagged_cyphertext() {
+        let config = Config::builtin(None);
+        let key = Key::from_password("123456", &config).unwrap();
+
+        // a tagged cyphertext rewritten as the untagged version 1
+        let mut cyphertext = key.encrypt(b"This is a secret").unwrap();
+        cyphertext[CIPHERTEXT_MAGIC.len()] = 1;
+        cyphertext.truncate(cyphertext.len() - 32);
+
+        let error = key.decrypt(&cyphertext).unwrap_err();
+        assert_equal!(error.kind, ErrorKind::Tampered);
+
+        // stripped of magic, version and tag it reads as `digest | iv | cbc`
+        let cyphertext = key.encrypt(b"This is a secret").unwrap();
+        let stripped = cyphertext[CIPHERTEXT_MAGIC.len() + 1..cyphertext.len() - 32].to_vec();
+        assert_equal!(key.is_tagged(&stripped), false);
+        let error = key.decrypt(&stripped).unwrap_err();
+        assert_equal!(error.kind, ErrorKind::Tampered);
+    }
+
+    #[test]
+    fn test_random_salt_changes_derived_key() {
+        let config = Config::from_vec(&[100, 100, 100]).with_random_salt();
+        let other = Config::from_vec(&[100, 100, 100]).with_random_salt();
+
+        let key = Key::from_password("123456", &config).unwrap();
+        let same = Key::from_password("123456", &config).unwrap();
+        assert_equal!(key.key == same.key, true);
+        let different = Key::from_password("123456", &other).unwrap();
+        assert_equal!(key.key == different.key, false);
+
+        let legacy = Config::from_vec(&[100, 100, 100]);
+        assert_equal!(legacy.has_legacy_salt(), true);
+        let legacy_key = Key::from_password("123456", &legacy).unwrap();
+        assert_equal!(key.key == legacy_key.key, false);
+    }
+
+    #[test]
+    fn test_memory_hard_kdfs() {
+        let config = Config::from_vec(&[100, 100, 100]).with_random_salt();
+        let argon2id = config.with_kdf(Kdf::Argon2id {
+            memory: 256,
+            iterations: 1,
+            parallelism: 1,
+        });
+        let scrypt = config.with_kdf(Kdf::Scrypt {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        });
+
+        let pbkdf2_key = Key::from_password("123456", &config).unwrap();
+        let argon2id_key = Key::from_password("123456", &argon2id).unwrap();
+        let scrypt_key = Key::from_password("123456", &scrypt).unwrap();
+        assert_equal!(argon2id_key.kdf, Some(argon2id.kdf));
+        assert_equal!(pbkdf2_key.key == argon2id_key.key, false);
+        assert_equal!(argon2id_key.key == scrypt_key.key, false);
+
+        let cyphertext = argon2id_key.encrypt(b"memory hard").unwrap();
+        assert_equal!(*argon2id_key.decrypt(&cyphertext).unwrap(), b"memory hard");
+
+        let invalid = config.with_kdf(Kdf::Argon2id {
+            memory: 0,
+            iterations: 0,
+            parallelism: 0,
+        });
+        assert_equal!(Key::from_password("123456", &invalid).is_err(), true);
+    }
+
+    #[test]
+    fn test_wrap_and_unwrap_key() {
+        let config = Config::from_vec(&[100, 100, 100]);
+        let key = Key::generate();
+        let cyphertext = key.encrypt(b"wrapped").unwrap();
+
+        let wrapped = key.wrap("123456", &config).unwrap();
+        assert_equal!(wrapped.is_wrapped(), true);
+        assert_equal!(wrapped.key.is_none(), true);
+        assert_equal!(wrapped.decrypt(&cyphertext).is_err(), true);
+        assert_equal!(wrapped.unwrap_with_password("654321").is_err(), true);
+
+        let unwrapped = wrapped.unwrap_with_password("123456").unwrap();
+        assert_equal!(unwrapped.is_wrapped(), false);
+        assert_equal!(*unwrapped.decrypt(&cyphertext).unwrap(), b"wrapped");
+    }
+
+    #[test]
+    fn test_key_debug_is_redacted() {
+        let key = Key::generate();
+        let debug = format!("{:?}", key);
+        assert_equal!(debug.contains(&b64encode(key.key_bytes().unwrap())), false);
+        assert_equal!(debug.contains(&b64encode(key.mac_bytes().unwrap())), false);
+        assert_equal!(
+            format!("{:?}", key.key),
+            "Some(KeyMaterial<32>([REDACTED]))"
+        );
+    }
+
+    #[test]
+    fn test_import_legacy_key_sizes() {
+        // legacy keys stored 127 bytes of `key` and `mac` material, of
+        // which only the first 32 and 16 bytes were used respectively
+        let mut legacy_key = [0xaa; 127];
+        let mut legacy_mac = [0xbb; 127];
+        let key = Key::generate();
+        legacy_key[..32].copy_from_slice(key.key_bytes().unwrap());
+        legacy_mac[..16].copy_from_slice(&key.mac_bytes().unwrap()[..16]);
+        let yaml = format!(
+            "algo: {}\nkey: {}\nmac: {}\niv: {}\nmagic: ~\n",
+            key.algo,
+            b64encode(&legacy_key),
+            b64encode(&legacy_mac),
+            b64encode(key.iv_bytes().unwrap()),
+        );
+        let imported = Key::from_yaml(yaml).unwrap();
+        assert_equal!(imported.key == key.key, true);
+        assert_equal!(imported.mac_bytes().unwrap()[..16], legacy_mac[..16]);
+        assert_equal!(imported.mac_bytes().unwrap()[16..], [0; 16]);
+
+        // the zero-padded mac key yields the same digest as the 16 byte one
+        let digest = crate::aes256cbc::hmac_256_digest(&legacy_mac[..16], key.iv_bytes().unwrap());
+        assert_equal!(imported.digest(), digest.unwrap());
+
+        let cyphertext = imported.encrypt(b"legacy key").unwrap();
+        assert_equal!(*imported.decrypt(&cyphertext).unwrap(), b"legacy key");
+    }
+
+    #[test]
+    fn test_import_rejects_invalid_key_sizes() {
+        let key = Key::generate();
+        let short = format!(
+            "algo: {}\nkey: {}\nmac: {}\niv: {}\nmagic: ~\n",
+            key.algo,
+            b64encode(&[0u8; 16]),
+            b64encode(key.mac_bytes().unwrap()),
+            b64encode(key.iv_bytes().unwrap()),
+        );
+        assert_equal!(Key::from_yaml(short).is_err(), true);
+
+        let missing_iv = format!(
+            "algo: {}\nkey: {}\nmac: {}\nmagic: ~\n",
+            key.algo,
+            b64encode(key.key_bytes().unwrap()),
+            b64encode(key.mac_bytes().unwrap()),
+        );
+        assert_equal!(Key::from_yaml(missing_iv).is_err(), true);
+
+        let yaml = key.to_yaml().unwrap();
+        assert_equal!(Key::from_yaml(yaml).unwrap() == key, true);
+    }
+    #[test]
+    fn test_agree() {
+        let alice = Key::generate();
+        let bob = Key::generate();
+        let ours = alice.agree(&bob.public_key().unwrap()).unwrap();
+        let theirs = bob.agree(&alice.public_key().unwrap()).unwrap();
+        assert_equal!(ours == theirs, true);
+        assert_equal!(
+            alice.public_key().unwrap() == bob.public_key().unwrap(),
+            false
+        );
+
+        let cyphertext = ours.encrypt(b"data key").unwrap();
+        assert_equal!(
+            theirs.decrypt(&cyphertext).unwrap().to_vec(),
+            b"data key".to_vec()
+        );
+        assert_equal!(alice.agree("not a public key").is_err(), true);
+        assert_equal!(alice.agree(&b64encode(&[0u8; 32])).is_err(), true);
+    }
+    #[test]
+    fn test_identity() {
+        let identity = Identity::generate();
+        let key = Key::generate();
+        let ours = identity.agree(&key.public_key().unwrap()).unwrap();
+        let theirs = key.agree(&identity.recipient).unwrap();
+        assert_equal!(ours == theirs, true);
+
+        let yaml = identity.to_yaml().unwrap();
+        assert_equal!(Identity::from_yaml(yaml.clone()).unwrap() == identity, true);
+        let other = Identity::generate();
+        let forged = yaml.replace(&identity.recipient, &other.recipient);
+        assert_equal!(Identity::from_yaml(forged).is_err(), true);
+
+        let key = key.with_identity(identity);
+        assert_equal!(
+            key.recipient().unwrap(),
+            key.identity.as_ref().unwrap().recipient
+        );
     }
 }
@@ -27,6 +27,8 @@ use ConfirmationOption::*;
 pub struct ConfirmationDialog<'a> {
     pub question: Option<Vec<Spans<'a>>>,
     pub selected: ConfirmationOption,
+    pub title: String,
+    pub confirm_label: String,
 }
 
 impl<'a> ConfirmationDialog<'a> {
@@ -34,8 +36,16 @@ impl<'a> ConfirmationDialog<'a> {
         ConfirmationDialog {
             question: question,
             selected: No,
+            title: String::from("Delete Secret"),
+            confirm_label: String::from("Yes, delete"),
         }
     }
+    /// Sets the title of the dialog and the label of its "yes" button
+    pub fn with_labels(mut self, title: &str, confirm_label: &str) -> ConfirmationDialog<'a> {
+        self.title = String::from(title);
+        self.confirm_label = String::from(confirm_label);
+        self
+    }
     pub fn toggle_selected(&mut self) {
         self.selected = match self.selected {
             ConfirmationOption::No => Yes,
@@ -70,7 +80,7 @@ impl<'a> Component for ConfirmationDialog<'a> {
         let confirmation = Block::default()
             .borders(Borders::ALL)
             .style(block_style())
-            .title(format!("Delete Secret"))
+            .title(self.title.clone())
             .border_type(BorderType::Thick);
 
         let (top, bottom) = vertical_split(chunk);
@@ -90,7 +100,7 @@ impl<'a> Component for ConfirmationDialog<'a> {
             .wrap(Wrap { trim: false });
 
         let button_yes = Paragraph::new(vec![Spans::from(Span::styled(
-            format!("Yes, delete"),
+            self.confirm_label.clone(),
             match self.selected {
                 Yes => ui::default_style()
                     .bg(Color::LightGreen)
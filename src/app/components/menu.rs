@@ -55,6 +55,7 @@ impl Menu {
     pub fn default() -> Menu {
         let mut menu = Menu::empty();
         menu.add_item("Secrets", KeyCode::Char('S'), "/");
+        menu.add_item("Trash", KeyCode::Char('T'), "/trash");
         menu.add_item("Help", KeyCode::Char('H'), "/help");
         menu.add_item("Configuration", KeyCode::Char('C'), "/config");
         menu.add_item("About", KeyCode::Char('A'), "/about");
@@ -271,8 +272,12 @@ impl Component for Menu {
                 for (label, item) in self.items.iter() {
                     let label = label.clone();
                     if item.code == code {
+                        let route_path = item.route_path.clone();
                         match self.select(&label) {
-                            Ok(_) => return Ok(Refresh),
+                            Ok(_) => {
+                                context.borrow_mut().goto(&route_path);
+                                return Ok(Refresh);
+                            }
                             Err(error) => {
                                 log_error(format!("Menu.process_keyboard(): {}", error));
                                 return Ok(Quit);
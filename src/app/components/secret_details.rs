@@ -12,8 +12,9 @@ use crate::app::ui;
 use crate::ironpunk::*;
 
 extern crate clipboard;
-use crate::aes256cbc::Key;
+use crate::aes256cbc::SharedKey;
 use crate::app::{AES256Secret, AES256Tomb, TombConfig};
+use crate::sensitive::SensitiveString;
 
 use crossterm::event::{KeyCode, KeyEvent};
 
@@ -25,7 +26,7 @@ use tui::{
 };
 
 pub struct SecretDetails<'a> {
-    key: Key,
+    key: SharedKey,
     tomb: AES256Tomb,
     secret: Option<AES256Secret>,
     tomb_config: TombConfig,
@@ -36,7 +37,7 @@ pub struct SecretDetails<'a> {
 
 impl<'a> SecretDetails<'a> {
     pub fn new(
-        key: Key,
+        key: SharedKey,
         tomb: AES256Tomb,
         secret: Option<AES256Secret>,
         tomb_config: TombConfig,
@@ -112,13 +113,13 @@ impl<'a> SecretDetails<'a> {
             None => Err(Error::with_message(format!("no secret selected"))),
         }
     }
-    pub fn get_plaintext(&mut self, secret: &AES256Secret) -> Result<String, Error> {
-        match self.tomb.get_string(secret.path.as_str(), self.key.clone()) {
+    pub fn get_plaintext(&mut self, secret: &AES256Secret) -> Result<SensitiveString, Error> {
+        match self.tomb.get_string(secret.path.as_str(), &self.key) {
             Ok(secret) => Ok(secret),
             Err(err) => return Err(Error::with_message(format!("{}", err))),
         }
     }
-    pub fn selected_secret_string(&mut self) -> Result<String, Error> {
+    pub fn selected_secret_string(&mut self) -> Result<SensitiveString, Error> {
         match self.selected_secret() {
             Ok(secret) => self.get_plaintext(&secret),
             Err(err) => Err(err),
@@ -176,6 +177,28 @@ impl<'a> SecretDetails<'a> {
             true,
             true,
         );
+        let field_history = TextField::new(
+            "history",
+            "history",
+            match secret.history.len() {
+                0 => String::from("<none>"),
+                _ => secret
+                    .history
+                    .iter()
+                    .enumerate()
+                    .map(|(index, previous)| {
+                        format!(
+                            "{}: {}",
+                            index + 1,
+                            chrono_humanize::HumanTime::from(previous.updated_at)
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            },
+            true,
+            true,
+        );
 
         self.form.add_field(field_name);
         self.form.add_field(field_group);
@@ -183,6 +206,7 @@ impl<'a> SecretDetails<'a> {
         self.form.add_field(field_username);
         self.form.add_field(field_url);
         self.form.add_field(field_updated_at);
+        self.form.add_field(field_history);
         self.form.add_field(field_digest);
         self.form.add_field(field_notes);
     }
@@ -1,13 +1,16 @@
 use super::logging::*;
 use crate::aes256cbc::default_key_filename;
 use crate::core::version;
-use crate::tomb::default_tomb_filename;
+use crate::tomb::generator::{Policy, DEFAULT_POLICY, PASSPHRASE_POLICY};
+use crate::tomb::{default_history_retention, default_tomb_filename};
 use crate::{
     config::{YamlFile, YamlFileError},
     logger,
+    migrations::{config_format_version, DocumentKind, CONFIG_FORMAT_VERSION},
 };
 use serde::{Deserialize, Serialize};
 use shellexpand;
+use std::collections::BTreeMap;
 use std::fmt;
 
 pub const TOMB_CONFIG: &'static str = "~/.tomb.config.yaml";
@@ -69,14 +72,26 @@ impl ColorTheme {
 }
 #[derive(PartialEq, Clone, Serialize, Deserialize)]
 pub struct TombConfig {
+    #[serde(default = "config_format_version")]
+    pub format_version: u32,
     pub colors: ColorTheme,
     pub key_filename: String,
     pub tomb_filename: String,
     pub log_filename: String,
     pub version: Option<String>,
+    /// How many previous values of each secret are kept
+    #[serde(default = "default_history_retention")]
+    pub history_retention: usize,
+    /// Named policies of `tomb generate`, which override the builtin
+    /// `default` and `passphrase` ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub policies: BTreeMap<String, Policy>,
 }
 
 impl YamlFile<Error> for TombConfig {
+    fn document_kind() -> Option<DocumentKind> {
+        Some(DocumentKind::Config)
+    }
     fn default() -> Result<TombConfig, Error> {
         TombConfig::import(default_tomb_config_filename().as_str())
     }
@@ -91,11 +106,14 @@ impl TombConfig {
         colors: ColorTheme,
     ) -> TombConfig {
         TombConfig {
+            format_version: CONFIG_FORMAT_VERSION,
             version: Some(version()),
             key_filename: key_filename.to_string(),
             tomb_filename: tomb_filename.to_string(),
             log_filename: log_filename.to_string(),
             colors,
+            history_retention: default_history_retention(),
+            policies: BTreeMap::new(),
         }
     }
     pub fn builtin() -> TombConfig {
@@ -109,6 +127,26 @@ impl TombConfig {
     pub fn load() -> TombConfig {
         TombConfig::default().unwrap_or(TombConfig::builtin())
     }
+    /// The named generator policy, configured or builtin
+    pub fn policy(&self, name: &str) -> Option<Policy> {
+        match self.policies.get(name) {
+            Some(policy) => Some(policy.clone()),
+            None if name == DEFAULT_POLICY => Some(Policy::builtin()),
+            None if name == PASSPHRASE_POLICY => Some(Policy::passphrase(6)),
+            None => None,
+        }
+    }
+    /// The names of the configured and builtin generator policies
+    pub fn policy_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.policies.keys().cloned().collect();
+        for builtin in [DEFAULT_POLICY, PASSPHRASE_POLICY] {
+            if !self.policies.contains_key(builtin) {
+                names.push(String::from(builtin));
+            }
+        }
+        names.sort();
+        names
+    }
     pub fn set_colors(&mut self, colors: ColorTheme) {
         self.colors = colors.clone();
     }
@@ -2,8 +2,9 @@
 #![allow(dead_code)]
 use super::super::super::ui::*;
 use super::text_field::TextField;
-use crate::aes256cbc::Key;
+use crate::aes256cbc::SharedKey;
 use crate::app::log_error;
+use crate::sensitive::SensitiveString;
 use crate::tomb::{AES256Secret, AES256Tomb};
 
 use crate::ironpunk::*;
@@ -23,7 +24,7 @@ pub struct SecretField {
     pub field: TextField,
     pub secret: Option<AES256Secret>,
     pub tomb: AES256Tomb,
-    pub key: Key,
+    pub key: SharedKey,
     pub visible: bool,
 }
 
@@ -35,7 +36,7 @@ impl SecretField {
         visible: bool,
         secret: Option<AES256Secret>,
         tomb: AES256Tomb,
-        key: Key,
+        key: SharedKey,
     ) -> SecretField {
         SecretField {
             field: TextField::new(id, title, String::new(), read_only, visible),
@@ -61,13 +62,13 @@ impl SecretField {
         self.field.backspace()
     }
 
-    pub fn get_plaintext(&mut self) -> Result<String, Error> {
+    pub fn get_plaintext(&mut self) -> Result<SensitiveString, Error> {
         let secret = match &self.secret {
             Some(secret) => secret.clone(),
             None => return Err(Error::with_message(format!("no secret selected"))),
         };
 
-        match self.tomb.get_string(secret.path.as_str(), self.key.clone()) {
+        match self.tomb.get_string(secret.path.as_str(), &self.key) {
             Ok(secret) => Ok(secret),
             Err(err) => return Err(Error::with_message(format!("{}", err))),
         }
@@ -167,7 +168,7 @@ impl Field for SecretField {
 
         match self
             .tomb
-            .add_secret(path.as_str(), plaintext.clone(), self.key.clone())
+            .add_secret(path.as_str(), plaintext.clone(), &self.key)
         {
             Ok(_) => {
                 if self.visible {
@@ -191,7 +192,7 @@ impl Field for SecretField {
             return secret.value.clone();
         }
         match self.get_plaintext() {
-            Ok(plaintext) => plaintext.clone(),
+            Ok(plaintext) => plaintext.to_string(),
             Err(error) => {
                 log_error(format!(
                     "error setting plaintext value into field {}",
@@ -172,6 +172,6 @@ impl Component for Form {
 pub fn vertical_stack(size: Rect, constraints: Vec<Constraint>) -> Vec<Rect> {
     Layout::default()
         .direction(Direction::Vertical)
-        .constraints(constraints.as_ref())
+        .constraints(constraints.as_slice())
         .split(size)
 }
@@ -12,7 +12,7 @@ pub use components::{menu::Menu, modal::Modal, searchbox::SearchBox};
 
 pub use {application::*, geometry::*, logging::*, routes::*, state::*};
 
-use crate::aes256cbc::{Config as AesConfig, Key};
+use crate::aes256cbc::{Config as AesConfig, Key, SharedKey};
 
 use crate::tomb::{AES256Secret, AES256Tomb};
 pub use config::TombConfig;
@@ -25,6 +25,7 @@ pub fn start(
     aes_config: AesConfig,
     tick_interval: u64,
 ) -> Result<(), ironpunk::SharedError> {
+    let key: SharedKey = Rc::new(key);
     let mut router = ironpunk::SharedRouter::new();
     let menu = Rc::new(RefCell::new(Menu::default()));
 
@@ -52,6 +53,22 @@ pub fn start(
             aes_config.clone(),
         ))),
     );
+    router.add(
+        "/generate/:key",
+        Rc::new(RefCell::new(GenerateSecret::new(
+            key.clone(),
+            tomb.clone(),
+            tomb_config.clone(),
+        ))),
+    );
+    router.add(
+        "/new",
+        Rc::new(RefCell::new(NewSecret::new(
+            key.clone(),
+            tomb.clone(),
+            tomb_config.clone(),
+        ))),
+    );
     router.add(
         "/edit/:key",
         Rc::new(RefCell::new(EditSecret::new(
@@ -60,6 +77,14 @@ pub fn start(
             tomb_config.clone(),
         ))),
     );
+    router.add(
+        "/trash",
+        Rc::new(RefCell::new(Trash::new(
+            menu.clone(),
+            key.clone(),
+            tomb.clone(),
+        ))),
+    );
     router.add(
         "/",
         Rc::new(RefCell::new(Application::new(
@@ -17,7 +17,8 @@ use mac_notification_sys::*;
 
 extern crate clipboard;
 use super::super::{AES256Secret, AES256Tomb, TombConfig};
-use crate::aes256cbc::{Config as AesConfig, Key};
+use crate::aes256cbc::{Config as AesConfig, SharedKey};
+use crate::sensitive::SensitiveString;
 
 use clipboard::{ClipboardContext, ClipboardProvider};
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
@@ -40,7 +41,7 @@ pub enum FocusedComponent {
 }
 
 pub struct Application<'a> {
-    key: Key,
+    key: SharedKey,
     tomb: AES256Tomb,
     aes_config: AesConfig,
     tomb_config: TombConfig,
@@ -62,7 +63,7 @@ pub struct Application<'a> {
 impl<'a> Application<'a> {
     pub fn new(
         menu: SharedMenu,
-        key: Key,
+        key: SharedKey,
         tomb: AES256Tomb,
         tomb_config: TombConfig,
         aes_config: AesConfig,
@@ -146,7 +147,7 @@ impl<'a> Application<'a> {
             .collect();
 
         let pattern = self.searchbox.pattern.clone();
-        match self.tomb.reload() {
+        match self.tomb.reload_with_key(&self.key) {
             // load latest version from disk
             Ok(_) => {}
             Err(e) => {
@@ -196,13 +197,13 @@ impl<'a> Application<'a> {
             None => Err(Error::with_message(format!("no secret selected"))),
         }
     }
-    pub fn get_plaintext(&mut self, secret: &AES256Secret) -> Result<String, Error> {
-        match self.tomb.get_string(secret.path.as_str(), self.key.clone()) {
+    pub fn get_plaintext(&mut self, secret: &AES256Secret) -> Result<SensitiveString, Error> {
+        match self.tomb.get_string(secret.path.as_str(), &self.key) {
             Ok(secret) => Ok(secret),
             Err(err) => return Err(Error::with_message(format!("{}", err))),
         }
     }
-    pub fn selected_secret_string(&mut self) -> Result<String, Error> {
+    pub fn selected_secret_string(&mut self) -> Result<SensitiveString, Error> {
         match self.selected_secret() {
             Ok(secret) => self.get_plaintext(&secret),
             Err(err) => Err(err),
@@ -366,6 +367,20 @@ impl Component for Application<'_> {
                             "cannot delete: no secret selected"
                         ))),
                     },
+                    KeyCode::Char('g') => match self.items.current() {
+                        Some(secret) => {
+                            let path = format!("/generate/{}", secret.key());
+                            context.borrow_mut().goto(&path);
+                            Ok(Propagate)
+                        }
+                        None => Err(Error::with_message(format!(
+                            "cannot generate: no secret selected"
+                        ))),
+                    },
+                    KeyCode::Char('n') => {
+                        context.borrow_mut().goto("/new");
+                        Ok(Refresh)
+                    }
                     KeyCode::Char('A') => {
                         context.borrow_mut().goto("/about");
                         Ok(Refresh)
@@ -374,6 +389,10 @@ impl Component for Application<'_> {
                         context.borrow_mut().goto("/config");
                         Ok(Refresh)
                     }
+                    KeyCode::Char('T') => {
+                        context.borrow_mut().goto("/trash");
+                        Ok(Refresh)
+                    }
                     KeyCode::Char('H') | KeyCode::Char('?') => {
                         context.borrow_mut().goto("/help");
                         Ok(Refresh)
@@ -392,7 +411,7 @@ impl Component for Application<'_> {
                                 self.reset_statusbar();
                                 self.set_visible(true);
                                 self.set_pinned(false);
-                                self.set_text(&format!("Secret: {}", plaintext));
+                                self.set_text(&format!("Secret: {}", plaintext.as_str()));
                             }
                             Err(error) => {
                                 log_error(format!("cannot reveal secret: {}", error));
@@ -418,7 +437,7 @@ impl Component for Application<'_> {
                         Some(secret) => match self.selected_secret_string() {
                             Ok(plaintext) => {
                                 let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                                ctx.set_contents(plaintext).unwrap();
+                                ctx.set_contents(plaintext.to_string()).unwrap();
                                 log_error(format!("copied secret to clipboard: {:?}", secret.path));
                                 let text = format!("{:?} copied to clipboard", secret.path);
                                 self.set_text(&text);
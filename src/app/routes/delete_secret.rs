@@ -2,7 +2,7 @@ use super::super::components::confirmation::{
     paragraph_style, ConfirmationDialog, ConfirmationOption,
 };
 use crate::aes256cbc::Config as AesConfig;
-use crate::aes256cbc::Key;
+use crate::aes256cbc::SharedKey;
 
 // use crate::config::YamlFile;
 use crate::app::ui::*;
@@ -18,7 +18,7 @@ use tui::{backend::CrosstermBackend, Terminal};
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct DeleteSecret<'a> {
-    key: Key,
+    key: SharedKey,
     secret_path: Option<String>,
     tomb: AES256Tomb,
     aes_config: AesConfig,
@@ -30,7 +30,7 @@ pub struct DeleteSecret<'a> {
 
 impl<'a> DeleteSecret<'a> {
     pub fn new(
-        key: Key,
+        key: SharedKey,
         tomb: AES256Tomb,
         tomb_config: TombConfig,
         aes_config: AesConfig,
@@ -75,11 +75,17 @@ impl<'a> DeleteSecret<'a> {
         secret: AES256Secret,
     ) -> Result<LoopEvent, Error> {
         let path = secret.path.clone();
+        // other routes and processes may have saved since this route
+        // cloned the tomb, saving a stale tomb is rejected
+        if let Err(err) = self.tomb.reload_with_key(&self.key) {
+            log_error(format!("error deleting secret {}: {}", path, err));
+            return Ok(Quit);
+        }
 
         match self.tomb.delete_secret(&path) {
-            Ok(_) => match self.tomb.save() {
+            Ok(_) => match self.tomb.save_with_key(&self.key) {
                 Ok(_) => {
-                    log_error(format!("deleted secret: {}", path));
+                    log_error(format!("moved secret to trash: {}", path));
                     context.borrow_mut().goto("/");
                     Ok(Propagate)
                 }
@@ -1,7 +1,7 @@
 use super::super::geometry::*;
 use super::super::ui;
 use super::super::{AES256Tomb, TombConfig};
-use crate::aes256cbc::Key;
+use crate::aes256cbc::SharedKey;
 
 use crate::ironpunk::*;
 
@@ -18,14 +18,14 @@ use tui::{
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct EditSecret<'a> {
-    key: Key,
+    key: SharedKey,
     tomb: AES256Tomb,
     tomb_config: TombConfig,
     phantom: PhantomData<&'a Option<()>>,
 }
 
 impl<'a> EditSecret<'a> {
-    pub fn new(key: Key, tomb: AES256Tomb, tomb_config: TombConfig) -> EditSecret<'a> {
+    pub fn new(key: SharedKey, tomb: AES256Tomb, tomb_config: TombConfig) -> EditSecret<'a> {
         EditSecret {
             key,
             tomb,
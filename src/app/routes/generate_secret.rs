@@ -0,0 +1,211 @@
+use super::super::components::confirmation::{
+    paragraph_style, ConfirmationDialog, ConfirmationOption,
+};
+use crate::aes256cbc::SharedKey;
+use crate::app::ui::*;
+use crate::app::TombConfig;
+use crate::ironpunk::*;
+use crate::tomb::generator::DEFAULT_POLICY;
+use crate::tomb::{AES256Secret, AES256Tomb};
+
+use super::super::logging::log_error;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::{io, marker::PhantomData};
+use tui::{backend::CrosstermBackend, Terminal};
+
+#[derive(Clone)]
+pub struct GenerateSecret<'a> {
+    key: SharedKey,
+    secret_path: Option<String>,
+    tomb: AES256Tomb,
+    tomb_config: TombConfig,
+    phantom: PhantomData<&'a Option<()>>,
+    dialog: ConfirmationDialog<'a>,
+}
+
+impl<'a> GenerateSecret<'a> {
+    pub fn new(key: SharedKey, tomb: AES256Tomb, tomb_config: TombConfig) -> GenerateSecret<'a> {
+        GenerateSecret {
+            key,
+            tomb,
+            tomb_config,
+            secret_path: None,
+            phantom: PhantomData,
+            dialog: ConfirmationDialog::new(None).with_labels("Generate Secret", "Yes, generate"),
+        }
+    }
+    pub fn get_secret(
+        &mut self,
+        context: SharedContext,
+        router: SharedRouter,
+    ) -> Option<AES256Secret> {
+        let path = context.borrow().location.clone();
+        match router.recognize(path.as_str()) {
+            Ok(matched) => {
+                let params = matched.params();
+                match params.find("key") {
+                    Some(key) => self.tomb.data.get(key).cloned(),
+                    None => None,
+                }
+            }
+            Err(err) => {
+                log_error(err.to_string());
+                None
+            }
+        }
+    }
+    /// The policy the secret was generated with, or the default one
+    fn policy_name(&self, secret: &AES256Secret) -> String {
+        match secret.generator_policy() {
+            Some((name, _)) => name,
+            None => String::from(DEFAULT_POLICY),
+        }
+    }
+    fn generate_secret(
+        &mut self,
+        context: SharedContext,
+        secret: AES256Secret,
+    ) -> Result<LoopEvent, Error> {
+        let path = secret.path.clone();
+        // other routes and processes may have saved since this route
+        // cloned the tomb, saving a stale tomb is rejected
+        if let Err(err) = self.tomb.reload_with_key(&self.key) {
+            log_error(format!("error generating secret {}: {}", path, err));
+            return Ok(Quit);
+        }
+        let (name, policy) = match secret.generator_policy() {
+            Some(recorded) => recorded,
+            None => (
+                String::from(DEFAULT_POLICY),
+                self.tomb_config.policy(DEFAULT_POLICY).unwrap(),
+            ),
+        };
+        match self.tomb.generate_secret(&path, &name, &policy, &self.key) {
+            Ok(_) => match self.tomb.save_with_key(&self.key) {
+                Ok(_) => {
+                    log_error(format!("generated secret: {}", path));
+                    context.borrow_mut().goto("/");
+                    Ok(Propagate)
+                }
+                Err(err) => {
+                    log_error(format!("error generating secret {}: {}", path, err));
+                    Ok(Quit)
+                }
+            },
+            Err(err) => {
+                log_error(format!("error generating secret {}: {}", path, err));
+                Ok(Quit)
+            }
+        }
+    }
+}
+
+impl Component for GenerateSecret<'_> {
+    fn name(&self) -> &str {
+        "GenerateSecret"
+    }
+    fn id(&self) -> String {
+        match self.secret_path.clone() {
+            None => panic!("GenerateSecret route did not receive the secret_path"),
+            Some(path) => format!("GenerateSecret:{}", path),
+        }
+    }
+    fn render_in_parent(
+        &mut self,
+        rect: &mut Frame<CrosstermBackend<io::Stdout>>,
+        chunk: Rect,
+    ) -> Result<(), Error> {
+        self.dialog.render_in_parent(rect, chunk)
+    }
+
+    #[allow(unused_variables)]
+    fn process_keyboard(
+        &mut self,
+        event: KeyEvent,
+        terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+        context: SharedContext,
+        router: SharedRouter,
+    ) -> Result<LoopEvent, Error> {
+        self.dialog
+            .process_keyboard(event, terminal, context.clone(), router.clone())?;
+        let path = context.borrow().location.clone();
+        match event.code {
+            KeyCode::Esc => {
+                context.borrow_mut().goback();
+                Ok(Propagate)
+            }
+            KeyCode::Enter => match self.dialog.choice() {
+                ConfirmationOption::Yes => match self.get_secret(context.clone(), router.clone()) {
+                    Some(secret) => self.generate_secret(context.clone(), secret),
+                    None => Err(Error::with_message(format!(
+                        "failed to retrieve secret: {}",
+                        path
+                    ))),
+                },
+                ConfirmationOption::No => {
+                    log_error(format!("canceled generation of secret {}", path));
+                    context.borrow_mut().goback();
+                    Ok(Refresh)
+                }
+            },
+            _ => {
+                if event.modifiers == KeyModifiers::CONTROL && event.code == KeyCode::Char('q') {
+                    return Ok(Quit);
+                }
+                Ok(Propagate)
+            }
+        }
+    }
+}
+impl Route for GenerateSecret<'_> {
+    fn render(
+        &mut self,
+        terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+        context: SharedContext,
+        router: SharedRouter,
+    ) -> Result<(), Error> {
+        match self.get_secret(context.clone(), router.clone()) {
+            Some(secret) => {
+                let policy = format!("with the {} policy?", self.policy_name(&secret));
+                self.dialog.set_question(Some(vec![
+                    Spans::from(vec![Span::styled(
+                        "Replace the value of the secret",
+                        paragraph_style(),
+                    )]),
+                    Spans::from(vec![Span::styled(
+                        secret.path.clone(),
+                        paragraph_style().fg(color_blurred()),
+                    )]),
+                    Spans::from(vec![Span::styled(
+                        "with a generated one, keeping it in its history,",
+                        paragraph_style(),
+                    )]),
+                    Spans::from(vec![Span::styled(policy, paragraph_style())]),
+                ]))?;
+            }
+            None => {
+                self.dialog
+                    .set_question(Some(vec![Spans::from(vec![Span::styled(
+                        "could not retrieve secret",
+                        paragraph_style(),
+                    )])]))?;
+            }
+        };
+
+        terminal.draw(|parent| {
+            let chunk = parent.size();
+            match self.render_in_parent(parent, chunk) {
+                Ok(_) => (),
+                Err(err) => {
+                    log(format!(
+                        "error rendering component {}: {}",
+                        self.name(),
+                        err
+                    ));
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+}
@@ -86,10 +86,18 @@ Keyboard Shortcuts:
     't' toggle visibility
     'r' reveal
     'c' copy to clipboard
+    'd' move to trash
+    'g' replace with a generated value
+    'n' create a secret with a generated value
+
+  Trash
+  ~~~~~
+    'r' or 'enter' restore the selected secret
 
   Screens
   ~~~~~~~
     'H' or '?' show this help screen
+    'T' show trash screen
     'C' show configuration screen
     'A' show about screen
     'left' and 'right' move between screens
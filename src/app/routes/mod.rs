@@ -8,5 +8,11 @@ pub mod delete_secret;
 pub use delete_secret::*;
 pub mod edit_secret;
 pub use edit_secret::*;
+pub mod generate_secret;
+pub use generate_secret::*;
+pub mod new_secret;
+pub use new_secret::*;
+pub mod trash;
+pub use trash::*;
 pub mod application;
 pub use application::*;
@@ -0,0 +1,223 @@
+use super::super::components::confirmation::paragraph_style;
+use crate::aes256cbc::SharedKey;
+use crate::app::ui::*;
+use crate::app::TombConfig;
+use crate::ironpunk::*;
+use crate::tomb::generator::DEFAULT_POLICY;
+use crate::tomb::AES256Tomb;
+
+use super::super::logging::log_error;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::{io, marker::PhantomData};
+use tui::{
+    backend::CrosstermBackend,
+    layout::Alignment,
+    widgets::{Block, BorderType, Borders, Paragraph, Wrap},
+    Terminal,
+};
+
+const COMPONENT_NAME: &str = "NewSecret";
+const DEFAULT_STATUS: &str = "press 'enter' to generate and store the secret";
+
+/// Form creating a secret with a value generated by one of the
+/// policies of the config, as `tomb generate` does
+#[derive(Clone)]
+pub struct NewSecret<'a> {
+    key: SharedKey,
+    tomb: AES256Tomb,
+    tomb_config: TombConfig,
+    path: String,
+    policies: Vec<String>,
+    policy: usize,
+    text: String,
+    phantom: PhantomData<&'a Option<()>>,
+}
+
+impl<'a> NewSecret<'a> {
+    pub fn new(key: SharedKey, tomb: AES256Tomb, tomb_config: TombConfig) -> NewSecret<'a> {
+        let policies = tomb_config.policy_names();
+        let policy = policies
+            .iter()
+            .position(|name| name == DEFAULT_POLICY)
+            .unwrap_or(0);
+        NewSecret {
+            key,
+            tomb,
+            tomb_config,
+            path: String::new(),
+            policies,
+            policy,
+            text: String::from(DEFAULT_STATUS),
+            phantom: PhantomData,
+        }
+    }
+    fn policy_name(&self) -> String {
+        self.policies[self.policy].clone()
+    }
+    fn next_policy(&mut self) {
+        self.policy = (self.policy + 1) % self.policies.len();
+    }
+    fn previous_policy(&mut self) {
+        self.policy = (self.policy + self.policies.len() - 1) % self.policies.len();
+    }
+    /// Clears the form before leaving, so that it is empty when opened again
+    fn close(&mut self, context: SharedContext) {
+        self.path.clear();
+        self.text = String::from(DEFAULT_STATUS);
+        context.borrow_mut().goto("/");
+    }
+    fn generate_secret(&mut self, context: SharedContext) -> Result<LoopEvent, Error> {
+        let path = self.path.trim().to_string();
+        if path.is_empty() {
+            self.text = String::from("the path of the secret cannot be empty");
+            return Ok(Refresh);
+        }
+        // other routes and processes may have saved since this route
+        // cloned the tomb, saving a stale tomb is rejected
+        if let Err(err) = self.tomb.reload_with_key(&self.key) {
+            log_error(format!("error generating secret {}: {}", path, err));
+            return Ok(Quit);
+        }
+        if self.tomb.get(&path).is_ok() {
+            self.text = format!(
+                "{} already exists, press 'g' on it to replace its value",
+                path
+            );
+            return Ok(Refresh);
+        }
+        let name = self.policy_name();
+        let policy = match self.tomb_config.policy(&name) {
+            Some(policy) => policy,
+            None => {
+                self.text = format!("unknown policy: {}", name);
+                return Ok(Refresh);
+            }
+        };
+        let generated = match self.tomb.generate_secret(&path, &name, &policy, &self.key) {
+            Ok(_) => self.tomb.save_with_key(&self.key),
+            Err(err) => Err(err),
+        };
+        match generated {
+            Ok(_) => {
+                log_error(format!("generated secret: {}", path));
+                self.close(context);
+                Ok(Refresh)
+            }
+            Err(err) => {
+                log_error(format!("error generating secret {}: {}", path, err));
+                self.text = format!("cannot generate secret: {}", err);
+                Ok(Refresh)
+            }
+        }
+    }
+}
+
+impl Component for NewSecret<'_> {
+    fn name(&self) -> &str {
+        COMPONENT_NAME
+    }
+    fn id(&self) -> String {
+        String::from(COMPONENT_NAME)
+    }
+    fn render_in_parent(
+        &mut self,
+        rect: &mut Frame<CrosstermBackend<io::Stdout>>,
+        chunk: Rect,
+    ) -> Result<(), Error> {
+        let chunk = get_modal_rect(chunk);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .style(paragraph_style())
+            .title("New Secret (<Esc> to cancel)")
+            .border_type(BorderType::Thick);
+        let name = self.policy_name();
+        let entropy = match self.tomb_config.policy(&name) {
+            Some(policy) => format!("{:.0} bits", policy.entropy()),
+            None => String::from("unknown"),
+        };
+        let form = Paragraph::new(vec![
+            Spans::from(vec![Span::styled("Path", paragraph_style())]),
+            Spans::from(vec![Span::styled(
+                format!("{}_", self.path),
+                paragraph_style().fg(color_blurred()),
+            )]),
+            Spans::from(vec![Span::raw("")]),
+            Spans::from(vec![Span::styled(
+                "Policy ('up' and 'down' to choose)",
+                paragraph_style(),
+            )]),
+            Spans::from(vec![Span::styled(
+                format!("{} ({})", name, entropy),
+                paragraph_style().fg(color_blurred()),
+            )]),
+            Spans::from(vec![Span::raw("")]),
+            Spans::from(vec![Span::styled(self.text.clone(), paragraph_style())]),
+        ])
+        .block(block)
+        .style(paragraph_style())
+        .alignment(Alignment::Left)
+        .wrap(Wrap { trim: false });
+        rect.render_widget(form, chunk);
+        Ok(())
+    }
+
+    #[allow(unused_variables)]
+    fn process_keyboard(
+        &mut self,
+        event: KeyEvent,
+        terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+        context: SharedContext,
+        router: SharedRouter,
+    ) -> Result<LoopEvent, Error> {
+        if event.modifiers == KeyModifiers::CONTROL && event.code == KeyCode::Char('q') {
+            return Ok(Quit);
+        }
+        match event.code {
+            KeyCode::Esc => {
+                self.close(context);
+                Ok(Refresh)
+            }
+            KeyCode::Enter => self.generate_secret(context),
+            KeyCode::Up => {
+                self.previous_policy();
+                Ok(Refresh)
+            }
+            KeyCode::Down | KeyCode::Tab => {
+                self.next_policy();
+                Ok(Refresh)
+            }
+            KeyCode::Backspace => {
+                self.path.pop();
+                Ok(Refresh)
+            }
+            KeyCode::Char(c) => {
+                self.path.push(c);
+                Ok(Refresh)
+            }
+            _ => Ok(Propagate),
+        }
+    }
+}
+impl Route for NewSecret<'_> {
+    fn render(
+        &mut self,
+        terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+        _context: SharedContext,
+        _router: SharedRouter,
+    ) -> Result<(), Error> {
+        terminal.draw(|parent| {
+            let chunk = parent.size();
+            match self.render_in_parent(parent, chunk) {
+                Ok(_) => (),
+                Err(err) => {
+                    log_error(format!(
+                        "error rendering component {}: {}",
+                        self.name(),
+                        err
+                    ));
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+#![allow(unused_variables)]
+
+use super::super::components::menu::SharedMenu;
+use super::super::geometry::*;
+use super::super::logging::log_error;
+use super::super::ui;
+use super::application::status_paragraph;
+
+use crate::aes256cbc::SharedKey;
+use crate::app::StatefulList;
+use crate::ironpunk::*;
+use crate::tomb::AES256Tomb;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::{io, marker::PhantomData};
+use tui::{
+    backend::CrosstermBackend,
+    text::{Span, Spans},
+    widgets::{Block, BorderType, Borders, List, ListItem},
+    Terminal,
+};
+
+const COMPONENT_NAME: &str = "Trash";
+const DEFAULT_STATUS: &str = "press 'r' or 'enter' to restore the selected secret";
+
+pub struct Trash<'a> {
+    key: SharedKey,
+    tomb: AES256Tomb,
+    menu: SharedMenu,
+    items: StatefulList,
+    text: String,
+    phantom: PhantomData<&'a Option<()>>,
+}
+
+impl<'a> Trash<'a> {
+    pub fn new(menu: SharedMenu, key: SharedKey, tomb: AES256Tomb) -> Trash<'a> {
+        Trash {
+            key,
+            tomb,
+            menu,
+            items: StatefulList::empty(),
+            text: String::from(DEFAULT_STATUS),
+            phantom: PhantomData,
+        }
+    }
+    fn refresh_items(&mut self) {
+        match self.tomb.reload_with_key(&self.key) {
+            // load latest version from disk
+            Ok(_) => {}
+            Err(e) => {
+                log_error(format!("failed to reload tomb from disk{}", e));
+            }
+        };
+        match self.tomb.list_trash("*") {
+            Ok(items) => self.items.update(items),
+            Err(err) => log_error(format!("cannot list trash: {}", err)),
+        }
+        if self.items.current().is_none() && !self.items.items.is_empty() {
+            self.items.state.select(Some(0));
+        }
+    }
+    fn restore_selected(&mut self) {
+        let path = match self.items.current() {
+            Some(secret) => secret.path,
+            None => return,
+        };
+        // other routes and processes may have saved since the last
+        // reload, saving a stale tomb is rejected
+        self.refresh_items();
+        let restored = match self.tomb.restore_secret(&path) {
+            Ok(_) => self.tomb.save_with_key(&self.key),
+            Err(err) => Err(err),
+        };
+        match restored {
+            Ok(_) => {
+                log_error(format!("restored secret: {}", path));
+                self.text = format!("restored secret: {}", path);
+                self.refresh_items();
+            }
+            Err(err) => {
+                log_error(format!("error restoring secret {}: {}", path, err));
+                self.text = format!("cannot restore secret: {}", path);
+            }
+        }
+    }
+    fn render_list(&mut self) -> List<'a> {
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .style(ui::default_style().fg(ui::color_blurred()))
+            .title("Deleted Secrets")
+            .border_type(BorderType::Plain);
+        let items: Vec<_> = self
+            .items
+            .items
+            .iter()
+            .map(|secret| {
+                let deleted = match secret.deleted_at {
+                    Some(deleted_at) => chrono_humanize::HumanTime::from(deleted_at).to_string(),
+                    None => String::from("unknown"),
+                };
+                ListItem::new(Spans::from(vec![
+                    Span::styled(secret.path.clone(), ui::default_style()),
+                    Span::styled(
+                        format!("  deleted {}", deleted),
+                        ui::default_style().fg(ui::color_blurred()),
+                    ),
+                ]))
+            })
+            .collect();
+        List::new(items).block(block).highlight_style(
+            ui::default_style()
+                .bg(ui::color_default())
+                .fg(ui::color_default_fg()),
+        )
+    }
+}
+
+impl Component for Trash<'_> {
+    fn name(&self) -> &str {
+        COMPONENT_NAME
+    }
+    fn id(&self) -> String {
+        String::from(COMPONENT_NAME)
+    }
+    fn tick(
+        &mut self,
+        terminal: &mut Terminal<Backend>,
+        context: SharedContext,
+        router: SharedRouter,
+    ) -> Result<LoopEvent, Error> {
+        self.menu.borrow_mut().tick(terminal, context, router)
+    }
+
+    fn render_in_parent(
+        &mut self,
+        rect: &mut Frame<CrosstermBackend<io::Stdout>>,
+        chunk: Rect,
+    ) -> Result<(), Error> {
+        let (header, body, footer) = vertical_stack(chunk);
+        self.menu.borrow_mut().render_in_parent(rect, header)?;
+        if !self.items.items.is_empty() {
+            let list = self.render_list();
+            rect.render_stateful_widget(list, body, &mut self.items.state);
+        } else {
+            let empty = status_paragraph("Deleted Secrets", "The trash is empty");
+            rect.render_widget(empty, body);
+        }
+        rect.render_widget(status_paragraph("", &self.text), footer);
+        Ok(())
+    }
+
+    fn process_keyboard(
+        &mut self,
+        event: KeyEvent,
+        terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+        context: SharedContext,
+        router: SharedRouter,
+    ) -> Result<LoopEvent, Error> {
+        match event.code {
+            KeyCode::Char('q') => {
+                log_error(String::from("tomb closed"));
+                Ok(Quit)
+            }
+            KeyCode::Up if !self.items.items.is_empty() => {
+                self.items.previous();
+                Ok(Refresh)
+            }
+            KeyCode::Down if !self.items.items.is_empty() => {
+                self.items.next();
+                Ok(Refresh)
+            }
+            KeyCode::Char('r') | KeyCode::Enter => {
+                self.restore_selected();
+                Ok(Refresh)
+            }
+            _ => {
+                if event.modifiers == KeyModifiers::CONTROL && event.code == KeyCode::Char('q') {
+                    return Ok(Quit);
+                }
+                self.menu
+                    .borrow_mut()
+                    .process_keyboard(event, terminal, context, router)
+            }
+        }
+    }
+}
+impl Route for Trash<'_> {
+    fn render(
+        &mut self,
+        terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+        context: SharedContext,
+        router: SharedRouter,
+    ) -> Result<(), Error> {
+        self.refresh_items();
+        let location = context.borrow().location.clone();
+        self.menu.borrow_mut().select_by_location(location);
+        terminal.draw(|parent| {
+            let chunk = parent.size();
+            match self.render_in_parent(parent, chunk) {
+                Ok(_) => (),
+                Err(err) => {
+                    log_error(format!(
+                        "error rendering component {}: {}",
+                        self.name(),
+                        err
+                    ));
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
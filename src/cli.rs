@@ -1,5 +1,6 @@
 extern crate clap;
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use chrono::prelude::*;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
 use clipboard::ClipboardContext;
 use clipboard::ClipboardProvider;
 use console::style;
@@ -7,14 +8,32 @@ use console::style;
 use mac_notification_sys::*;
 use std::path::Path;
 //use console::style;
+use serde_json::json;
 use std::panic;
+use std::str::FromStr;
 use tomb::{
-    aes256cbc::{default_key_filename, Config as AesConfig, Key},
-    app::{self, TombConfig},
+    aes256cbc::{
+        default_identity_filename, default_key_filename, Config as AesConfig, Identity, Kdf, Key,
+        ARGON2_ITERATIONS, ARGON2_MEMORY, ARGON2_PARALLELISM, SCRYPT_LOG_N, SCRYPT_P, SCRYPT_R,
+    },
+    app::{self, config::default_tomb_config_filename, TombConfig},
     config::YamlFile,
     core::version,
+    ioutils::{
+        absolute_path, create_private_file, create_private_file_like, lock_file, read_bytes,
+        shred_file, FileLock,
+    },
     logger,
-    tomb::{default_tomb_filename, AES256Tomb},
+    migrations::{format_version, migrate_file, DocumentKind},
+    output::{self, fail, ErrorCode, Format},
+    sensitive::{Sensitive, SensitiveBytes},
+    tomb::{
+        default_tomb_filename,
+        generator::{Policy, DEFAULT_POLICY},
+        merge::{Conflict, Merge, Side},
+        verify::Issue,
+        AES256Secret, AES256Tomb, TombFormat,
+    },
 };
 
 pub fn confirm_password() -> Option<String> {
@@ -36,7 +55,7 @@ fn get_password_from_matches(matches: &ArgMatches) -> String {
     let password = if ask_password {
         match confirm_password() {
             Some(password) => {
-                logger::out::ok(format!("confirmed password: {}", password));
+                logger::err::ok(String::from("password confirmed"));
                 password
             }
             None => String::from(matches.value_of("password").unwrap_or("")),
@@ -47,20 +66,17 @@ fn get_password_from_matches(matches: &ArgMatches) -> String {
     password
 }
 
-fn load_key(matches: &ArgMatches) -> Key {
-    let config = AesConfig::default().unwrap();
+fn load_key(matches: &ArgMatches, config: &AesConfig) -> Key {
     let password = get_password_from_matches(matches);
     let key_filename = matches.value_of("key_filename").unwrap_or("");
 
-    if key_filename.len() > 0 {
+    let key = if key_filename.len() > 0 {
         match Key::import(key_filename) {
-            Ok(key) => key,
+            Ok(key) => match key.is_wrapped() {
+                true => unwrap_key(matches, key),
+                false => key,
+            },
             Err(err) => {
-                logger::err::error(format!(
-                    "{} {}",
-                    style("cannot import encryption key").color256(195),
-                    err
-                ));
                 logger::err::info(format!(
                     "{}",
                     style(
@@ -68,31 +84,170 @@ fn load_key(matches: &ArgMatches) -> Key {
                     )
                     .color256(149),
                 ));
-                std::process::exit(1);
+                fail(
+                    ErrorCode::KeyError,
+                    format!(
+                        "{} {}",
+                        style("cannot import encryption key").color256(195),
+                        err
+                    ),
+                )
             }
         }
     } else if password.len() > 0 {
-        Key::from_password(&password, &config)
+        if config.has_legacy_salt() {
+            logger::err::warning(String::from(
+                "this tomb derives its salt from the password, run 'tomb rekey' to move it to a random salt, the new password may be the same",
+            ));
+        }
+        match Key::from_password(&password, config) {
+            Ok(key) => key,
+            Err(error) => fail(ErrorCode::KeyError, error),
+        }
     } else {
-        logger::err::error(format!(
-            "{}{}{}{}{}",
-            style("either").color256(195),
-            style("--password, --key-filename").color256(190),
-            style(" or ").color256(195),
-            style("--ask-password").color256(190),
-            style(" is required").color256(195),
-        ));
-        std::process::exit(1);
+        fail(
+            ErrorCode::InvalidArgument,
+            format!(
+                "{}{}{}{}{}",
+                style("either").color256(195),
+                style("--password, --key-filename").color256(190),
+                style(" or ").color256(195),
+                style("--ask-password").color256(190),
+                style(" is required").color256(195),
+            ),
+        )
+    };
+    match load_identity(matches) {
+        Some(identity) => key.with_identity(identity),
+        None => key,
+    }
+}
+/// Loads the identity of `--identity-file`, or the default one if it exists
+fn load_identity(matches: &ArgMatches) -> Option<Identity> {
+    let identity_filename = match matches.value_of("identity_filename") {
+        Some(filename) => String::from(filename),
+        None => default_identity_filename(),
+    };
+    if !Path::new(&absolute_path(&identity_filename)).exists() {
+        return None;
+    }
+    match Identity::import(&identity_filename) {
+        Ok(identity) => Some(identity),
+        Err(err) => fail(
+            ErrorCode::KeyError,
+            format!("{} {}", style("cannot import identity").color256(195), err),
+        ),
+    }
+}
+
+fn unwrap_key(matches: &ArgMatches, key: Key) -> Key {
+    let password = match matches.value_of("password") {
+        Some(password) => String::from(password),
+        None => rpassword::prompt_password_stderr("Key password: ").unwrap(),
+    };
+    match key.unwrap_with_password(&password) {
+        Ok(key) => key,
+        Err(error) => fail(ErrorCode::KeyError, error),
+    }
+}
+
+fn parse_number<T: FromStr>(matches: &ArgMatches, name: &str) -> T
+where
+    T::Err: std::fmt::Display,
+{
+    let value = matches.value_of(name).unwrap_or("");
+    match value.parse::<T>() {
+        Ok(number) => number,
+        Err(error) => fail(
+            ErrorCode::InvalidArgument,
+            format!(
+                "{} {:?}: {}",
+                style(format!("invalid value for {}", name)).color256(195),
+                value,
+                error
+            ),
+        ),
+    }
+}
+
+/// The `--format` given to the innermost subcommand, which clap only
+/// propagates down from the ones given before it
+fn get_format_from_matches(matches: &ArgMatches) -> Format {
+    let mut format = "table";
+    let mut current = Some(matches);
+    while let Some(matches) = current {
+        if matches.occurrences_of("output_format") > 0 {
+            format = matches.value_of("output_format").unwrap();
+        }
+        current = matches.subcommand().1;
+    }
+    Format::from_str(format).unwrap()
+}
+
+fn get_kdf_from_matches(matches: &ArgMatches) -> Kdf {
+    match matches.value_of("kdf").unwrap_or("pbkdf2") {
+        "argon2id" => Kdf::Argon2id {
+            memory: parse_number(matches, "argon2_memory"),
+            iterations: parse_number(matches, "argon2_iterations"),
+            parallelism: parse_number(matches, "argon2_parallelism"),
+        },
+        "scrypt" => Kdf::Scrypt {
+            log_n: parse_number(matches, "scrypt_log_n"),
+            r: parse_number(matches, "scrypt_r"),
+            p: parse_number(matches, "scrypt_p"),
+        },
+        _ => Kdf::Pbkdf2,
+    }
+}
+
+/// Loads a tomb in either format along with its key, unsealing its metadata
+fn load_tomb_and_key(matches: &ArgMatches) -> (AES256Tomb, Key) {
+    let tomb_filepath = matches.value_of("tomb_filename").unwrap();
+    let config = match AES256Tomb::import_config(tomb_filepath) {
+        Ok(config) => config,
+        Err(err) => fail(ErrorCode::LoadFailed, err),
+    };
+    let key = load_key(matches, &config);
+    match AES256Tomb::import_with_key(tomb_filepath, &key) {
+        Ok(mut tomb) => {
+            tomb.history_retention = TombConfig::load().history_retention;
+            // members open the tomb with the data key wrapped for them
+            let key = match tomb.unlock_data_key(&key) {
+                Ok(Some(data_key)) => data_key,
+                Ok(None) => key,
+                // members of shared groups only decrypt their groups
+                Err(_) if tomb.group_only => key,
+                Err(err) => fail(ErrorCode::AccessDenied, err),
+            };
+            (tomb.with_filepath(tomb_filepath), key)
+        }
+        Err(err) => fail(ErrorCode::LoadFailed, err),
     }
 }
 
-fn load_tomb(matches: &ArgMatches) -> AES256Tomb {
+/// Keeps other processes from saving the tomb file until the returned
+/// lock is dropped, taken before loading tombs that will be saved
+fn lock_tomb(matches: &ArgMatches) -> FileLock {
+    let tomb_filepath = matches.value_of("tomb_filename").unwrap();
+    match lock_file(tomb_filepath) {
+        Ok(lock) => lock,
+        Err(err) => fail(
+            ErrorCode::IoError,
+            format!("failed to lock tomb file - {}", err),
+        ),
+    }
+}
+/// Loads the key only for containers and tombs with sealed metadata
+fn load_tomb_and_optional_key(matches: &ArgMatches) -> (AES256Tomb, Option<Key>) {
     let tomb_filepath = matches.value_of("tomb_filename").unwrap();
     match AES256Tomb::import(tomb_filepath) {
-        Ok(tomb) => tomb.with_filepath(tomb_filepath),
-        Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+        Ok(mut tomb) if !tomb.sealed => {
+            tomb.history_retention = TombConfig::load().history_retention;
+            (tomb.with_filepath(tomb_filepath), None)
+        }
+        _ => {
+            let (tomb, key) = load_tomb_and_key(matches);
+            (tomb, Some(key))
         }
     }
 }
@@ -101,28 +256,19 @@ fn init_command(matches: &ArgMatches) {
     let mut tomb_config = TombConfig::load();
     tomb_config.save().unwrap();
     let ask_password = matches.is_present("ask_password");
-    let key_cycles = matches
-        .value_of("key_cycles")
-        .unwrap_or("")
-        .parse::<u32>()
-        .unwrap_or(1000);
-    let salt_cycles = matches
-        .value_of("salt_cycles")
-        .unwrap_or("")
-        .parse::<u32>()
-        .unwrap_or(1000);
-    let iv_cycles = matches
-        .value_of("iv_cycles")
-        .unwrap_or("")
-        .parse::<u32>()
-        .unwrap_or(1000);
+    let key_cycles: u32 = parse_number(matches, "key_cycles");
+    let salt_cycles: u32 = parse_number(matches, "salt_cycles");
+    let iv_cycles: u32 = parse_number(matches, "iv_cycles");
+    let kdf = get_kdf_from_matches(matches);
 
     let tomb_filepath = matches.value_of("tomb_filename").unwrap();
     let key_filename = matches.value_of("key_filename").unwrap();
 
-    let key = if !Path::new(key_filename).exists() {
-        let vec: [u32; 3] = [key_cycles, salt_cycles, iv_cycles];
-        let custom_config = AesConfig::from_vec(&vec);
+    let vec: [u32; 3] = [key_cycles, salt_cycles, iv_cycles];
+    let custom_config = AesConfig::from_vec(&vec).with_kdf(kdf);
+    // the salt is stored in the tomb config only for keys derived from
+    // the password here, `Key::wrap` keeps its own in the key file
+    let (key, aes_config) = if !Path::new(key_filename).exists() {
         let password = if ask_password {
             match confirm_password() {
                 Some(password) => password,
@@ -131,43 +277,175 @@ fn init_command(matches: &ArgMatches) {
         } else {
             String::from(matches.value_of("password").unwrap_or(""))
         };
-        logger::err::info(format!("deriving key from password, please be patient..."));
-        let key = Key::from_password(&password, &custom_config);
+        logger::err::info(format!(
+            "deriving key from password with {}, please be patient...",
+            kdf.name()
+        ));
+        let wrap_key = matches.is_present("wrap_key");
+        let (key, aes_config) = if wrap_key {
+            (Key::generate(), custom_config.clone())
+        } else {
+            let derived_config = custom_config.with_random_salt();
+            match Key::from_password(&password, &derived_config) {
+                Ok(key) => (key, derived_config),
+                Err(error) => fail(ErrorCode::KeyError, error),
+            }
+        };
+        let wrapped = if wrap_key {
+            match key.wrap(&password, &custom_config) {
+                Ok(wrapped) => Some(wrapped),
+                Err(error) => fail(ErrorCode::KeyError, error),
+            }
+        } else {
+            None
+        };
 
-        let key_path = match key.export(key_filename) {
+        let key_path = match wrapped.as_ref().unwrap_or(&key).export(key_filename) {
             Ok(path) => path,
-            Err(error) => {
-                eprintln!("{}", error);
-                std::process::exit(1);
-            }
+            Err(error) => fail(ErrorCode::IoError, error),
         };
         logger::err::ok(format!("generated key: {}", style(key_path).color256(214)));
-        key
+        (key, aes_config)
     } else {
-        Key::import(key_filename).unwrap()
+        match Key::import(key_filename) {
+            Ok(key) => match key.is_wrapped() {
+                true => (unwrap_key(matches, key), custom_config),
+                false => (key, custom_config),
+            },
+            Err(error) => fail(ErrorCode::KeyError, error),
+        }
     };
 
-    let config = AesConfig::default().unwrap();
-
-    if AES256Tomb::import(tomb_filepath).is_ok() {
+    if Path::new(&absolute_path(tomb_filepath)).exists() {
         logger::err::warning(format!("file already exists: {}", tomb_filepath));
         std::process::exit(0);
     }
-    let mut tomb = AES256Tomb::new(tomb_filepath, key.clone(), config.clone());
-    match tomb.save() {
+    let mut tomb = AES256Tomb::new(tomb_filepath, &key, aes_config);
+    tomb.set_sealed(matches.is_present("seal_metadata"));
+    if matches.is_present("container") {
+        tomb.format = TombFormat::Container;
+    }
+    match tomb.save_with_key(&key) {
         Ok(target) => {
             logger::out::ok(format!("initialized tomb file: {}", target));
         }
-        Err(err) => {
-            logger::err::error(format!("failed to save tomb file - {}", err));
-            std::process::exit(1);
-        }
+        Err(err) => fail(
+            ErrorCode::SaveFailed,
+            format!("failed to save tomb file - {}", err),
+        ),
+    };
+}
+
+fn wrap_key_command(matches: &ArgMatches) {
+    let key_filename = matches.value_of("key_filename").unwrap();
+    let key = match Key::import(key_filename) {
+        Ok(key) => key,
+        Err(error) => fail(ErrorCode::KeyError, error),
+    };
+    if key.is_wrapped() {
+        logger::err::warning(format!("key is already wrapped: {}", key_filename));
+        std::process::exit(0);
+    }
+    let kdf = match matches.value_of("kdf").unwrap_or("argon2id") {
+        "pbkdf2" => Kdf::Pbkdf2,
+        "scrypt" => Kdf::scrypt(),
+        _ => Kdf::argon2id(),
     };
+    let password = match confirm_password() {
+        Some(password) => password,
+        None => std::process::exit(1),
+    };
+    let config = AesConfig::builtin(None).with_kdf(kdf);
+    logger::err::info(format!(
+        "wrapping key with {}, please be patient...",
+        kdf.name()
+    ));
+    match key.wrap(&password, &config) {
+        Ok(wrapped) => match wrapped.export(key_filename) {
+            Ok(path) => {
+                logger::err::ok(format!("wrapped key: {}", style(path).color256(214)));
+            }
+            Err(error) => fail(ErrorCode::IoError, error),
+        },
+        Err(error) => fail(ErrorCode::KeyError, error),
+    }
 }
 
+/// Strips the newline ending the output of `echo` and of most editors
+fn strip_trailing_newline(mut value: Vec<u8>) -> Vec<u8> {
+    if value.ends_with(b"\n") {
+        value.pop();
+        if value.ends_with(b"\r") {
+            value.pop();
+        }
+    }
+    value
+}
+/// Opens `$VISUAL` or `$EDITOR` on a private temporary file holding
+/// `current`, which is shredded once the editor exits
+fn edit_secret_value(current: &[u8]) -> Result<Vec<u8>, String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or(String::from("vi"));
+    let (filename, mut file) =
+        create_private_file("tomb-edit").map_err(|err| format!("{}", err))?;
+    let edited = std::io::Write::write_all(&mut file, current)
+        .and_then(|_| file.sync_all())
+        .map_err(|err| format!("{}", err))
+        .and_then(|_| {
+            drop(file);
+            let mut words = editor.split_whitespace();
+            let program = words.next().unwrap_or("vi");
+            match std::process::Command::new(program)
+                .args(words)
+                .arg(&filename)
+                .status()
+            {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(format!("editor {} exited with {}", editor, status)),
+                Err(error) => Err(format!("cannot run editor {}: {}", editor, error)),
+            }
+        })
+        .and_then(|_| std::fs::read(&filename).map_err(|err| format!("{}", err)));
+    if let Err(error) = shred_file(&filename) {
+        logger::err::warning(format!("{}", error));
+    }
+    edited.map(strip_trailing_newline)
+}
+/// The value to save from the source given in the command-line,
+/// prompting for it with hidden input when none is
+fn read_secret_value(matches: &ArgMatches, current: Option<SensitiveBytes>) -> SensitiveBytes {
+    let path = matches.value_of("path").expect("missing key path");
+    let value = if let Some(value) = matches.value_of("value") {
+        Ok(Vec::from(value.as_bytes()))
+    } else if matches.is_present("stdin") {
+        let mut value = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut value)
+            .map(|_| strip_trailing_newline(value))
+            .map_err(|err| format!("cannot read the value from stdin: {}", err))
+    } else if let Some(filename) = matches.value_of("from_file") {
+        read_bytes(filename).map_err(|err| format!("{}", err))
+    } else if matches.is_present("edit") {
+        edit_secret_value(current.as_ref().map(|v| v.as_slice()).unwrap_or(b""))
+    } else {
+        let value = rpassword::prompt_password_stderr(&format!("Value of {}: ", path)).unwrap();
+        let confirmation = rpassword::prompt_password_stderr("Confirm value: ").unwrap();
+        match value == confirmation {
+            true => Ok(value.into_bytes()),
+            false => Err(String::from("Value/Confirmation mismatch")),
+        }
+    };
+    match value {
+        Ok(value) if value.is_empty() => fail(
+            ErrorCode::InvalidArgument,
+            format!("refusing to save an empty value to {}", path),
+        ),
+        Ok(value) => Sensitive::new(value),
+        Err(err) => fail(ErrorCode::InvalidArgument, err),
+    }
+}
 fn save_command(matches: &ArgMatches) {
     let path = matches.value_of("path").expect("missing key path");
-    let value = matches.value_of("value").expect("missing value");
     let url = match matches.value_of("url") {
         Some(url) => Some(String::from(url)),
         None => None,
@@ -180,9 +458,14 @@ fn save_command(matches: &ArgMatches) {
         Some(username) => Some(String::from(username)),
         None => None,
     };
-    let key = load_key(matches);
-    let mut tomb = load_tomb(matches);
-    match tomb.add_secret(path, String::from(value), key) {
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let current = match matches.is_present("edit") {
+        true => tomb.get_bytes(path, &key).ok(),
+        false => None,
+    };
+    let value = read_secret_value(matches, current);
+    match tomb.add_secret_from_bytes(path, value.to_vec(), &key) {
         Ok(mut secret) => {
             tomb.upsert_secret(
                 secret
@@ -191,45 +474,311 @@ fn save_command(matches: &ArgMatches) {
                     .with_username(username),
             );
 
-            match tomb.save() {
+            match tomb.save_with_key(&key) {
                 Ok(_) => {
                     logger::out::ok(format!("added secret: {}", path));
                 }
-                Err(err) => {
-                    eprintln!("{}", err);
-                    std::process::exit(1);
-                }
+                Err(err) => fail(ErrorCode::SaveFailed, err),
             };
         }
-        Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+        Err(err) => fail(ErrorCode::OperationFailed, err),
+    }
+}
+/// The policy named by `--policy`, or the one the secret was generated
+/// with, or the default one, along with the options overriding it
+fn get_policy_from_matches(
+    matches: &ArgMatches,
+    tomb_config: &TombConfig,
+    secret: Option<&AES256Secret>,
+) -> Result<(String, Policy), String> {
+    let recorded = secret.and_then(|secret| secret.generator_policy());
+    let (mut name, mut policy) = match (matches.value_of("policy"), recorded) {
+        (Some(name), _) => match tomb_config.policy(name) {
+            Some(policy) => (String::from(name), policy),
+            None => {
+                return Err(format!(
+                    "unknown policy {}, available policies: {}",
+                    name,
+                    tomb_config.policy_names().join(", ")
+                ))
+            }
+        },
+        (None, Some(recorded)) => recorded,
+        (None, None) => (
+            String::from(DEFAULT_POLICY),
+            tomb_config.policy(DEFAULT_POLICY).unwrap(),
+        ),
+    };
+    let base = policy.clone();
+    if matches.is_present("length") {
+        policy.length = parse_number(matches, "length");
+    }
+    if matches.is_present("words") {
+        policy.words = parse_number(matches, "words");
+    }
+    if let Some(separator) = matches.value_of("separator") {
+        policy.separator = String::from(separator);
+    }
+    for (class, enabled) in [
+        ("no_lowercase", &mut policy.lowercase),
+        ("no_uppercase", &mut policy.uppercase),
+        ("no_digits", &mut policy.digits),
+        ("no_symbols", &mut policy.symbols),
+    ] {
+        if matches.is_present(class) {
+            *enabled = false;
+        }
+    }
+    for (class, minimum) in [
+        ("min_lowercase", &mut policy.min_lowercase),
+        ("min_uppercase", &mut policy.min_uppercase),
+        ("min_digits", &mut policy.min_digits),
+        ("min_symbols", &mut policy.min_symbols),
+    ] {
+        if matches.is_present(class) {
+            *minimum = parse_number(matches, class);
+        }
+    }
+    if matches.is_present("exclude_ambiguous") {
+        policy.exclude_ambiguous = true;
+    }
+    if policy != base {
+        name = String::from("custom");
+    }
+    Ok((name, policy))
+}
+fn generate_command(matches: &ArgMatches) {
+    let tomb_config = TombConfig::load();
+    if matches.is_present("list_policies") {
+        let mut records = Vec::new();
+        for name in tomb_config.policy_names() {
+            let policy = tomb_config.policy(&name).unwrap();
+            match output::format() {
+                Format::Table => println!(
+                    "{}\t{:.0} bits\t{}",
+                    name,
+                    policy.entropy(),
+                    policy.to_json()
+                ),
+                _ => records.push(json!({
+                    "name": name,
+                    "entropy": policy.entropy().round() as u64,
+                    "policy": policy,
+                })),
+            }
+        }
+        if output::is_structured() {
+            output::print_records(records);
+        }
+        return;
+    }
+    let path = matches.value_of("path").expect("missing key path");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let existing = tomb.get(path).ok();
+    let (name, policy) = match get_policy_from_matches(matches, &tomb_config, existing.as_ref()) {
+        Ok(policy) => policy,
+        Err(err) => fail(ErrorCode::InvalidArgument, err),
+    };
+    let generated = tomb
+        .generate_secret(path, &name, &policy, &key)
+        .and_then(|_| tomb.save_with_key(&key));
+    if let Err(err) = generated {
+        fail(
+            ErrorCode::OperationFailed,
+            format!("failed to generate secret - {}", err),
+        );
+    }
+    let print = matches.is_present("print") || matches.is_present("reveal");
+    let plaintext = match print {
+        true => match tomb.get_string(path, &key) {
+            Ok(plaintext) => Some(plaintext),
+            Err(err) => fail(ErrorCode::DecryptFailed, err),
+        },
+        false => None,
+    };
+    match output::format() {
+        Format::Table => {
+            logger::out::ok(format!(
+                "generated secret: {} ({} policy, {:.0} bits)",
+                path,
+                name,
+                policy.entropy()
+            ));
+            if let Some(plaintext) = plaintext {
+                println!("{}", plaintext.as_str());
+            }
         }
+        _ => output::print_record(json!({
+            "path": path,
+            "policy": name,
+            "entropy": policy.entropy().round() as u64,
+            "value": plaintext.as_ref().map(|plaintext| plaintext.as_str()),
+        })),
     }
 }
 fn get_command(matches: &ArgMatches) {
     let path = matches.value_of("path").expect("missing key path");
-    let key = load_key(matches);
-    let tomb = load_tomb(matches);
-    match tomb.get_string(path, key) {
-        Ok(plaintext) => {
-            println!("{}", plaintext)
+    let version = match matches.value_of("version") {
+        Some(_) => parse_number::<usize>(matches, "version"),
+        None => 0,
+    };
+    let (tomb, key) = load_tomb_and_key(matches);
+    let secret = match tomb.get(path) {
+        Ok(secret) => secret,
+        Err(err) => fail(ErrorCode::NotFound, err),
+    };
+    let plaintext = match tomb.get_version_string(path, version, &key) {
+        Ok(plaintext) => plaintext,
+        Err(err) => fail(ErrorCode::DecryptFailed, err),
+    };
+    match output::format() {
+        Format::Table => println!("{}", plaintext.as_str()),
+        _ => {
+            let mut record =
+                output::secret_record(&secret, tomb.shared_group(path), Some(plaintext.as_str()));
+            record["version"] = json!(version);
+            output::print_record(record)
         }
-        Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+    }
+}
+/// Prints a field of a secret alone, for scripts
+fn show_field(tomb: &AES256Tomb, secret: &AES256Secret, field: &str, key: Option<&Key>) {
+    let value = match (field, key) {
+        ("value", Some(key)) => match tomb.get_string(&secret.path, key) {
+            Ok(plaintext) => Some(plaintext.to_string()),
+            Err(err) => fail(ErrorCode::DecryptFailed, err),
+        },
+        _ => match secret.field(field) {
+            Ok(value) => value,
+            Err(err) => fail(ErrorCode::InvalidArgument, err),
+        },
+    };
+    match (value, output::format()) {
+        (None, _) => fail(
+            ErrorCode::NotFound,
+            format!("secret {} has no {}", secret.path, field),
+        ),
+        (Some(value), Format::Table) => println!("{}", value),
+        (Some(value), _) => output::print_record(json!({ field: value })),
+    }
+}
+/// A timestamp as in "3 days ago", followed by the date itself
+fn humanize(timestamp: &DateTime<Utc>) -> String {
+    format!(
+        "{} {}",
+        chrono_humanize::HumanTime::from(*timestamp),
+        style(format!("({})", timestamp.to_rfc2822())).color256(245)
+    )
+}
+fn show_command(matches: &ArgMatches) {
+    let path = matches.value_of("path").expect("missing key path");
+    let field = matches.value_of("field");
+    // the value is only decrypted when asked for, which needs the key
+    let (tomb, key) = match matches.is_present("reveal") || field == Some("value") {
+        true => {
+            let (tomb, key) = load_tomb_and_key(matches);
+            (tomb, Some(key))
+        }
+        false => load_tomb_and_optional_key(matches),
+    };
+    let secret = match tomb.get(path) {
+        Ok(secret) => secret,
+        Err(err) => fail(ErrorCode::NotFound, err),
+    };
+    if let Some(field) = field {
+        return show_field(&tomb, &secret, field, key.as_ref());
+    }
+    let plaintext = match &key {
+        Some(key) if matches.is_present("reveal") => match tomb.get_string(path, key) {
+            Ok(plaintext) => Some(plaintext),
+            Err(err) => fail(ErrorCode::DecryptFailed, err),
+        },
+        _ => None,
+    };
+    let value = plaintext.as_ref().map(|plaintext| plaintext.as_str());
+    if output::is_structured() {
+        let mut record = output::secret_record(&secret, tomb.shared_group(path), value);
+        let history: Vec<&DateTime<Utc>> = secret
+            .history
+            .iter()
+            .map(|previous| &previous.updated_at)
+            .collect();
+        record["history"] = json!(history);
+        return output::print_record(record);
+    }
+    let none = String::from("<none>");
+    let mut rows = vec![
+        ("group", secret.group()),
+        ("username", secret.username.clone().unwrap_or(none.clone())),
+        ("url", secret.url.clone().unwrap_or(none.clone())),
+        ("notes", secret.notes.clone().unwrap_or(none.clone())),
+        ("created", humanize(&secret.created_at)),
+        ("updated", humanize(&secret.updated_at)),
+        (
+            "history",
+            match secret.history.len() {
+                0 => none.clone(),
+                1 => String::from("1 previous value"),
+                count => format!("{} previous values", count),
+            },
+        ),
+    ];
+    if let Some(group) = tomb.shared_group(path) {
+        rows.insert(1, ("shared", String::from(group)));
+    }
+    if let Some(value) = value {
+        rows.insert(0, ("value", String::from(value)));
+    }
+    println!("{}", style(&secret.path).color256(190));
+    for (label, value) in rows {
+        println!("  {:<10} {}", style(label).color256(195), value);
+    }
+    let attributes = secret.attributes.clone().unwrap_or_default();
+    if !attributes.is_empty() {
+        println!("  {}", style("attributes").color256(195));
+        for (name, value) in attributes {
+            println!(
+                "    {} {}",
+                style(format!("{}:", name)).color256(149),
+                value
+            );
+        }
+    }
+}
+fn history_command(matches: &ArgMatches) {
+    let path = matches.value_of("path").expect("missing key path");
+    let (tomb, _) = load_tomb_and_optional_key(matches);
+    let secret = match tomb.get(path) {
+        Ok(secret) => secret,
+        Err(err) => fail(ErrorCode::NotFound, err),
+    };
+    if output::is_structured() {
+        let mut records =
+            vec![json!({"version": 0, "updated_at": secret.updated_at, "current": true})];
+        for (index, previous) in secret.history.iter().enumerate() {
+            records.push(
+                json!({"version": index + 1, "updated_at": previous.updated_at, "current": false}),
+            );
         }
+        return output::print_records(records);
+    }
+    println!("0\t{}\tcurrent", secret.updated_at.to_rfc2822());
+    for (index, previous) in secret.history.iter().enumerate() {
+        println!("{}\t{}", index + 1, previous.updated_at.to_rfc2822());
     }
 }
 fn copy_command(matches: &ArgMatches) {
     let path = matches.value_of("path").expect("missing key path");
-    let key = load_key(matches);
-    let tomb = load_tomb(matches);
-    match tomb.get_string(path, key) {
+    let (tomb, key) = load_tomb_and_key(matches);
+    if let Err(err) = tomb.get(path) {
+        fail(ErrorCode::NotFound, err);
+    }
+    match tomb.get_string(path, &key) {
         Ok(plaintext) => {
             let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-            ctx.set_contents(plaintext).unwrap();
-            eprintln!("{} secret copied to clipboard 🎉", path);
+            ctx.set_contents(plaintext.to_string()).unwrap();
+            logger::err::ok(format!("{} secret copied to clipboard 🎉", path));
 
             #[cfg(feature = "osx")]
             {
@@ -243,100 +792,1469 @@ fn copy_command(matches: &ArgMatches) {
                 .unwrap();
             }
         }
-        Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+        Err(err) => fail(ErrorCode::DecryptFailed, err),
+    }
+}
+fn edit_command(matches: &ArgMatches) {
+    let path = matches.value_of("path").expect("missing key path");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_optional_key(matches);
+    let mut secret = match tomb.get(path) {
+        Ok(secret) => secret,
+        Err(err) => fail(ErrorCode::NotFound, err),
+    };
+    // empty values clear the field
+    let cleared = |value: &str| match value {
+        "" => None,
+        value => Some(String::from(value)),
+    };
+    if let Some(username) = matches.value_of("username") {
+        secret.set_username(cleared(username));
+    }
+    if let Some(url) = matches.value_of("url") {
+        secret.set_url(cleared(url));
+    }
+    if let Some(notes) = matches.value_of("notes") {
+        secret.set_notes(cleared(notes));
+    }
+    for assignment in matches.values_of("set_attr").unwrap_or_default() {
+        match assignment.split_once('=') {
+            Some((name, value)) if !name.is_empty() => secret.set_attribute(name, value),
+            _ => fail(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "{} {:?}: expected NAME=VALUE",
+                    style("invalid value for --set-attr").color256(195),
+                    assignment
+                ),
+            ),
+        }
+    }
+    for name in matches.values_of("unset_attr").unwrap_or_default() {
+        if !secret.unset_attribute(name) {
+            logger::err::warning(format!("secret {} has no attribute {}", path, name));
+        }
+    }
+    secret.updated_at = Utc::now();
+    tomb.upsert_secret(secret);
+    let new_path = matches.value_of("rename");
+    if let Some(new_path) = new_path {
+        if let Err(err) = tomb.rename_secret(path, new_path) {
+            fail(
+                ErrorCode::OperationFailed,
+                format!("failed to rename secret - {}", err),
+            );
+        }
+    }
+    let saved = match &key {
+        Some(key) => tomb.save_with_key(key),
+        None => tomb.save(),
+    };
+    match (saved, new_path) {
+        (Ok(_), Some(new_path)) => {
+            logger::out::ok(format!("edited secret: {} renamed to {}", path, new_path))
         }
+        (Ok(_), None) => logger::out::ok(format!("edited secret: {}", path)),
+        (Err(err), _) => fail(ErrorCode::SaveFailed, err),
     }
 }
 fn delete_command(matches: &ArgMatches) {
     let path = matches.value_of("path").expect("missing key path");
-    //let key = load_key(matches);
-    let mut tomb = load_tomb(matches);
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_optional_key(matches);
     match tomb.delete_secret(path) {
         Ok(_) => {
-            match tomb.save() {
+            let saved = match &key {
+                Some(key) => tomb.save_with_key(key),
+                None => tomb.save(),
+            };
+            match saved {
                 Ok(_) => {
-                    logger::out::ok(format!("deleted secret: {}", path));
-                }
-                Err(err) => {
-                    eprintln!("{}", err);
-                    std::process::exit(1);
+                    logger::out::ok(format!("moved secret to trash: {}", path));
                 }
+                Err(err) => fail(ErrorCode::SaveFailed, err),
             };
         }
-        Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+        Err(err) => fail(ErrorCode::NotFound, err),
+    }
+}
+/// Parses durations like `30d`, `12h`, `15m` or `45s`, in days when
+/// the unit is omitted
+fn parse_duration(matches: &ArgMatches, name: &str) -> chrono::Duration {
+    let value = matches.value_of(name).unwrap_or("");
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(position) => value.split_at(position),
+        None => (value, "d"),
+    };
+    let duration = match (number.parse::<i64>(), unit) {
+        (Ok(number), "d") => Some(chrono::Duration::days(number)),
+        (Ok(number), "h") => Some(chrono::Duration::hours(number)),
+        (Ok(number), "m") => Some(chrono::Duration::minutes(number)),
+        (Ok(number), "s") => Some(chrono::Duration::seconds(number)),
+        _ => None,
+    };
+    match duration {
+        Some(duration) => duration,
+        None => fail(
+            ErrorCode::InvalidArgument,
+            format!(
+                "{} {:?}: expected a number of days or a number followed by d, h, m or s",
+                style(format!("invalid value for {}", name)).color256(195),
+                value,
+            ),
+        ),
+    }
+}
+fn trash_list_command(matches: &ArgMatches) {
+    let pattern = matches.value_of("pattern").expect("missing key pattern");
+    let (tomb, _) = load_tomb_and_optional_key(matches);
+    let secrets = match tomb.list_trash(pattern) {
+        Ok(secrets) => secrets,
+        Err(err) => fail(ErrorCode::InvalidArgument, err),
+    };
+    if output::is_structured() {
+        let records = secrets
+            .iter()
+            .map(|entry| output::secret_record(entry, tomb.shared_group(&entry.path), None))
+            .collect();
+        return output::print_records(records);
+    }
+    for entry in secrets {
+        let deleted_at = match entry.deleted_at {
+            Some(deleted_at) => deleted_at.to_rfc2822(),
+            None => String::from("unknown"),
+        };
+        println!("{}\t{}", deleted_at, entry.path)
+    }
+}
+fn trash_restore_command(matches: &ArgMatches) {
+    let path = matches.value_of("path").expect("missing key path");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_optional_key(matches);
+    if let Err(err) = tomb.restore_secret(path) {
+        fail(ErrorCode::NotFound, err);
+    }
+    let saved = match &key {
+        Some(key) => tomb.save_with_key(key),
+        None => tomb.save(),
+    };
+    match saved {
+        Ok(_) => {
+            logger::out::ok(format!("restored secret: {}", path));
         }
+        Err(err) => fail(ErrorCode::SaveFailed, err),
+    };
+}
+fn trash_purge_command(matches: &ArgMatches) {
+    let older_than = parse_duration(matches, "older_than");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_optional_key(matches);
+    let purged = tomb.purge_trash(older_than);
+    if purged.is_empty() {
+        logger::err::warning(String::from("no secrets to purge"));
+        return;
     }
+    let saved = match &key {
+        Some(key) => tomb.save_with_key(key),
+        None => tomb.save(),
+    };
+    match saved {
+        Ok(_) => {
+            for secret in purged {
+                logger::out::ok(format!("purged secret: {}", secret.path));
+            }
+        }
+        Err(err) => fail(ErrorCode::SaveFailed, err),
+    };
 }
 fn list_command(matches: &ArgMatches) {
     let pattern = matches.value_of("pattern").expect("missing key pattern");
-    // let key = load_key(matches);
-    let tomb = load_tomb(matches);
-    match tomb.list(pattern) {
-        Ok(secrets) => {
-            for entry in secrets {
-                println!("{}", entry.path)
-            }
+    // values are only decrypted with --reveal, which always needs the key
+    let (tomb, key) = match matches.is_present("reveal") {
+        true => {
+            let (tomb, key) = load_tomb_and_key(matches);
+            (tomb, Some(key))
         }
-        Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+        false => load_tomb_and_optional_key(matches),
+    };
+    let secrets = match tomb.list(pattern) {
+        Ok(secrets) => secrets,
+        Err(err) => fail(ErrorCode::InvalidArgument, err),
+    };
+    let mut records = Vec::new();
+    for entry in secrets {
+        let plaintext = match &key {
+            Some(key) if matches.is_present("reveal") && tomb.can_access(&entry.path) => {
+                match tomb.get_string(&entry.path, key) {
+                    Ok(plaintext) => Some(plaintext),
+                    Err(err) => fail(ErrorCode::DecryptFailed, err),
+                }
+            }
+            _ => None,
+        };
+        let value = plaintext.as_ref().map(|plaintext| plaintext.as_str());
+        match output::format() {
+            Format::Table => match value {
+                Some(value) => println!("{}\t{}", entry.path, value),
+                None => println!("{}", entry.path),
+            },
+            _ => records.push(output::secret_record(
+                &entry,
+                tomb.shared_group(&entry.path),
+                value,
+            )),
         }
     }
+    if output::is_structured() {
+        output::print_records(records);
+    }
 }
-fn ui_command(matches: &ArgMatches) {
-    let key = load_key(matches);
-    let mut tomb = load_tomb(matches);
-    match tomb.save() {
+fn seal_command(matches: &ArgMatches) {
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    if tomb.sealed {
+        logger::err::warning(String::from("tomb metadata is already sealed"));
+        std::process::exit(0);
+    }
+    tomb.set_sealed(true);
+    match tomb.save_with_key(&key) {
         Ok(target) => {
-            logger::out::ok(format!("saved file: {}", target));
+            logger::out::ok(format!("sealed metadata of tomb file: {}", target));
         }
-        Err(err) => {
-            logger::err::error(format!("failed to save tomb file - {}", err));
-            std::process::exit(1);
+        Err(err) => fail(
+            ErrorCode::SaveFailed,
+            format!("failed to save tomb file - {}", err),
+        ),
+    };
+}
+fn rekey_command(matches: &ArgMatches) {
+    let key_filename = matches.value_of("key_filename").unwrap();
+    let wrapped = match Key::import(key_filename) {
+        Ok(key) => key.wrapped,
+        Err(_) => None,
+    };
+    let _lock = lock_tomb(matches);
+    let (mut tomb, old) = load_tomb_and_key(matches);
+    if tomb.has_members() {
+        logger::err::info(format!(
+            "{}",
+            style("TIP: add the new key with 'tomb member add' and remove the old one with 'tomb member remove'").color256(149),
+        ));
+        fail(
+            ErrorCode::Unsupported,
+            "the secrets of this tomb are encrypted with a data key shared between its members",
+        );
+    }
+    let password = match matches.value_of("new_password") {
+        Some(password) => String::from(password),
+        None => match confirm_password() {
+            Some(password) => password,
+            None => std::process::exit(1),
+        },
+    };
+    logger::err::info(format!(
+        "deriving the new key with {}, please be patient...",
+        tomb.config.kdf.name()
+    ));
+    // wrapped keys are replaced by a new random key wrapped under the
+    // new password, other keys are derived from the new password
+    let rekeyed = match wrapped {
+        Some(wrapped) => {
+            let key = Key::generate();
+            let exported = match key.wrap(&password, &wrapped.config) {
+                Ok(exported) => exported,
+                Err(error) => fail(ErrorCode::KeyError, error),
+            };
+            tomb.config = tomb.config.with_random_salt();
+            tomb.rekey(&old, &key).map(|_| (key, Some(exported)))
         }
+        None => tomb
+            .rekey_with_password(&old, &password)
+            .map(|key| (key, None)),
     };
-
-    let tomb_config = TombConfig::load();
-    let aes_config = AesConfig::default().unwrap_or(AesConfig::builtin(None));
-    let tick_interval = matches.value_of("tick_interval").unwrap_or("3145");
-    let tick_interval = match tick_interval.parse::<u64>() {
-        Ok(tick_interval) => tick_interval,
+    let (new, exported) = match rekeyed {
+        Ok(rekeyed) => rekeyed,
+        Err(error) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to re-encrypt tomb - {}", error),
+        ),
+    };
+    // the new key is only moved over the current one once the tomb is
+    // saved, so that a failure never leaves a key that cannot open it
+    let new_key_filename = format!("{}.new", absolute_path(key_filename));
+    if let Err(error) = create_private_file_like(&new_key_filename, key_filename) {
+        fail(ErrorCode::IoError, error);
+    }
+    if let Err(error) = exported.as_ref().unwrap_or(&new).export(&new_key_filename) {
+        fail(ErrorCode::IoError, error);
+    }
+    match tomb.save_with_key(&new) {
+        Ok(target) => {
+            logger::out::ok(format!("re-encrypted tomb file: {}", target));
+        }
         Err(err) => {
-            logger::err::error(format!(
-                "tick interval is not a valid number {:?}: {}",
-                tick_interval, err
+            let _ = std::fs::remove_file(&new_key_filename);
+            fail(
+                ErrorCode::SaveFailed,
+                format!("failed to save tomb file - {}", err),
+            )
+        }
+    };
+    match std::fs::rename(&new_key_filename, absolute_path(key_filename)) {
+        Ok(_) => {
+            logger::err::ok(format!(
+                "replaced key: {}",
+                style(key_filename).color256(214)
             ));
-            std::process::exit(1);
+        }
+        Err(error) => fail(
+            ErrorCode::IoError,
+            format!(
+                "the tomb was re-encrypted but the key could not be replaced, the new key is {}: {}",
+                style(&new_key_filename).color256(214),
+                error
+            ),
+        ),
+    }
+}
+fn restore_backup_command(matches: &ArgMatches) {
+    let tomb_filepath = matches.value_of("tomb_filename").unwrap();
+    let generation = match matches.value_of("generation") {
+        Some(_) => parse_number::<usize>(matches, "generation"),
+        None => {
+            let backups = AES256Tomb::backups(tomb_filepath);
+            if backups.is_empty() {
+                logger::err::warning(format!("no backups of {}", tomb_filepath));
+            }
+            let mut records = Vec::new();
+            for (generation, filename) in backups {
+                let modified = std::fs::metadata(&filename)
+                    .and_then(|meta| meta.modified())
+                    .map(DateTime::<Local>::from)
+                    .ok();
+                match output::format() {
+                    Format::Table => println!(
+                        "{}\t{}\t{}",
+                        generation,
+                        match modified {
+                            Some(modified) => modified.to_rfc2822(),
+                            None => String::from("unknown"),
+                        },
+                        filename
+                    ),
+                    _ => records.push(json!({
+                        "generation": generation,
+                        "modified_at": modified,
+                        "filename": filename,
+                    })),
+                }
+            }
+            if output::is_structured() {
+                output::print_records(records);
+            }
+            return;
         }
     };
-    match app::start(tomb, key, tomb_config, aes_config, tick_interval) {
-        Ok(()) => {}
-        Err(error) => {
-            eprintln!("{}", error);
-            std::process::exit(1);
+    match AES256Tomb::restore_backup(tomb_filepath, generation) {
+        Ok(target) => {
+            logger::out::ok(format!(
+                "restored backup {} of tomb file: {}",
+                generation, target
+            ));
+        }
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to restore backup - {}", err),
+        ),
+    };
+}
+/// Loads one of the tombs to merge, loading the key only for
+/// containers and tombs with sealed metadata. The files are left
+/// untouched, tombs written by an older version are migrated in memory
+fn load_tomb_to_merge(matches: &ArgMatches, filename: &str, key: &mut Option<Key>) -> AES256Tomb {
+    if let Ok(tomb) = AES256Tomb::read(filename) {
+        if !tomb.sealed {
+            return tomb;
+        }
+    }
+    if key.is_none() {
+        match AES256Tomb::read_config(filename) {
+            Ok(config) => *key = Some(load_key(matches, &config)),
+            Err(err) => fail(ErrorCode::LoadFailed, err),
         }
     }
+    match AES256Tomb::read_with_key(filename, key.as_ref().unwrap()) {
+        Ok(tomb) => tomb,
+        Err(err) => fail(ErrorCode::LoadFailed, err),
+    }
 }
+fn ask_conflict_side(conflict: &Conflict) -> Option<Side> {
+    let updated_at = |secret: &Option<AES256Secret>| match secret {
+        Some(secret) => secret.updated_at.to_rfc2822(),
+        None => String::from("deleted"),
+    };
+    eprintln!(
+        "{} {}\n\tours:   {}\n\ttheirs: {}",
+        style(conflict.path()).color256(190),
+        style(format!("({})", conflict.describe())).color256(195),
+        updated_at(&conflict.ours),
+        updated_at(&conflict.theirs),
+    );
+    loop {
+        eprint!("keep [o]urs, [t]heirs, [n]ewest or [s]kip? ");
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            return None;
+        }
+        match answer.trim() {
+            "o" | "ours" => return Some(Side::Ours),
+            "t" | "theirs" => return Some(Side::Theirs),
+            "n" | "newest" => return Some(conflict.newest()),
+            "s" | "skip" => return None,
+            _ => continue,
+        }
+    }
+}
+fn merge_command(matches: &ArgMatches) {
+    let base_filepath = matches.value_of("base").unwrap();
+    let ours_filepath = matches.value_of("ours").unwrap();
+    let theirs_filepath = matches.value_of("theirs").unwrap();
+    let _lock = match lock_file(ours_filepath) {
+        Ok(lock) => lock,
+        Err(err) => fail(
+            ErrorCode::IoError,
+            format!("failed to lock tomb file - {}", err),
+        ),
+    };
+    let mut key: Option<Key> = None;
+    let base = load_tomb_to_merge(matches, base_filepath, &mut key);
+    let mut ours = load_tomb_to_merge(matches, ours_filepath, &mut key);
+    let theirs = load_tomb_to_merge(matches, theirs_filepath, &mut key);
 
-fn main() {
-    panic::set_hook(Box::new(|e| {
-        eprintln!("{}", e);
-    }));
-
-    let tomb_filename = default_tomb_filename();
-    let key_filename = default_key_filename();
-    let version = version();
+    let mut merge = match Merge::new(&base, &ours, &theirs) {
+        Ok(merge) => merge,
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to merge tomb files - {}", err),
+        ),
+    };
+    if merge.needs_key() && key.is_none() {
+        key = Some(load_key(matches, &ours.config));
+    }
+    // members open the tomb with their own key, its secrets are
+    // encrypted with the data key
+    if let Some(member_key) = key.take() {
+        key = match ours.unlock(&member_key) {
+            Ok(data_key) => Some(data_key.unwrap_or(member_key)),
+            Err(err) => fail(
+                ErrorCode::OperationFailed,
+                format!("failed to merge tomb files - {}", err),
+            ),
+        };
+    }
+    if merge.needs_key() {
+        if let Err(err) = merge.compare_values(&ours, key.as_ref().unwrap()) {
+            fail(
+                ErrorCode::OperationFailed,
+                format!("failed to merge tomb files - {}", err),
+            );
+        }
+    }
+    let strategy = matches.value_of("strategy").unwrap_or("ask");
+    let mut unresolved = Vec::new();
+    for conflict in std::mem::take(&mut merge.conflicts) {
+        let side = match strategy {
+            "ours" => Some(Side::Ours),
+            "theirs" => Some(Side::Theirs),
+            "newest" => Some(conflict.newest()),
+            _ => match console::user_attended_stderr() {
+                true => ask_conflict_side(&conflict),
+                false => None,
+            },
+        };
+        match side {
+            Some(side) => merge.resolve(&conflict, side),
+            None => unresolved.push(conflict),
+        }
+    }
+    if !unresolved.is_empty() {
+        for conflict in &unresolved {
+            logger::err::error(format!(
+                "conflict in {}: {}",
+                conflict.path(),
+                conflict.describe()
+            ));
+        }
+        fail(
+            ErrorCode::MergeConflict,
+            format!(
+                "{} unresolved conflicts, {} was not modified",
+                unresolved.len(),
+                ours_filepath
+            ),
+        );
+    }
+    if let Err(err) = merge.apply(&mut ours) {
+        fail(
+            ErrorCode::OperationFailed,
+            format!("failed to merge tomb files - {}", err),
+        );
+    }
+    // saved like any other change: checked against the file ours was
+    // read from, backed up and written atomically
+    ours.set_filepath(ours_filepath);
+    let saved = match &key {
+        Some(key) => ours.save_with_key(key),
+        None => ours.save(),
+    };
+    match saved {
+        Ok(target) => {
+            logger::out::ok(format!("merged tomb file: {}", target));
+        }
+        Err(err) => fail(
+            ErrorCode::SaveFailed,
+            format!("failed to save tomb file - {}", err),
+        ),
+    };
+}
+fn convert_command(matches: &ArgMatches) {
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let format = match matches.value_of("format").unwrap_or("container") {
+        "yaml" => TombFormat::Yaml,
+        _ => TombFormat::Container,
+    };
+    if tomb.format == format {
+        logger::err::warning(format!("tomb is already in the {:?} format", format));
+        std::process::exit(0);
+    }
+    tomb.format = format;
+    match tomb.save_with_key(&key) {
+        Ok(target) => {
+            logger::out::ok(format!("converted tomb file {} to {:?}", target, format));
+        }
+        Err(err) => fail(
+            ErrorCode::SaveFailed,
+            format!("failed to save tomb file - {}", err),
+        ),
+    };
+}
+fn keygen_command(matches: &ArgMatches) {
+    let identity = matches.is_present("identity");
+    let filename = match identity {
+        true => matches.value_of("identity_filename").unwrap(),
+        false => matches.value_of("key_filename").unwrap(),
+    };
+    if Path::new(&absolute_path(filename)).exists() {
+        fail(
+            ErrorCode::AlreadyExists,
+            format!("{} already exists", filename),
+        );
+    }
+    let (exported, recipient) = match identity {
+        true => {
+            let identity = Identity::generate();
+            (identity.export(filename), Some(identity.recipient))
+        }
+        false => (Key::generate().export(filename), None),
+    };
+    match exported {
+        Ok(target) => match output::format() {
+            Format::Table => {
+                if let Some(recipient) = recipient {
+                    println!("{}", recipient);
+                }
+                logger::err::ok(format!("generated {}", target))
+            }
+            _ => output::print_record(json!({"filename": target, "public_key": recipient})),
+        },
+        Err(err) => fail(
+            ErrorCode::IoError,
+            format!("failed to write {} - {}", filename, err),
+        ),
+    }
+}
+fn member_list_command(matches: &ArgMatches) {
+    let tomb_filepath = matches.value_of("tomb_filename").unwrap();
+    let tomb = match AES256Tomb::import(tomb_filepath) {
+        Ok(tomb) => tomb,
+        Err(_) => load_tomb_and_key(matches).0,
+    };
+    if !tomb.has_members() {
+        logger::err::warning(format!("tomb {} has no members", tomb_filepath));
+    }
+    if output::is_structured() {
+        let records = tomb
+            .members
+            .iter()
+            .map(|member| {
+                json!({
+                    "name": member.name,
+                    "added_at": member.added_at,
+                    "public_key": member.public_key,
+                })
+            })
+            .collect();
+        return output::print_records(records);
+    }
+    for member in &tomb.members {
+        println!(
+            "{}\t{}\t{}",
+            member.name,
+            member.added_at.to_rfc2822(),
+            member.public_key
+        );
+    }
+}
+fn print_public_key(public_key: &str) {
+    match output::format() {
+        Format::Table => println!("{}", public_key),
+        _ => output::print_record(json!({ "public_key": public_key })),
+    }
+}
+fn member_public_key_command(matches: &ArgMatches) {
+    if let Some(identity) = load_identity(matches) {
+        return print_public_key(&identity.recipient);
+    }
+    let key = load_key(matches, &AesConfig::builtin(None));
+    match key.recipient() {
+        Ok(public_key) => print_public_key(&public_key),
+        Err(err) => fail(ErrorCode::KeyError, err),
+    }
+}
+fn member_add_command(matches: &ArgMatches) {
+    let name = matches.value_of("name").expect("missing member name");
+    let public_key = matches.value_of("public_key").expect("missing public key");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let added = match tomb.has_members() {
+        true => Ok(key),
+        false => {
+            let owner = matches.value_of("owner").unwrap();
+            logger::err::info(format!(
+                "moving the secrets under a new data key shared with member {}",
+                owner
+            ));
+            tomb.enable_members(owner, &key)
+        }
+    }
+    .and_then(|data_key| {
+        tomb.add_member(name, public_key, &data_key)?;
+        tomb.save_with_key(&data_key)
+    });
+    match added {
+        Ok(target) => logger::out::ok(format!("added member {} to tomb {}", name, target)),
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to add member - {}", err),
+        ),
+    }
+}
+fn member_remove_command(matches: &ArgMatches) {
+    let name = matches.value_of("name").expect("missing member name");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let removed = tomb
+        .remove_member(name, &key)
+        .and_then(|data_key| tomb.save_with_key(&data_key));
+    match removed {
+        Ok(target) => logger::out::ok(format!(
+            "removed member {} from tomb {} and rotated its data key",
+            name, target
+        )),
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to remove member - {}", err),
+        ),
+    }
+}
+fn group_list_command(matches: &ArgMatches) {
+    let tomb_filepath = matches.value_of("tomb_filename").unwrap();
+    let tomb = match AES256Tomb::import(tomb_filepath) {
+        Ok(tomb) => tomb,
+        Err(_) => load_tomb_and_key(matches).0,
+    };
+    if tomb.groups.is_empty() {
+        logger::err::warning(format!("tomb {} has no shared groups", tomb_filepath));
+    }
+    let mut records = Vec::new();
+    for (name, group) in &tomb.groups {
+        let members: Vec<&str> = group
+            .members
+            .iter()
+            .map(|member| member.name.as_str())
+            .collect();
+        match output::format() {
+            Format::Table => println!("{}\t{}", name, members.join(", ")),
+            _ => records.push(json!({"group": name, "members": members})),
+        }
+    }
+    if output::is_structured() {
+        output::print_records(records);
+    }
+}
+fn group_share_command(matches: &ArgMatches) {
+    let group = matches.value_of("group").expect("missing group");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let shared = tomb
+        .share_group(group, &key)
+        .and_then(|_| tomb.save_with_key(&key));
+    match shared {
+        Ok(target) => logger::out::ok(format!(
+            "moved the secrets under {} of tomb {} to a group key",
+            group, target
+        )),
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to share group - {}", err),
+        ),
+    }
+}
+fn group_unshare_command(matches: &ArgMatches) {
+    let group = matches.value_of("group").expect("missing group");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let unshared = tomb
+        .unshare_group(group, &key)
+        .and_then(|_| tomb.save_with_key(&key));
+    match unshared {
+        Ok(target) => logger::out::ok(format!(
+            "moved the secrets under {} of tomb {} back to its key",
+            group, target
+        )),
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to unshare group - {}", err),
+        ),
+    }
+}
+fn group_add_command(matches: &ArgMatches) {
+    let group = matches.value_of("group").expect("missing group");
+    let name = matches.value_of("name").expect("missing member name");
+    let public_key = matches.value_of("public_key").expect("missing public key");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let added = tomb
+        .add_group_member(group, name, public_key)
+        .and_then(|_| tomb.save_with_key(&key));
+    match added {
+        Ok(target) => logger::out::ok(format!(
+            "added member {} to group {} of tomb {}",
+            name, group, target
+        )),
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to add group member - {}", err),
+        ),
+    }
+}
+fn group_remove_command(matches: &ArgMatches) {
+    let group = matches.value_of("group").expect("missing group");
+    let name = matches.value_of("name").expect("missing member name");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    let removed = tomb
+        .remove_group_member(group, name, &key)
+        .and_then(|_| tomb.save_with_key(&key));
+    match removed {
+        Ok(target) => logger::out::ok(format!(
+            "removed member {} from group {} of tomb {} and rotated its key",
+            name, group, target
+        )),
+        Err(err) => fail(
+            ErrorCode::OperationFailed,
+            format!("failed to remove group member - {}", err),
+        ),
+    }
+}
+fn verify_command(matches: &ArgMatches) {
+    let repair = matches.is_present("repair");
+    let _lock = lock_tomb(matches);
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    // secrets that are not valid utf-8, such as binary files, are
+    // only noticed and do not fail the verification
+    let (issues, notices): (Vec<Issue>, Vec<Issue>) = tomb
+        .verify(&key)
+        .into_iter()
+        .partition(|issue| issue.is_failure());
+    for notice in &notices {
+        logger::err::info(notice.describe());
+    }
+    for issue in &issues {
+        logger::err::warning(issue.describe());
+    }
+    if issues.is_empty() {
+        logger::out::ok(format!("verified {} secrets", tomb.data.len()));
+        return;
+    }
+    let remaining = if repair && issues.iter().any(|issue| issue.is_repairable()) {
+        let remaining: Vec<Issue> = tomb
+            .repair(&key)
+            .into_iter()
+            .filter(|issue| issue.is_failure())
+            .collect();
+        match tomb.save_with_key(&key) {
+            Ok(target) => logger::out::ok(format!(
+                "repaired {} issues in tomb file {}",
+                issues.len() - remaining.len(),
+                target
+            )),
+            Err(err) => fail(
+                ErrorCode::SaveFailed,
+                format!("failed to save tomb file - {}", err),
+            ),
+        }
+        remaining
+    } else {
+        issues
+    };
+    if remaining.is_empty() {
+        return;
+    }
+    if !repair && remaining.iter().any(|issue| issue.is_repairable()) {
+        logger::err::info(format!(
+            "{}",
+            style("TIP: run 'tomb verify --repair' to reindex inconsistent and duplicated secrets")
+                .color256(149),
+        ));
+    }
+    fail(
+        ErrorCode::VerificationFailed,
+        format!("{} issues found", remaining.len()),
+    );
+}
+/// The format version of a key or config file, without migrating it
+fn file_format_version(kind: DocumentKind, filename: &str) -> Result<u32, String> {
+    match std::fs::read_to_string(absolute_path(filename)) {
+        Ok(yaml) => format_version(kind, &yaml).map_err(|err| format!("{}", err)),
+        Err(err) => Err(format!("failed to read {}: {}", filename, err)),
+    }
+}
+fn report_format_version(kind: DocumentKind, filename: &str, version: Option<u32>) -> bool {
+    match version {
+        Some(version) if version == kind.current_version() => {
+            logger::out::ok(format!(
+                "{} file {} is at format version {}",
+                kind.name(),
+                filename,
+                version
+            ));
+            false
+        }
+        Some(version) => {
+            logger::err::warning(format!(
+                "{} file {} is at format version {}, the current version is {}",
+                kind.name(),
+                filename,
+                version,
+                kind.current_version()
+            ));
+            true
+        }
+        None => {
+            logger::err::info(format!(
+                "{} file {} is an encrypted container, run with --migrate to check it with the key",
+                kind.name(),
+                filename
+            ));
+            false
+        }
+    }
+}
+fn doctor_command(matches: &ArgMatches) {
+    let migrate = matches.is_present("migrate");
+    let tomb_filepath = matches.value_of("tomb_filename").unwrap();
+    let key_filename = matches.value_of("key_filename").unwrap();
+    let config_filename = default_tomb_config_filename();
+    let mut outdated = false;
+    let mut failed = false;
+
+    for (kind, filename) in [
+        (DocumentKind::Config, config_filename.as_str()),
+        (DocumentKind::Key, key_filename),
+    ] {
+        if !Path::new(&absolute_path(filename)).exists() {
+            logger::err::warning(format!("{} file {} does not exist", kind.name(), filename));
+            continue;
+        }
+        match file_format_version(kind, filename) {
+            Ok(version) if migrate && version != kind.current_version() => {
+                if let Err(err) = migrate_file(kind, filename) {
+                    logger::err::error(format!("failed to migrate {} file - {}", kind.name(), err));
+                    failed = true;
+                }
+            }
+            Ok(version) => outdated |= report_format_version(kind, filename, Some(version)),
+            Err(err) => {
+                logger::err::error(err);
+                failed = true;
+            }
+        }
+    }
+
+    let version = match AES256Tomb::file_format_version(tomb_filepath, None) {
+        Ok(version) => version,
+        Err(err) => fail(ErrorCode::LoadFailed, err),
+    };
+    let tomb_kind = DocumentKind::Tomb;
+    match version {
+        Some(version) if !migrate || version == tomb_kind.current_version() => {
+            outdated |= report_format_version(tomb_kind, tomb_filepath, Some(version))
+        }
+        None if !migrate => outdated |= report_format_version(tomb_kind, tomb_filepath, None),
+        // values written before authentication tags are re-encrypted
+        // with the key, loading the tomb migrates its yaml first
+        version => {
+            let _lock = lock_tomb(matches);
+            let (mut tomb, key) = load_tomb_and_key(matches);
+            match AES256Tomb::file_format_version(tomb_filepath, Some(&key)) {
+                Ok(Some(current)) if current != tomb_kind.current_version() => {
+                    match tomb.save_with_key(&key) {
+                        Ok(_) if tomb.format_version != tomb_kind.current_version() => {
+                            logger::err::error(format!(
+                                "the tomb file {} can only be migrated with the key of the tomb",
+                                tomb_filepath
+                            ));
+                            failed = true;
+                        }
+                        Ok(target) => logger::err::info(format!(
+                            "migrated tomb file {} from format version {} to {}",
+                            target,
+                            version.unwrap_or(current),
+                            tomb_kind.current_version()
+                        )),
+                        Err(err) => {
+                            logger::err::error(format!("failed to save tomb file - {}", err));
+                            failed = true;
+                        }
+                    }
+                }
+                Ok(version) => outdated |= report_format_version(tomb_kind, tomb_filepath, version),
+                Err(err) => {
+                    logger::err::error(format!("{}", err));
+                    failed = true;
+                }
+            }
+        }
+    }
+    if failed {
+        fail(
+            ErrorCode::OperationFailed,
+            "failed to check the format version of every file",
+        );
+    }
+    if outdated {
+        logger::err::info(format!(
+            "{}",
+            style("TIP: run 'tomb doctor --migrate' to upgrade, older files are migrated on load as well").color256(149),
+        ));
+    }
+}
+fn ui_command(matches: &ArgMatches) {
+    let (mut tomb, key) = load_tomb_and_key(matches);
+    match tomb.save_with_key(&key) {
+        Ok(target) => {
+            logger::out::ok(format!("saved file: {}", target));
+        }
+        Err(err) => fail(
+            ErrorCode::SaveFailed,
+            format!("failed to save tomb file - {}", err),
+        ),
+    };
+
+    let tomb_config = TombConfig::load();
+    let aes_config = AesConfig::default().unwrap_or(AesConfig::builtin(None));
+    let tick_interval = matches.value_of("tick_interval").unwrap_or("3145");
+    let tick_interval = match tick_interval.parse::<u64>() {
+        Ok(tick_interval) => tick_interval,
+        Err(err) => fail(
+            ErrorCode::InvalidArgument,
+            format!(
+                "tick interval is not a valid number {:?}: {}",
+                tick_interval, err
+            ),
+        ),
+    };
+    match app::start(tomb, key, tomb_config, aes_config, tick_interval) {
+        Ok(()) => {}
+        Err(error) => fail(ErrorCode::OperationFailed, error),
+    }
+}
+
+fn main() {
+    panic::set_hook(Box::new(|e| {
+        eprintln!("{}", e);
+    }));
+
+    let tomb_filename = default_tomb_filename();
+    let key_filename = default_key_filename();
+    let identity_filename = default_identity_filename();
+    let owner = std::env::var("USER").unwrap_or(String::from("owner"));
+    let version = version();
+    let argon2_memory = ARGON2_MEMORY.to_string();
+    let argon2_iterations = ARGON2_ITERATIONS.to_string();
+    let argon2_parallelism = ARGON2_PARALLELISM.to_string();
+    let scrypt_log_n = SCRYPT_LOG_N.to_string();
+    let scrypt_r = SCRYPT_R.to_string();
+    let scrypt_p = SCRYPT_P.to_string();
     let app = App::new("⚰Tomb")
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .about("Password Manager")
         .version(version.as_str())
+        .arg(
+            Arg::with_name("output_format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&Format::NAMES)
+                .default_value("table")
+                .help("print records as json, yaml or shell variables, along with errors and their code")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reveal")
+                .long("reveal")
+                .help("include the values of secrets in the output of list and show")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("save")
+                .about("store a secret in the tomb")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .short("U")
+                        .value_name("URL")
+                        .help("attach a URL to this secret, for example a login url")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("notes")
+                        .long("notes")
+                        .short("N")
+                        .value_name("NOTES")
+                        .help("attach a text to this secret, stored as plaintext")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("username")
+                        .long("username")
+                        .short("u")
+                        .value_name("USERNAME")
+                        .help("attach a text to this secret, stored as plaintext")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("KEY PATH")
+                        .help("the path to the secret")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .value_name("VALUE")
+                        .help("the secret value to be saved, which ends up in the shell history: prefer the prompt shown without it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .help("read the value from stdin, without its trailing newline")
+                        .conflicts_with_all(&["value", "from_file", "edit"]),
+                )
+                .arg(
+                    Arg::with_name("from_file")
+                        .long("from-file")
+                        .value_name("FILENAME")
+                        .help("read the value from a file, byte for byte")
+                        .conflicts_with_all(&["value", "edit"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("edit")
+                        .long("edit")
+                        .short("e")
+                        .help("write the value in $EDITOR, on a private temporary file shredded afterwards")
+                        .conflicts_with("value"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("generate")
+                .about("store a random password or passphrase in the tomb")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("KEY PATH")
+                        .help("the path to the secret")
+                        .required_unless("list_policies")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("policy")
+                        .long("policy")
+                        .short("p")
+                        .value_name("NAME")
+                        .help("a policy of ~/.tomb.config.yaml, 'default' or 'passphrase', defaults to the one the secret was generated with")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("length")
+                        .long("length")
+                        .value_name("LENGTH")
+                        .help("the number of characters")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no_lowercase")
+                        .long("no-lowercase")
+                        .help("leave out lowercase letters"),
+                )
+                .arg(
+                    Arg::with_name("no_uppercase")
+                        .long("no-uppercase")
+                        .help("leave out uppercase letters"),
+                )
+                .arg(
+                    Arg::with_name("no_digits")
+                        .long("no-digits")
+                        .help("leave out digits"),
+                )
+                .arg(
+                    Arg::with_name("no_symbols")
+                        .long("no-symbols")
+                        .help("leave out symbols"),
+                )
+                .arg(
+                    Arg::with_name("exclude_ambiguous")
+                        .long("exclude-ambiguous")
+                        .help("leave out characters such as 0, O, 1, l and I"),
+                )
+                .arg(
+                    Arg::with_name("min_lowercase")
+                        .long("min-lowercase")
+                        .value_name("COUNT")
+                        .help("the minimum number of lowercase letters")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min_uppercase")
+                        .long("min-uppercase")
+                        .value_name("COUNT")
+                        .help("the minimum number of uppercase letters")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min_digits")
+                        .long("min-digits")
+                        .value_name("COUNT")
+                        .help("the minimum number of digits")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min_symbols")
+                        .long("min-symbols")
+                        .value_name("COUNT")
+                        .help("the minimum number of symbols")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("words")
+                        .long("words")
+                        .value_name("COUNT")
+                        .help("generate a passphrase of this many words from the bundled wordlist")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("separator")
+                        .long("separator")
+                        .value_name("SEPARATOR")
+                        .help("the separator of the words of passphrases")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("print")
+                        .long("print")
+                        .help("print the generated value"),
+                )
+                .arg(
+                    Arg::with_name("list_policies")
+                        .long("list-policies")
+                        .help("list the available policies"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("initializes a tomb file and generates a key")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("password")
+                        .long("password")
+                        .short("P")
+                        .required_unless_one(&["key_filename", "ask_password"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ask_password")
+                        .long("ask-password")
+                        .short("p")
+                        .required_unless_one(&["password", "ask_password"])
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("key_cycles")
+                        .default_value("16000")
+                        .long("key")
+                        .short("K")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("salt_cycles")
+                        .default_value("16000")
+                        .long("salt")
+                        .short("S")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("iv_cycles")
+                        .default_value("16000")
+                        .long("iv")
+                        .short("I")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("container")
+                        .long("container")
+                        .help("store the whole tomb encrypted in a single armored container")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("seal_metadata")
+                        .long("seal-metadata")
+                        .help(
+                            "encrypt the path, username, url, notes and attributes of secrets too",
+                        )
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("wrap_key")
+                        .long("wrap-key")
+                        .help("generate a random key and store it encrypted under the password")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("kdf")
+                        .long("kdf")
+                        .help(
+                            "the key derivation function used to derive the key from the password",
+                        )
+                        .possible_values(&["pbkdf2", "argon2id", "scrypt"])
+                        .default_value("pbkdf2")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("argon2_memory")
+                        .long("argon2-memory")
+                        .help("argon2id memory cost in KiB")
+                        .default_value(&argon2_memory)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("argon2_iterations")
+                        .long("argon2-iterations")
+                        .help("argon2id number of iterations")
+                        .default_value(&argon2_iterations)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("argon2_parallelism")
+                        .long("argon2-parallelism")
+                        .help("argon2id degree of parallelism")
+                        .default_value(&argon2_parallelism)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("scrypt_log_n")
+                        .long("scrypt-log-n")
+                        .help("scrypt cost parameter as log2(N)")
+                        .default_value(&scrypt_log_n)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("scrypt_r")
+                        .long("scrypt-r")
+                        .help("scrypt block size")
+                        .default_value(&scrypt_r)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("scrypt_p")
+                        .long("scrypt-p")
+                        .help("scrypt parallelization parameter")
+                        .default_value(&scrypt_p)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("wrap-key")
+                .about("encrypt the key file under a password")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("kdf")
+                        .long("kdf")
+                        .help("the key derivation function used to derive the key-encryption key")
+                        .possible_values(&["pbkdf2", "argon2id", "scrypt"])
+                        .default_value("argon2id")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("seal")
+                .about("encrypt the metadata of the secrets in an existing tomb")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .about("change the password or key of the tomb, re-encrypting every secret")
+                .arg(
+                    Arg::with_name("password")
+                        .long("password")
+                        .short("P")
+                        .help("the current password, required for wrapped key files")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("new_password")
+                        .long("new-password")
+                        .help("the new password, prompted for when omitted")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore-backup")
+                .about("list the backups of the tomb file, or restore one of them")
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("generation")
+                        .value_name("GENERATION")
+                        .help("the backup to restore, 1 being the most recent")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
-            SubCommand::with_name("save")
-                .about("store a secret in the tomb")
+            SubCommand::with_name("convert")
+                .about("convert the tomb file between the yaml and the encrypted container formats")
+                .arg(
+                    Arg::with_name("format")
+                        .long("to")
+                        .help("the format to convert the tomb file to")
+                        .possible_values(&["container", "yaml"])
+                        .default_value("container")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("key_filename")
                         .long("key-filename")
@@ -354,90 +2272,87 @@ fn main() {
                         .default_value(&tomb_filename)
                         .help("the path to the tomb file containing the encrypted secrets")
                         .takes_value(true),
-                )
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("three-way merge of tomb files, usable as a git merge driver")
                 .arg(
-                    Arg::with_name("url")
-                        .long("url")
-                        .short("U")
-                        .value_name("URL")
-                        .help("attach a URL to this secret, for example a login url")
-                        .takes_value(true),
+                    Arg::with_name("base")
+                        .help("the tomb file both sides were changed from")
+                        .required(true),
                 )
                 .arg(
-                    Arg::with_name("notes")
-                        .long("notes")
-                        .short("N")
-                        .value_name("NOTES")
-                        .help("attach a text to this secret, stored as plaintext")
-                        .takes_value(true),
+                    Arg::with_name("ours")
+                        .help("the tomb file to merge into, overwritten with the result")
+                        .required(true),
                 )
                 .arg(
-                    Arg::with_name("username")
-                        .long("username")
-                        .short("u")
-                        .value_name("USERNAME")
-                        .help("attach a text to this secret, stored as plaintext")
-                        .takes_value(true),
+                    Arg::with_name("theirs")
+                        .help("the tomb file to merge from")
+                        .required(true),
                 )
                 .arg(
-                    Arg::with_name("path")
-                        .value_name("KEY PATH")
-                        .help("the path to the secret")
-                        .required(true)
+                    Arg::with_name("strategy")
+                        .long("strategy")
+                        .short("s")
+                        .help("how to resolve secrets changed differently on both sides")
+                        .possible_values(&["ask", "ours", "theirs", "newest"])
+                        .default_value("ask")
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("value")
-                        .value_name("VALUE")
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
                         .required(true)
-                        .help("the secret value to be saved")
                         .takes_value(true),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("init")
-                .about("initializes a tomb file and generates a key")
+            SubCommand::with_name("verify")
+                .about("check that every secret is indexed by its path and can be decrypted")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("reindex inconsistent and duplicated secrets by their path"),
+                )
                 .arg(
                     Arg::with_name("key_filename")
                         .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
                         .short("k")
                         .default_value(&key_filename)
                         .required(true)
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("password")
-                        .long("password")
-                        .short("P")
-                        .required_unless_one(&["key_filename", "ask_password"])
-                        .takes_value(true),
-                )
-                .arg(
-                    Arg::with_name("ask_password")
-                        .long("ask-password")
-                        .short("p")
-                        .required_unless_one(&["password", "ask_password"])
-                        .takes_value(false),
-                )
-                .arg(
-                    Arg::with_name("key_cycles")
-                        .default_value("16000")
-                        .long("key")
-                        .short("K")
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
                         .takes_value(true),
-                )
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("check the format versions of the tomb, key and config files")
                 .arg(
-                    Arg::with_name("salt_cycles")
-                        .default_value("16000")
-                        .long("salt")
-                        .short("S")
-                        .takes_value(true),
+                    Arg::with_name("migrate")
+                        .long("migrate")
+                        .help("upgrade files written by older versions, keeping a copy of each"),
                 )
                 .arg(
-                    Arg::with_name("iv_cycles")
-                        .default_value("16000")
-                        .long("iv")
-                        .short("I")
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
                         .takes_value(true),
                 )
                 .arg(
@@ -502,6 +2417,80 @@ fn main() {
                         .help("the path to the tomb file containing the encrypted secrets")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("version")
+                        .long("version")
+                        .short("V")
+                        .value_name("N")
+                        .help("a previous version of the secret as listed by 'tomb history', 0 being the current one")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("KEY PATH")
+                        .help("the path to the secret")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("show the metadata of a secret, and its value with --reveal")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("field")
+                        .long("field")
+                        .short("f")
+                        .value_name("FIELD")
+                        .help("print a single field: path, name, group, username, url, notes, created_at, updated_at, value or attributes.<name>")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("KEY PATH")
+                        .help("the path to the secret")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("list the previous versions of a secret")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("path")
                         .value_name("KEY PATH")
@@ -548,9 +2537,93 @@ fn main() {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("edit")
+                .about("change the metadata, attributes or path of a secret, keeping its value")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tomb_filename")
+                        .long("tomb")
+                        .short("t")
+                        .value_name("FILENAME")
+                        .default_value(&tomb_filename)
+                        .help("the path to the tomb file containing the encrypted secrets")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("username")
+                        .long("username")
+                        .short("u")
+                        .value_name("USERNAME")
+                        .help("replace the username of this secret, an empty one removes it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .short("U")
+                        .value_name("URL")
+                        .help("replace the URL of this secret, an empty one removes it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("notes")
+                        .long("notes")
+                        .short("N")
+                        .value_name("NOTES")
+                        .help("replace the notes of this secret, empty ones remove them")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("set_attr")
+                        .long("set-attr")
+                        .value_name("NAME=VALUE")
+                        .help("set an attribute of this secret, can be repeated")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("unset_attr")
+                        .long("unset-attr")
+                        .value_name("NAME")
+                        .help("remove an attribute of this secret, can be repeated")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rename")
+                        .long("rename")
+                        .value_name("NEW PATH")
+                        .help("move this secret to another path")
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::with_name("changes")
+                        .args(&["username", "url", "notes", "set_attr", "unset_attr", "rename"])
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("KEY PATH")
+                        .help("the path to the secret")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("delete")
-                .about("delete a secret")
+                .about("move a secret to the trash")
                 .arg(
                     Arg::with_name("key_filename")
                         .long("key-filename")
@@ -605,37 +2678,508 @@ fn main() {
                         .default_value("*")
                         .takes_value(true),
                 ),
+        )
+        .subcommand(
+            SubCommand::with_name("trash")
+                .about("browse, restore and purge deleted secrets")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("list deleted secrets")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("pattern")
+                                .value_name("PATTERN")
+                                .help("the path to the secret")
+                                .default_value("*")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("restore a deleted secret")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("path")
+                                .value_name("KEY PATH")
+                                .help("the path to the secret")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("purge")
+                        .about("permanently remove deleted secrets")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("older_than")
+                                .long("older-than")
+                                .value_name("DURATION")
+                                .help("only purge secrets deleted longer ago, in days or suffixed with d, h, m or s")
+                                .default_value("30d")
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("generate a random key, or an identity to be added to tombs by its public key")
+                .arg(
+                    Arg::with_name("identity")
+                        .long("identity")
+                        .help("generate an X25519 identity instead of a key"),
+                )
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .help("the path to the aes256cbc key to generate")
+                        .short("k")
+                        .default_value(&key_filename)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("identity_filename")
+                        .long("identity-file")
+                        .short("i")
+                        .value_name("FILENAME")
+                        .default_value(&identity_filename)
+                        .help("the path to the identity to generate")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("member")
+                .about("share the tomb between several keys")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("list the members of the tomb")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("public-key")
+                        .about("print the recipient to share with members of a tomb to be added")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("identity_filename")
+                                .long("identity-file")
+                                .short("i")
+                                .value_name("FILENAME")
+                                .default_value(&identity_filename)
+                                .help("the path to the identity, which takes precedence over the key")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("wrap the data key of the tomb for another member")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .help("the name of the member")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("public_key")
+                                .value_name("PUBLIC KEY")
+                                .help("the output of 'tomb member public-key' of the new member")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("owner")
+                                .long("owner")
+                                .value_name("NAME")
+                                .help("the member name of your own key, when adding the first member")
+                                .default_value(&owner)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("remove a member and rotate the data key of the tomb")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .help("the name of the member")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("group")
+                .about("encrypt groups of secrets with their own key shared with other members")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("list the shared groups of the tomb and their members")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("share")
+                        .about("encrypt the secrets of a group with a new group key")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("group")
+                                .value_name("GROUP")
+                                .help("the group of the secrets, for example /work")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("unshare")
+                        .about("encrypt the secrets of a group with the key of the tomb again")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("group")
+                                .value_name("GROUP")
+                                .help("the group of the secrets, for example /work")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("wrap the key of a group for another member")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("group")
+                                .value_name("GROUP")
+                                .help("the group of the secrets, for example /work")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .help("the name of the member")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("public_key")
+                                .value_name("PUBLIC KEY")
+                                .help("the output of 'tomb member public-key' of the new member")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("remove a member of a group and rotate its key")
+                        .arg(
+                            Arg::with_name("key_filename")
+                                .long("key-filename")
+                                .help("the path to the aes256cbc key to encrypt the tomb secrets")
+                                .short("k")
+                                .default_value(&key_filename)
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("tomb_filename")
+                                .long("tomb")
+                                .short("t")
+                                .value_name("FILENAME")
+                                .default_value(&tomb_filename)
+                                .help("the path to the tomb file containing the encrypted secrets")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("group")
+                                .value_name("GROUP")
+                                .help("the group of the secrets, for example /work")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .help("the name of the member")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                ),
         );
 
     let matches = app.get_matches();
+    output::set_format(get_format_from_matches(&matches));
 
     match matches.subcommand() {
         ("init", Some(matches)) => {
             init_command(&matches);
         }
+        ("wrap-key", Some(matches)) => {
+            wrap_key_command(&matches);
+        }
         ("save", Some(matches)) => {
             save_command(&matches);
         }
+        ("generate", Some(matches)) => generate_command(&matches),
         ("get", Some(matches)) => {
             get_command(&matches);
         }
+        ("show", Some(matches)) => show_command(&matches),
+        ("history", Some(matches)) => {
+            history_command(&matches);
+        }
         ("copy", Some(matches)) => {
             copy_command(&matches);
         }
+        ("edit", Some(matches)) => edit_command(&matches),
         ("delete", Some(matches)) => {
             delete_command(&matches);
         }
+        ("trash", Some(matches)) => match matches.subcommand() {
+            ("list", Some(matches)) => trash_list_command(&matches),
+            ("restore", Some(matches)) => trash_restore_command(&matches),
+            ("purge", Some(matches)) => trash_purge_command(&matches),
+            (cmd, _) => fail(
+                ErrorCode::Unsupported,
+                format!("command not implemented: trash {}", cmd),
+            ),
+        },
         ("list", Some(matches)) => {
             list_command(&matches);
         }
-        ("ui", Some(matches)) => {
-            ui_command(&matches);
+        ("seal", Some(matches)) => {
+            seal_command(&matches);
         }
-        (cmd, Some(_matches)) => {
-            eprintln!("command not implemented: {}", cmd);
+        ("rekey", Some(matches)) => {
+            rekey_command(&matches);
         }
-        (cmd, None) => {
-            eprintln!("unhandled command: {}", cmd);
+        ("restore-backup", Some(matches)) => {
+            restore_backup_command(&matches);
+        }
+        ("convert", Some(matches)) => {
+            convert_command(&matches);
+        }
+        ("merge", Some(matches)) => {
+            merge_command(&matches);
+        }
+        ("doctor", Some(matches)) => {
+            doctor_command(&matches);
+        }
+        ("verify", Some(matches)) => {
+            verify_command(&matches);
+        }
+        ("keygen", Some(matches)) => {
+            keygen_command(&matches);
+        }
+        ("member", Some(matches)) => match matches.subcommand() {
+            ("list", Some(matches)) => member_list_command(&matches),
+            ("public-key", Some(matches)) => member_public_key_command(&matches),
+            ("add", Some(matches)) => member_add_command(&matches),
+            ("remove", Some(matches)) => member_remove_command(&matches),
+            (cmd, _) => fail(
+                ErrorCode::Unsupported,
+                format!("command not implemented: member {}", cmd),
+            ),
+        },
+        ("group", Some(matches)) => match matches.subcommand() {
+            ("list", Some(matches)) => group_list_command(&matches),
+            ("share", Some(matches)) => group_share_command(&matches),
+            ("unshare", Some(matches)) => group_unshare_command(&matches),
+            ("add", Some(matches)) => group_add_command(&matches),
+            ("remove", Some(matches)) => group_remove_command(&matches),
+            (cmd, _) => fail(
+                ErrorCode::Unsupported,
+                format!("command not implemented: group {}", cmd),
+            ),
+        },
+        ("ui", Some(matches)) => {
+            ui_command(&matches);
         }
+        (cmd, Some(_matches)) => fail(
+            ErrorCode::Unsupported,
+            format!("command not implemented: {}", cmd),
+        ),
+        (cmd, None) => fail(
+            ErrorCode::Unsupported,
+            format!("unhandled command: {}", cmd),
+        ),
     }
 }
@@ -1,25 +1,43 @@
-use crate::{colors, ioutils::absolute_path};
+use crate::{
+    colors,
+    ioutils::{absolute_path, write_atomic, write_private_atomic},
+    migrations::{migrate_file, migrate_yaml, DocumentKind},
+};
 
 use console::style;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use std::{
-    fs::{self, File},
-    io::Write,
-};
+use std::{fs, path::Path};
 
 pub trait YamlFileError {
     fn with_message(message: String) -> Self;
 }
 
 pub trait YamlFile<Error: YamlFileError> {
+    /// The kind of document whose older format versions are migrated by
+    /// `from_yaml` and `import`, see `crate::migrations`
+    fn document_kind() -> Option<DocumentKind> {
+        None
+    }
+    /// Whether `export` creates files only readable by the current
+    /// user, for files holding key material
+    fn is_private() -> bool {
+        false
+    }
     fn from_yaml<'a>(data: String) -> Result<Self, Error>
     where
         Self: DeserializeOwned,
-        Self: Clone,
         Self: PartialEq,
     {
+        let data = match Self::document_kind() {
+            Some(kind) => match migrate_yaml(kind, &data) {
+                Ok(Some(migrated)) => migrated,
+                Ok(None) => data,
+                Err(error) => return Err(Error::with_message(format!("{}", error))),
+            },
+            None => data,
+        };
         let cfg: Self = match serde_yaml::from_str(&data) {
             Ok(config) => config,
             Err(error) => {
@@ -54,10 +72,16 @@ pub trait YamlFile<Error: YamlFileError> {
     fn import(filename: &str) -> Result<Self, Error>
     where
         Self: DeserializeOwned,
-        Self: Clone,
         Self: PartialEq,
     {
         let filename = absolute_path(filename);
+        if let Some(kind) = Self::document_kind() {
+            if Path::new(&filename).exists() {
+                if let Err(error) = migrate_file(kind, &filename) {
+                    return Err(Error::with_message(format!("{}", error)));
+                }
+            }
+        }
         match fs::read_to_string(filename.as_str()) {
             Ok(yaml) => YamlFile::from_yaml(yaml),
             Err(error) => {
@@ -81,18 +105,13 @@ pub trait YamlFile<Error: YamlFileError> {
             Ok(val) => val,
             Err(error) => return Err(error),
         };
-        let mut file = match File::create(filename.as_str()) {
-            Ok(file) => file,
-            Err(error) => {
-                return Err(Error::with_message(format!(
-                    "{}{}{}",
-                    style("failed to create file ").color256(colors::ERR_MSG),
-                    style(filename).color256(colors::ERR_VAR),
-                    style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
-                )))
-            }
+        let written = match Self::is_private() {
+            true => write_private_atomic(filename.as_str(), yaml.as_bytes()),
+            false => write_atomic(filename.as_str(), yaml.as_bytes()),
         };
-        file.write(yaml.as_ref()).unwrap();
-        Ok(filename)
+        match written {
+            Ok(_) => Ok(filename),
+            Err(error) => Err(Error::with_message(format!("{}", error))),
+        }
     }
 }
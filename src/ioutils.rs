@@ -2,15 +2,18 @@ use crate::colors;
 use crate::logger;
 use chrono::prelude::*;
 use console::style;
+use fs2::FileExt;
 use shellexpand;
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fmt::Formatter;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Read, Write};
 use std::ops::Deref;
 use std::path::Path;
-use std::{env, fmt, fs};
+use std::time::{Duration, Instant};
+use std::{env, fmt, fs, thread};
 
 pub fn absolute_path(src: &str) -> String {
     String::from(shellexpand::tilde(src))
@@ -120,6 +123,7 @@ pub fn open_write(target: &str) -> Result<std::fs::File, TombError> {
     OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(target.as_str())
         .map_err(|error| {
             TombError::with_message(format!(
@@ -176,6 +180,217 @@ pub fn read_file(filename: &str) -> Result<String, TombError> {
     Ok(text)
 }
 
+/// Writes `data` to a temporary file in the directory of `target`,
+/// syncs it to disk and renames it over `target`, so that a crash or
+/// a full disk never leaves `target` truncated. The permissions of an
+/// existing `target` are kept.
+pub fn write_atomic(target: &str, data: &[u8]) -> Result<(), TombError> {
+    write_atomic_with_mode(target, data, false)
+}
+
+/// Writes `data` like `write_atomic`, creating `target` only readable
+/// by the current user when it does not exist yet, for key material
+pub fn write_private_atomic(target: &str, data: &[u8]) -> Result<(), TombError> {
+    write_atomic_with_mode(target, data, true)
+}
+
+fn write_atomic_with_mode(target: &str, data: &[u8], private: bool) -> Result<(), TombError> {
+    let target = absolute_path(target);
+    let path = Path::new(&target);
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let basename = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => {
+            return Err(TombError::with_message(format!(
+                "{}{}",
+                style("not a file path: ").color256(colors::ERR_MSG),
+                style(&target).color256(colors::ERR_VAR),
+            )))
+        }
+    };
+    let temporary = directory.join(format!(".{}.tmp.{}", basename, std::process::id()));
+    let failed = |action: &str, error: std::io::Error| {
+        let _ = fs::remove_file(&temporary);
+        TombError::with_message(format!(
+            "{}{}{}",
+            style(format!("failed to {} ", action)).color256(colors::ERR_MSG),
+            style(&target).color256(colors::ERR_VAR),
+            style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
+        ))
+    };
+    // a leftover of a crashed process would keep its own permissions
+    let _ = fs::remove_file(&temporary);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    let mut file = options
+        .open(&temporary)
+        .map_err(|error| failed("create a temporary file for", error))?;
+    if let Ok(metadata) = fs::metadata(&target) {
+        file.set_permissions(metadata.permissions())
+            .map_err(|error| failed("copy the permissions of", error))?;
+    }
+    file.write_all(data)
+        .map_err(|error| failed("write", error))?;
+    file.sync_all().map_err(|error| failed("sync", error))?;
+    drop(file);
+    fs::rename(&temporary, &target).map_err(|error| failed("replace", error))?;
+    // persist the rename itself, not supported on every platform
+    if let Ok(directory) = File::open(&directory) {
+        let _ = directory.sync_all();
+    }
+    Ok(())
+}
+
+/// How long `lock_file` waits for another process to release a lock
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+thread_local! {
+    /// Lock files held by this thread, see `lock_file`
+    static HELD_LOCKS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Advisory exclusive lock on `target`, released when dropped
+pub struct FileLock {
+    filename: String,
+    /// `None` when the lock was already held by this thread
+    file: Option<File>,
+}
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            // removed while still locked, processes waiting on it
+            // notice and lock the file created next instead
+            let _ = fs::remove_file(&self.filename);
+            let _ = file.unlock();
+            HELD_LOCKS.with(|held| held.borrow_mut().remove(&self.filename));
+        }
+    }
+}
+
+/// Whether the locked `file` is still the lock file at `filename`,
+/// rather than one removed by the process that released it
+#[cfg(unix)]
+fn is_current_lock(file: &File, filename: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(filename)) {
+        (Ok(locked), Ok(current)) => locked.dev() == current.dev() && locked.ino() == current.ino(),
+        _ => false,
+    }
+}
+#[cfg(not(unix))]
+fn is_current_lock(_file: &File, _filename: &str) -> bool {
+    true
+}
+
+/// Locks `target` through a `target.lock` file, waiting up to
+/// `LOCK_TIMEOUT` for other processes to release it.
+///
+/// The lock file is separate from `target` because `write_atomic`
+/// replaces `target` with a new file, and it is removed when the lock
+/// is released. Locking a file this thread already holds succeeds
+/// right away, so that a command can hold the lock from loading the
+/// tomb until it is saved.
+pub fn lock_file(target: &str) -> Result<FileLock, TombError> {
+    let filename = format!("{}.lock", absolute_path(target));
+    if HELD_LOCKS.with(|held| held.borrow().contains(&filename)) {
+        return Ok(FileLock {
+            filename,
+            file: None,
+        });
+    }
+    let started = Instant::now();
+    let file = loop {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&filename)
+            .map_err(|error| {
+                TombError::with_message(format!(
+                    "{}{}{}",
+                    style("failed to open lock file ").color256(colors::ERR_MSG),
+                    style(&filename).color256(colors::ERR_VAR),
+                    style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
+                ))
+            })?;
+        while file.try_lock_exclusive().is_err() {
+            if started.elapsed() > LOCK_TIMEOUT {
+                return Err(TombError::with_message(format!(
+                    "{}{}{}",
+                    style("timed out waiting for another process to release ")
+                        .color256(colors::ERR_MSG),
+                    style(&filename).color256(colors::ERR_VAR),
+                    style(format!(" after {:?}", LOCK_TIMEOUT)).color256(colors::ERR_MSG),
+                )));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        if is_current_lock(&file, &filename) {
+            break file;
+        }
+    };
+    HELD_LOCKS.with(|held| held.borrow_mut().insert(filename.clone()));
+    Ok(FileLock {
+        filename,
+        file: Some(file),
+    })
+}
+
+/// The path of the given generation of backups of `target`
+pub fn backup_filename(target: &str, generation: usize) -> String {
+    format!("{}.bak.{}", absolute_path(target), generation)
+}
+
+/// Keeps a copy of the current contents of `target` as generation 1,
+/// shifting the older generations and dropping the ones beyond
+/// `generations`
+pub fn rotate_backups(target: &str, generations: usize) -> Result<(), TombError> {
+    let target = absolute_path(target);
+    if generations == 0 || !Path::new(&target).exists() {
+        return Ok(());
+    }
+    let failed = |filename: &str, error: std::io::Error| {
+        TombError::with_message(format!(
+            "{}{}{}",
+            style("failed to rotate backup ").color256(colors::ERR_MSG),
+            style(filename).color256(colors::ERR_VAR),
+            style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
+        ))
+    };
+    for generation in (1..generations).rev() {
+        let older = backup_filename(&target, generation);
+        if Path::new(&older).exists() {
+            fs::rename(&older, backup_filename(&target, generation + 1))
+                .map_err(|error| failed(&older, error))?;
+        }
+    }
+    let newest = backup_filename(&target, 1);
+    fs::copy(&target, &newest).map_err(|error| failed(&newest, error))?;
+    Ok(())
+}
+
+/// The generations and paths of the existing backups of `target`
+pub fn list_backups(target: &str) -> Vec<(usize, String)> {
+    let mut backups = Vec::new();
+    let mut generation = 1;
+    loop {
+        let filename = backup_filename(target, generation);
+        if !Path::new(&filename).exists() {
+            break;
+        }
+        backups.push((generation, filename));
+        generation += 1;
+    }
+    backups
+}
+
 /// Encodes &[u8] to a base64 string
 ///
 /// # Example
@@ -231,6 +446,83 @@ pub fn read_bytes(filename: &str) -> Result<Vec<u8>, TombError> {
     Ok(buffer)
 }
 
+/// Creates a new file only readable by the current user in the
+/// temporary directory, for plaintext handed to other programs such as
+/// an editor, to be removed with `shred_file`
+pub fn create_private_file(prefix: &str) -> Result<(String, File), TombError> {
+    let filename = env::temp_dir()
+        .join(format!(
+            ".{}.{}.{:016x}",
+            prefix,
+            std::process::id(),
+            rand::random::<u64>()
+        ))
+        .to_string_lossy()
+        .to_string();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match options.open(&filename) {
+        Ok(file) => Ok((filename, file)),
+        Err(error) => Err(TombError::with_message(format!(
+            "{}{}{}",
+            style("creating private file ").color256(colors::ERR_MSG),
+            style(&filename).color256(colors::ERR_VAR),
+            style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
+        ))),
+    }
+}
+
+/// Creates an empty `target` only readable by the current user, or
+/// with the permissions of `reference` when it exists, so that key
+/// material written to it afterwards is never exposed by the umask
+pub fn create_private_file_like(target: &str, reference: &str) -> Result<(), TombError> {
+    let target = absolute_path(target);
+    let failed = |error: std::io::Error| {
+        TombError::with_message(format!(
+            "{}{}{}",
+            style("creating private file ").color256(colors::ERR_MSG),
+            style(&target).color256(colors::ERR_VAR),
+            style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
+        ))
+    };
+    // a leftover would keep its own permissions
+    let _ = fs::remove_file(&target);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(&target).map_err(failed)?;
+    if let Ok(metadata) = fs::metadata(absolute_path(reference)) {
+        file.set_permissions(metadata.permissions())
+            .map_err(failed)?;
+    }
+    Ok(())
+}
+
+/// Overwrites a file with zeros before removing it, so that its
+/// contents do not linger in the freed blocks
+pub fn shred_file(target: &str) -> Result<(), TombError> {
+    let failed = |error: std::io::Error| {
+        TombError::with_message(format!(
+            "{}{}{}",
+            style("shredding ").color256(colors::ERR_MSG),
+            style(target).color256(colors::ERR_VAR),
+            style(format!("\n\t{}", error)).color256(colors::ERR_HLT),
+        ))
+    };
+    let length = fs::metadata(target).map_err(failed)?.len() as usize;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(target)
+        .map_err(failed)?;
+    file.write_all(&vec![0u8; length]).map_err(failed)?;
+    file.sync_all().map_err(failed)?;
+    drop(file);
+    fs::remove_file(target).map_err(failed)
+}
+
 pub fn delete_file(target: &str) -> Result<(), TombError> {
     fs::remove_file(target)
         .and_then(|_| {
@@ -358,4 +650,83 @@ mod tests {
             )
         )
     }
+
+    #[test]
+    fn test_private_file_is_shredded() {
+        let (filename, mut file) = create_private_file("tomb-test").unwrap();
+        file.write_all(b"123456").unwrap();
+        drop(file);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&filename).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        shred_file(&filename).unwrap();
+        assert_eq!(Path::new(&filename).exists(), false);
+    }
+
+    #[test]
+    fn test_write_atomic_with_backups() {
+        let directory = env::temp_dir().join(format!("tomb-backups-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let target = directory.join("tomb.yaml").to_string_lossy().to_string();
+
+        for generation in 0..4 {
+            rotate_backups(&target, 2).unwrap();
+            write_atomic(&target, format!("generation {}", generation).as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&target).unwrap(), "generation 3");
+        assert_eq!(list_backups(&target).len(), 2);
+        assert_eq!(
+            fs::read_to_string(backup_filename(&target, 1)).unwrap(),
+            "generation 2"
+        );
+        assert_eq!(
+            fs::read_to_string(backup_filename(&target, 2)).unwrap(),
+            "generation 1"
+        );
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 3);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let directory = env::temp_dir().join(format!("tomb-permissions-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let key = directory.join("tomb.key").to_string_lossy().to_string();
+        let mode = |filename: &str| fs::metadata(filename).unwrap().permissions().mode() & 0o777;
+
+        write_private_atomic(&key, b"key").unwrap();
+        assert_eq!(mode(&key), 0o600);
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&key, b"new key").unwrap();
+        assert_eq!(mode(&key), 0o640);
+        assert_eq!(fs::read_to_string(&key).unwrap(), "new key");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let target = env::temp_dir().join(format!("tomb-lock-{}.yaml", std::process::id()));
+        let target = target.to_string_lossy().to_string();
+        let lock = lock_file(&target).unwrap();
+        let nested = lock_file(&target).unwrap();
+        drop(nested);
+        let other = OpenOptions::new()
+            .write(true)
+            .open(format!("{}.lock", target))
+            .unwrap();
+        assert_eq!(other.try_lock_exclusive().is_err(), true);
+        drop(lock);
+        assert_eq!(other.try_lock_exclusive().is_ok(), true);
+        // the released lock file is removed, the stale one held by
+        // `other` does not keep the next lock from being taken
+        assert_eq!(Path::new(&format!("{}.lock", target)).exists(), false);
+        let next = lock_file(&target).unwrap();
+        drop(next);
+        assert_eq!(Path::new(&format!("{}.lock", target)).exists(), false);
+    }
 }
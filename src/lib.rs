@@ -6,4 +6,7 @@ pub mod core;
 pub mod ioutils;
 pub mod ironpunk;
 pub mod logger;
+pub mod migrations;
+pub mod output;
+pub mod sensitive;
 pub mod tomb;
@@ -73,40 +73,72 @@ pub mod format {
 
 pub mod out {
     use super::format;
+    use crate::output;
 
     pub fn warning(msg: String) {
+        if output::is_structured() {
+            return output::print_message("warning", &msg, false);
+        }
         println!("{}", format::warning(msg));
     }
     pub fn ok(msg: String) {
+        if output::is_structured() {
+            return output::print_message("ok", &msg, false);
+        }
         println!("{}", format::ok(msg));
     }
     pub fn error(msg: String) {
+        if output::is_structured() {
+            return output::print_message("error", &msg, false);
+        }
         println!("{}", format::error(msg));
     }
     pub fn info(msg: String) {
+        if output::is_structured() {
+            return output::print_message("info", &msg, false);
+        }
         println!("{}", format::info(msg));
     }
     pub fn success(msg: String) {
+        if output::is_structured() {
+            return output::print_message("success", &msg, false);
+        }
         println!("{}", format::success(msg));
     }
 }
 
 pub mod err {
     use super::format;
+    use crate::output;
 
     pub fn warning(msg: String) {
+        if output::is_structured() {
+            return output::print_message("warning", &msg, true);
+        }
         eprintln!("{}", format::warning(msg));
     }
     pub fn ok(msg: String) {
+        if output::is_structured() {
+            return output::print_message("ok", &msg, true);
+        }
         eprintln!("{}", format::ok(msg));
     }
     pub fn error(msg: String) {
+        if output::is_structured() {
+            return output::print_message("error", &msg, true);
+        }
         eprintln!("{}", format::error(msg));
     }
     pub fn info(msg: String) {
+        if output::is_structured() {
+            return output::print_message("info", &msg, true);
+        }
         eprintln!("{}", format::info(msg));
     }
     pub fn success(msg: String) {
+        if output::is_structured() {
+            return output::print_message("success", &msg, true);
+        }
         eprintln!("{}", format::success(msg));
     }
 }
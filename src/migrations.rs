@@ -0,0 +1,305 @@
+//! Format versions of tomb, key and config files
+//!
+//! Every document stores the `format_version` of its serde layout,
+//! files written before versions were introduced are version 1. Older
+//! documents are upgraded on load by the migrations registered for
+//! their `DocumentKind`, which operate on the yaml before it is
+//! deserialized, and documents from a newer version are refused.
+//!
+//! Tombs reach `TOMB_AUTHENTICATED_VERSION` only once their values
+//! are re-encrypted with the key, see `AES256Tomb::authenticate`.
+use crate::ioutils::{absolute_path, write_atomic, TombError};
+use crate::logger;
+use console::style;
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::fs;
+
+pub const TOMB_FORMAT_VERSION: u32 = 3;
+/// The tomb format version from which every value carries an
+/// authentication tag, values of older tombs may predate tags
+pub const TOMB_AUTHENTICATED_VERSION: u32 = 3;
+pub const KEY_FORMAT_VERSION: u32 = 2;
+pub const CONFIG_FORMAT_VERSION: u32 = 2;
+pub const IDENTITY_FORMAT_VERSION: u32 = 1;
+/// The version of files written before format versions were introduced
+pub const UNVERSIONED: u32 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DocumentKind {
+    Tomb,
+    Key,
+    Config,
+    Identity,
+}
+
+/// Upgrades a document from version `from` to `from + 1`, the new
+/// `format_version` is set once `apply` succeeds
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub apply: fn(&mut Mapping) -> Result<(), TombError>,
+}
+
+const TOMB_MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "store the format version and the key derivation function, fields added since default to empty",
+    apply: tomb_v1,
+}];
+const KEY_MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "store the format version",
+    apply: unversioned,
+}];
+const CONFIG_MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "store the format version, history_retention defaults to TOMB_HISTORY",
+    apply: unversioned,
+}];
+
+/// Fields added to unversioned documents are deserialized with their
+/// serde defaults, only the version needs to be stored
+fn unversioned(_document: &mut Mapping) -> Result<(), TombError> {
+    Ok(())
+}
+
+/// Unversioned tombs derived their key with PBKDF2 before the key
+/// derivation function became part of the config, it is stored so that
+/// they keep opening with their password whatever the default becomes
+fn tomb_v1(document: &mut Mapping) -> Result<(), TombError> {
+    let config = match document.get_mut(&Value::String(String::from("config"))) {
+        Some(Value::Mapping(config)) => config,
+        _ => return Ok(()),
+    };
+    let kdf = Value::String(String::from("kdf"));
+    if !config.contains_key(&kdf) {
+        let mut pbkdf2 = Mapping::new();
+        pbkdf2.insert(
+            Value::String(String::from("algorithm")),
+            Value::String(String::from("pbkdf2")),
+        );
+        config.insert(kdf, Value::Mapping(pbkdf2));
+    }
+    Ok(())
+}
+
+impl DocumentKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DocumentKind::Tomb => "tomb",
+            DocumentKind::Key => "key",
+            DocumentKind::Config => "config",
+            DocumentKind::Identity => "identity",
+        }
+    }
+    /// The version written by this build
+    pub fn current_version(&self) -> u32 {
+        match self {
+            DocumentKind::Tomb => TOMB_FORMAT_VERSION,
+            DocumentKind::Key => KEY_FORMAT_VERSION,
+            DocumentKind::Config => CONFIG_FORMAT_VERSION,
+            DocumentKind::Identity => IDENTITY_FORMAT_VERSION,
+        }
+    }
+    /// The version reached by the migrations of the yaml, below
+    /// `current_version` for tombs whose values need the key to be
+    /// upgraded
+    pub fn migrated_version(&self) -> u32 {
+        match self {
+            DocumentKind::Tomb => TOMB_AUTHENTICATED_VERSION - 1,
+            kind => kind.current_version(),
+        }
+    }
+    pub fn migrations(&self) -> &'static [Migration] {
+        match self {
+            DocumentKind::Tomb => TOMB_MIGRATIONS,
+            DocumentKind::Key => KEY_MIGRATIONS,
+            DocumentKind::Config => CONFIG_MIGRATIONS,
+            DocumentKind::Identity => &[],
+        }
+    }
+}
+
+pub fn unversioned_format() -> u32 {
+    UNVERSIONED
+}
+pub fn tomb_format_version() -> u32 {
+    TOMB_FORMAT_VERSION
+}
+pub fn key_format_version() -> u32 {
+    KEY_FORMAT_VERSION
+}
+pub fn config_format_version() -> u32 {
+    CONFIG_FORMAT_VERSION
+}
+pub fn identity_format_version() -> u32 {
+    IDENTITY_FORMAT_VERSION
+}
+
+/// Reads only the version, so that documents holding key material
+/// are not copied around unless they need to be migrated
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(default = "unversioned_format")]
+    format_version: u32,
+}
+
+/// The format version of a yaml document
+pub fn format_version(kind: DocumentKind, yaml: &str) -> Result<u32, TombError> {
+    let version = match serde_yaml::from_str::<Versioned>(yaml) {
+        Ok(versioned) => versioned.format_version,
+        Err(error) => {
+            return Err(TombError::with_message(format!(
+                "failed to read the format version of {} file: {}",
+                kind.name(),
+                error
+            )))
+        }
+    };
+    if version > kind.current_version() {
+        return Err(TombError::with_message(format!(
+            "{}{}{}",
+            style(format!("{} file format version ", kind.name())).color256(198),
+            style(version).color256(190),
+            style(format!(
+                " is newer than the supported version {}, please upgrade tomb",
+                kind.current_version()
+            ))
+            .color256(198),
+        )));
+    }
+    Ok(version)
+}
+
+/// Applies the migrations from the version of the document up to the
+/// current one, `None` when it is up to date
+pub fn migrate_yaml(kind: DocumentKind, yaml: &str) -> Result<Option<String>, TombError> {
+    let mut version = format_version(kind, yaml)?;
+    if version >= kind.migrated_version() {
+        return Ok(None);
+    }
+    let mut document: Mapping = match serde_yaml::from_str(yaml) {
+        Ok(document) => document,
+        Err(error) => {
+            return Err(TombError::with_message(format!(
+                "failed to deserialize {} file for migration: {}",
+                kind.name(),
+                error
+            )))
+        }
+    };
+    for migration in kind.migrations() {
+        if migration.from != version {
+            continue;
+        }
+        (migration.apply)(&mut document)?;
+        version += 1;
+        document.insert(
+            Value::String(String::from("format_version")),
+            Value::Number(version.into()),
+        );
+    }
+    if version != kind.migrated_version() {
+        return Err(TombError::with_message(format!(
+            "no migration of {} files from format version {}",
+            kind.name(),
+            version
+        )));
+    }
+    match serde_yaml::to_string(&document) {
+        Ok(yaml) => Ok(Some(yaml)),
+        Err(error) => Err(TombError::with_message(format!(
+            "failed to serialize migrated {} file: {}",
+            kind.name(),
+            error
+        ))),
+    }
+}
+
+/// The path of the copy of `filename` kept before migrating it from
+/// `version`
+pub fn migration_backup_filename(filename: &str, version: u32) -> String {
+    format!("{}.v{}.bak", absolute_path(filename), version)
+}
+
+/// Migrates a yaml file in place after copying it to
+/// `migration_backup_filename`, returning the backup when it was migrated
+pub fn migrate_file(kind: DocumentKind, filename: &str) -> Result<Option<String>, TombError> {
+    let filename = absolute_path(filename);
+    let yaml = match fs::read_to_string(&filename) {
+        Ok(yaml) => yaml,
+        Err(error) => {
+            return Err(TombError::with_message(format!(
+                "{}{}{}",
+                style("failed to read file ").color256(198),
+                style(&filename).color256(190),
+                style(format!("\n\t{}", error)).color256(197),
+            )))
+        }
+    };
+    let version = format_version(kind, &yaml)?;
+    let migrated = match migrate_yaml(kind, &yaml)? {
+        Some(migrated) => migrated,
+        None => return Ok(None),
+    };
+    let backup = migration_backup_filename(&filename, version);
+    if let Err(error) = fs::copy(&filename, &backup) {
+        return Err(TombError::with_message(format!(
+            "{}{}{}",
+            style("failed to back up file before migrating it ").color256(198),
+            style(&backup).color256(190),
+            style(format!("\n\t{}", error)).color256(197),
+        )));
+    }
+    write_atomic(&filename, migrated.as_bytes())?;
+    logger::err::info(format!(
+        "migrated {} file {} from format version {} to {}, previous version kept in {}",
+        kind.name(),
+        filename,
+        version,
+        kind.migrated_version(),
+        backup
+    ));
+    Ok(Some(backup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k9::assert_equal;
+
+    #[test]
+    fn test_migrate_unversioned_yaml() {
+        let yaml = "digest: []\nversion: 0.1.0\n";
+        assert_equal!(
+            format_version(DocumentKind::Tomb, yaml).unwrap(),
+            UNVERSIONED
+        );
+        let migrated = migrate_yaml(DocumentKind::Tomb, yaml).unwrap().unwrap();
+        assert_equal!(
+            format_version(DocumentKind::Tomb, &migrated).unwrap(),
+            TOMB_AUTHENTICATED_VERSION - 1
+        );
+        assert_equal!(migrated.contains("version: 0.1.0"), true);
+        assert_equal!(migrate_yaml(DocumentKind::Tomb, &migrated).unwrap(), None);
+    }
+    #[test]
+    fn test_migrate_v1_tomb() {
+        let yaml = include_str!("tomb/fixtures/tomb-v1.yaml");
+        assert_equal!(
+            format_version(DocumentKind::Tomb, yaml).unwrap(),
+            UNVERSIONED
+        );
+        assert_equal!(yaml.contains("kdf"), false);
+        let migrated = migrate_yaml(DocumentKind::Tomb, yaml).unwrap().unwrap();
+        let document: Mapping = serde_yaml::from_str(&migrated).unwrap();
+        let config = &document[&Value::String(String::from("config"))];
+        assert_equal!(config["kdf"]["algorithm"].as_str(), Some("pbkdf2"));
+    }
+    #[test]
+    fn test_refuse_newer_format_version() {
+        let yaml = format!("format_version: {}\n", KEY_FORMAT_VERSION + 1);
+        assert_equal!(format_version(DocumentKind::Key, &yaml).is_err(), true);
+        assert_equal!(migrate_yaml(DocumentKind::Key, &yaml).is_err(), true);
+    }
+}
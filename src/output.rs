@@ -0,0 +1,310 @@
+//! Output of the command-line in the format chosen with `--format`
+//!
+//! `table` prints text for people, colored when the output is a
+//! terminal. `json` and `yaml` print records, `env` prints them as
+//! shell assignments, and in all three the messages of `logger` become
+//! records too and errors are printed by `fail` with a stable code.
+use crate::tomb::AES256Secret;
+use serde_json::{json, Map, Value};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+    Yaml,
+    Env,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 4] = ["table", "json", "yaml", "env"];
+
+    pub fn name(&self) -> &'static str {
+        Format::NAMES[*self as usize]
+    }
+    /// Whether records are printed rather than text
+    pub fn is_structured(&self) -> bool {
+        *self != Format::Table
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Format, String> {
+        match name {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            "env" => Ok(Format::Env),
+            _ => Err(format!("unknown output format: {}", name)),
+        }
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Table as u8);
+
+/// Selects the format of the output of this process and disables
+/// colors unless it is a table printed to a terminal
+pub fn set_format(format: Format) {
+    FORMAT.store(format as u8, Ordering::SeqCst);
+    console::set_colors_enabled(!format.is_structured() && console::Term::stdout().is_term());
+    console::set_colors_enabled_stderr(
+        !format.is_structured() && console::Term::stderr().is_term(),
+    );
+}
+
+pub fn format() -> Format {
+    match FORMAT.load(Ordering::SeqCst) {
+        1 => Format::Json,
+        2 => Format::Yaml,
+        3 => Format::Env,
+        _ => Format::Table,
+    }
+}
+
+pub fn is_structured() -> bool {
+    format().is_structured()
+}
+
+/// Stable codes of the errors printed by `fail`, part of the interface
+/// of `--format json|yaml|env` and documented in the README
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// A command-line argument or option is invalid
+    InvalidArgument,
+    /// The key or identity cannot be read, derived or used
+    KeyError,
+    /// A tomb, config or key file cannot be read or parsed
+    LoadFailed,
+    /// The key is not allowed to decrypt the tomb or group
+    AccessDenied,
+    /// No secret, member, group or backup goes by the given name
+    NotFound,
+    /// The target already exists
+    AlreadyExists,
+    /// A secret cannot be decrypted
+    DecryptFailed,
+    /// The tomb cannot be saved, for example because another process
+    /// modified it since it was loaded
+    SaveFailed,
+    /// Secrets changed differently on both sides of a merge
+    MergeConflict,
+    /// `tomb verify` found issues
+    VerificationFailed,
+    /// A file cannot be written, moved or removed
+    IoError,
+    /// Any other failure of the requested operation
+    OperationFailed,
+    /// The command is not supported
+    Unsupported,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidArgument => "invalid_argument",
+            ErrorCode::KeyError => "key_error",
+            ErrorCode::LoadFailed => "load_failed",
+            ErrorCode::AccessDenied => "access_denied",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::AlreadyExists => "already_exists",
+            ErrorCode::DecryptFailed => "decrypt_failed",
+            ErrorCode::SaveFailed => "save_failed",
+            ErrorCode::MergeConflict => "merge_conflict",
+            ErrorCode::VerificationFailed => "verification_failed",
+            ErrorCode::IoError => "io_error",
+            ErrorCode::OperationFailed => "operation_failed",
+            ErrorCode::Unsupported => "unsupported",
+        }
+    }
+}
+
+/// Prints the error to stderr, as a record in structured formats, and
+/// exits with status 1
+pub fn fail<M: Display>(code: ErrorCode, message: M) -> ! {
+    let message = console::strip_ansi_codes(&format!("{}", message)).to_string();
+    match format() {
+        Format::Table => crate::logger::err::error(message),
+        _ => eprintln!(
+            "{}",
+            json!({"error": {"code": code.as_str(), "message": message}})
+        ),
+    }
+    std::process::exit(1)
+}
+
+/// A status message of `logger` as a record, printed to stdout as a
+/// comment in the env format
+pub fn print_message(level: &str, message: &str, stderr: bool) {
+    let message = console::strip_ansi_codes(message);
+    let line = match format() {
+        Format::Env => format!("# {}: {}", level, message),
+        _ => json!({"level": level, "message": message}).to_string(),
+    };
+    if stderr {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// The name of the shell variable of `path`: its words uppercased and
+/// joined by underscores
+pub fn env_name(path: &str) -> String {
+    let name: Vec<String> = path
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_ascii_uppercase())
+        .collect();
+    let name = name.join("_");
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() => name,
+        _ => format!("_{}", name),
+    }
+}
+
+fn env_quote(value: &Value) -> String {
+    let value = match value {
+        Value::String(value) => value.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    };
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// The shell assignments of a record, named after its `path` or `name`:
+/// `NAME=value` for its value and `NAME_FIELD=...` for its other fields
+pub fn env_lines(record: &Value) -> Vec<String> {
+    let fields = match record.as_object() {
+        Some(fields) => fields,
+        None => return vec![format!("VALUE={}", env_quote(record))],
+    };
+    let prefix = ["path", "name", "group"]
+        .iter()
+        .find_map(|field| fields.get(*field).and_then(Value::as_str))
+        .map(env_name);
+    let variable = |field: &str| match &prefix {
+        Some(prefix) if field == "value" => prefix.clone(),
+        Some(prefix) => format!("{}_{}", prefix, env_name(field)),
+        None => env_name(field),
+    };
+    let mut lines = Vec::new();
+    for (field, value) in fields {
+        match value {
+            Value::Null => {}
+            Value::Object(nested) => {
+                for (name, value) in nested {
+                    lines.push(format!(
+                        "{}_{}={}",
+                        variable(field),
+                        env_name(name),
+                        env_quote(value)
+                    ));
+                }
+            }
+            Value::Array(items) => {
+                let items: Vec<String> = items
+                    .iter()
+                    .map(|item| match item {
+                        Value::String(item) => item.clone(),
+                        item => item.to_string(),
+                    })
+                    .collect();
+                lines.push(format!(
+                    "{}={}",
+                    variable(field),
+                    env_quote(&Value::String(items.join(" ")))
+                ));
+            }
+            value => lines.push(format!("{}={}", variable(field), env_quote(value))),
+        }
+    }
+    lines
+}
+
+fn render(value: &Value) -> String {
+    match format() {
+        Format::Yaml => serde_yaml::to_string(value).unwrap(),
+        Format::Env => match value {
+            Value::Array(records) => records
+                .iter()
+                .flat_map(env_lines)
+                .collect::<Vec<String>>()
+                .join("\n"),
+            record => env_lines(record).join("\n"),
+        },
+        _ => serde_json::to_string_pretty(value).unwrap(),
+    }
+}
+
+/// Prints a single record in the structured format
+pub fn print_record(record: Value) {
+    let output = render(&record);
+    if !output.is_empty() {
+        println!("{}", output.trim_end());
+    }
+}
+
+/// Prints records as a list in the structured format
+pub fn print_records(records: Vec<Value>) {
+    print_record(Value::Array(records))
+}
+
+/// The fields of a secret shown by `tomb list` and `tomb get`, along
+/// with the shared group it belongs to and, when revealed, its value
+pub fn secret_record(
+    secret: &AES256Secret,
+    shared_group: Option<&str>,
+    value: Option<&str>,
+) -> Value {
+    let mut record = Map::new();
+    record.insert(String::from("path"), json!(secret.path));
+    record.insert(String::from("group"), json!(secret.group()));
+    record.insert(String::from("shared_group"), json!(shared_group));
+    record.insert(String::from("username"), json!(secret.username));
+    record.insert(String::from("url"), json!(secret.url));
+    record.insert(String::from("notes"), json!(secret.notes));
+    record.insert(String::from("attributes"), json!(secret.attributes));
+    record.insert(String::from("created_at"), json!(secret.created_at));
+    record.insert(String::from("updated_at"), json!(secret.updated_at));
+    if let Some(deleted_at) = secret.deleted_at {
+        record.insert(String::from("deleted_at"), json!(deleted_at));
+    }
+    if let Some(value) = value {
+        record.insert(String::from("value"), json!(value));
+    }
+    Value::Object(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{env_lines, env_name, Format};
+    use k9::assert_equal;
+    use serde_json::json;
+
+    #[test]
+    fn test_env_lines() {
+        assert_equal!("yaml".parse::<Format>(), Ok(Format::Yaml));
+        assert_equal!("xml".parse::<Format>().is_err(), true);
+        assert_equal!(env_name("/work/aws-key@prod").as_str(), "WORK_AWS_KEY_PROD");
+        assert_equal!(env_name("1password").as_str(), "_1PASSWORD");
+        assert_equal!(
+            env_lines(&json!({
+                "path": "work/aws",
+                "username": "bob",
+                "url": null,
+                "attributes": {"region": "eu-west-1"},
+                "value": "it's",
+            })),
+            vec![
+                String::from("WORK_AWS_ATTRIBUTES_REGION='eu-west-1'"),
+                String::from("WORK_AWS_PATH='work/aws'"),
+                String::from("WORK_AWS_USERNAME='bob'"),
+                String::from("WORK_AWS='it'\\''s'"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Containers for decrypted plaintext and key material
+//!
+//! `Sensitive` wipes its contents from memory when dropped and never
+//! prints them through `Debug`.
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::str::Utf8Error;
+use zeroize::Zeroize;
+
+pub struct Sensitive<T: Zeroize>(T);
+
+/// Decrypted bytes
+pub type SensitiveBytes = Sensitive<Vec<u8>>;
+/// Decrypted utf-8 text
+pub type SensitiveString = Sensitive<String>;
+
+impl<T: Zeroize> Sensitive<T> {
+    pub fn new(value: T) -> Sensitive<T> {
+        Sensitive(value)
+    }
+}
+impl SensitiveBytes {
+    /// Converts into a `SensitiveString` without leaving a copy
+    /// of the bytes behind when they are not valid utf-8
+    pub fn into_string(mut self) -> Result<SensitiveString, Utf8Error> {
+        match String::from_utf8(std::mem::take(&mut self.0)) {
+            Ok(value) => Ok(Sensitive(value)),
+            Err(error) => {
+                let reason = error.utf8_error();
+                error.into_bytes().zeroize();
+                Err(reason)
+            }
+        }
+    }
+}
+impl<T: Zeroize> Drop for Sensitive<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+impl<T: Zeroize> Deref for Sensitive<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+impl<T: Zeroize> DerefMut for Sensitive<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+impl<T: Zeroize> fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sensitive([REDACTED])")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sensitive::Sensitive;
+    use k9::assert_equal;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let secret = Sensitive::new(String::from("I <3 Nickelback"));
+        assert_equal!(format!("{:?}", secret), "Sensitive([REDACTED])");
+        assert_equal!(secret.as_str(), "I <3 Nickelback");
+    }
+
+    #[test]
+    fn test_into_string() {
+        let bytes = Sensitive::new(Vec::from("plaintext"));
+        assert_equal!(bytes.into_string().unwrap().as_str(), "plaintext");
+        assert_equal!(
+            Sensitive::new(vec![0xff, 0xfe]).into_string().is_err(),
+            true
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Whole-file encrypted tomb container
+//!
+//! An armored text file holding the base64 of:
+//!
+//! ```text
+//! magic ("TOMB") | version (1 byte) | header size (2 bytes, big-endian) | header | iv | cbc(tomb yaml) | tag
+//! ```
+//!
+//! The header is the json of the `Config` (KDF parameters, cycles and
+//! salt) required to derive the key from a password, it is stored in
+//! the clear but authenticated along with the encrypted tomb by
+//! `Key::encrypt_with_header`.
+use super::Error;
+use crate::aes256cbc::{Config as AesConfig, Key};
+use crate::ioutils::{b64decode, b64encode};
+use crate::sensitive::SensitiveBytes;
+
+pub const CONTAINER_MAGIC: &[u8] = b"TOMB";
+pub const CONTAINER_V1: u8 = 1;
+const ARMOR_BEGIN: &str = "-----BEGIN TOMB CONTAINER-----";
+const ARMOR_END: &str = "-----END TOMB CONTAINER-----";
+const ARMOR_WIDTH: usize = 64;
+
+/// Whether the contents of a tomb file are an armored container
+pub fn is_container(data: &str) -> bool {
+    data.trim_start().starts_with(ARMOR_BEGIN)
+}
+
+pub struct Container {
+    pub config: AesConfig,
+    header: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Container {
+    /// Encrypts the serialized tomb with the key
+    pub fn seal(config: &AesConfig, plaintext: &[u8], key: &Key) -> Result<Container, Error> {
+        let json = match serde_json::to_vec(config) {
+            Ok(json) => json,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "failed to encode container header: {}",
+                    error
+                )))
+            }
+        };
+        let size: u16 = match json.len().try_into() {
+            Ok(size) => size,
+            Err(_) => {
+                return Err(Error::with_message(format!(
+                    "container header too large: {} bytes",
+                    json.len()
+                )))
+            }
+        };
+        let mut header = CONTAINER_MAGIC.to_vec();
+        header.push(CONTAINER_V1);
+        header.extend_from_slice(&size.to_be_bytes());
+        header.extend(json);
+        let body = match key.encrypt_with_header(&header, plaintext) {
+            Ok(body) => body,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "cannot encrypt tomb container: {}",
+                    error
+                )))
+            }
+        };
+        Ok(Container {
+            config: config.clone(),
+            header,
+            body,
+        })
+    }
+    /// Parses the header of an armored container, without decrypting it
+    pub fn parse(armored: &str) -> Result<Container, Error> {
+        let data = dearmor(armored)?;
+        let prefix = CONTAINER_MAGIC.len() + 3;
+        if data.len() < prefix || !data.starts_with(CONTAINER_MAGIC) {
+            return Err(Error::with_message(String::from("not a tomb container")));
+        }
+        let version = data[CONTAINER_MAGIC.len()];
+        if version != CONTAINER_V1 {
+            return Err(Error::with_message(format!(
+                "unsupported tomb container version: {}",
+                version
+            )));
+        }
+        let size = u16::from_be_bytes([data[prefix - 2], data[prefix - 1]]) as usize;
+        if data.len() < prefix + size {
+            return Err(Error::with_message(format!(
+                "tomb container header truncated: expected {} bytes, got {}",
+                size,
+                data.len() - prefix
+            )));
+        }
+        let config: AesConfig = match serde_json::from_slice(&data[prefix..prefix + size]) {
+            Ok(config) => config,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "failed to decode container header: {}",
+                    error
+                )))
+            }
+        };
+        Ok(Container {
+            config,
+            header: data[..prefix + size].to_vec(),
+            body: data[prefix + size..].to_vec(),
+        })
+    }
+    /// Decrypts the serialized tomb
+    pub fn open(&self, key: &Key) -> Result<SensitiveBytes, Error> {
+        match key.decrypt_with_header(&self.header, &self.body) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(error) => Err(Error::with_message(format!(
+                "cannot open tomb container with the provided key: {}",
+                error
+            ))),
+        }
+    }
+    pub fn armor(&self) -> String {
+        let mut data = self.header.clone();
+        data.extend_from_slice(&self.body);
+        let encoded = b64encode(&data);
+        let mut armored = format!("{}\n", ARMOR_BEGIN);
+        for line in encoded.as_bytes().chunks(ARMOR_WIDTH) {
+            armored.push_str(&String::from_utf8_lossy(line));
+            armored.push('\n');
+        }
+        armored.push_str(ARMOR_END);
+        armored.push('\n');
+        armored
+    }
+}
+
+fn dearmor(armored: &str) -> Result<Vec<u8>, Error> {
+    let armored = armored.trim();
+    let encoded = match armored
+        .strip_prefix(ARMOR_BEGIN)
+        .and_then(|rest| rest.strip_suffix(ARMOR_END))
+    {
+        Some(encoded) => encoded.split_whitespace().collect::<String>(),
+        None => {
+            return Err(Error::with_message(format!(
+                "malformed tomb container: missing {} or {}",
+                ARMOR_BEGIN, ARMOR_END
+            )))
+        }
+    };
+    match b64decode(encoded.as_bytes()) {
+        Ok(data) => Ok(data),
+        Err(error) => Err(Error::with_message(format!(
+            "parse base64 tomb container: {}",
+            error
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_container, Container, ARMOR_BEGIN, ARMOR_END, CONTAINER_MAGIC};
+    use crate::aes256cbc::{Config as AesConfig, Key};
+    use crate::ioutils::b64encode;
+    use k9::assert_equal;
+
+    fn sealed(key: &Key) -> Container {
+        Container::seal(&AesConfig::builtin(None), b"data: {}\n", key).unwrap()
+    }
+    fn bytes(container: &Container) -> Vec<u8> {
+        let mut data = container.header.clone();
+        data.extend_from_slice(&container.body);
+        data
+    }
+    fn armored(data: &[u8]) -> String {
+        format!("{}\n{}\n{}\n", ARMOR_BEGIN, b64encode(data), ARMOR_END)
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = Key::generate();
+        let armored = sealed(&key).armor();
+        assert_equal!(is_container(&armored), true);
+        let container = Container::parse(&armored).unwrap();
+        assert_equal!(container.config == AesConfig::builtin(None), true);
+        assert_equal!(
+            container.open(&key).unwrap().to_vec(),
+            b"data: {}\n".to_vec()
+        );
+        assert_equal!(container.open(&Key::generate()).is_err(), true);
+    }
+    #[test]
+    fn test_tampered_header() {
+        let key = Key::generate();
+        let container = sealed(&key);
+        let header = String::from_utf8_lossy(&container.header).to_string();
+        let tampered = header.replace("16000", "16001");
+        assert_equal!(tampered == header, false);
+        let mut data = tampered.into_bytes();
+        data.extend_from_slice(&container.body);
+
+        let parsed = Container::parse(&armored(&data)).unwrap();
+        assert_equal!(parsed.config.key_cycles(), 16001);
+        let error = parsed.open(&key).err().unwrap();
+        assert_equal!(error.message.contains("authentication tag mismatch"), true);
+    }
+    #[test]
+    fn test_tampered_body() {
+        let key = Key::generate();
+        let container = sealed(&key);
+        let mut data = bytes(&container);
+        // first byte of the encrypted tomb, after the iv
+        let encrypted = container.header.len() + 16;
+        data[encrypted] ^= 0x01;
+
+        let error = Container::parse(&armored(&data))
+            .unwrap()
+            .open(&key)
+            .err()
+            .unwrap();
+        assert_equal!(error.message.contains("authentication tag mismatch"), true);
+    }
+    #[test]
+    fn test_truncated_container() {
+        let key = Key::generate();
+        let container = sealed(&key);
+        let data = bytes(&container);
+
+        let header = &data[..container.header.len() - 1];
+        let error = Container::parse(&armored(header)).err().unwrap();
+        assert_equal!(error.message.contains("header truncated"), true);
+
+        let body = &data[..container.header.len() + 16];
+        let error = Container::parse(&armored(body))
+            .unwrap()
+            .open(&key)
+            .err()
+            .unwrap();
+        assert_equal!(error.message.contains("too short"), true);
+
+        let armor = container.armor();
+        let unterminated = armor.trim_end().strip_suffix(ARMOR_END).unwrap();
+        assert_equal!(Container::parse(unterminated).is_err(), true);
+    }
+    #[test]
+    fn test_unsupported_version() {
+        let key = Key::generate();
+        let mut data = bytes(&sealed(&key));
+        data[CONTAINER_MAGIC.len()] = 2;
+        let error = Container::parse(&armored(&data)).err().unwrap();
+        assert_equal!(
+            error
+                .message
+                .contains("unsupported tomb container version: 2"),
+            true
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Random secret values generated from a `Policy`
+//!
+//! Passwords draw their characters from the enabled classes, with a
+//! minimum count per class, and passphrases draw their words from a
+//! bundled wordlist. `AES256Tomb::generate_secret` records the policy
+//! in the attributes of the secret, so that it is regenerated alike.
+use super::{AES256Secret, AES256Tomb, Error};
+use crate::aes256cbc::Key;
+use crate::sensitive::{Sensitive, SensitiveString};
+use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+/// Attribute holding the name of the policy a secret was generated with
+pub const POLICY_ATTRIBUTE: &str = "generator.policy";
+/// Attribute holding the json of the policy a secret was generated with
+pub const POLICY_SPEC_ATTRIBUTE: &str = "generator.spec";
+/// Name of the policy of generated secrets that were not given one
+pub const DEFAULT_POLICY: &str = "default";
+/// Name of the builtin passphrase policy
+pub const PASSPHRASE_POLICY: &str = "passphrase";
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "!#$%&()*+,-./:;<=>?@[]^_{|}~";
+/// Characters easily mistaken for one another when read or typed
+const AMBIGUOUS: &str = "0O1lI|";
+const WORDLIST: &str = include_str!("wordlist.txt");
+
+/// The words passphrases are made of, one per line of `wordlist.txt`
+pub fn wordlist() -> Vec<&'static str> {
+    WORDLIST.lines().filter(|word| !word.is_empty()).collect()
+}
+
+fn default_length() -> usize {
+    24
+}
+fn default_separator() -> String {
+    String::from("-")
+}
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Policy {
+    /// Number of characters of passwords
+    #[serde(default = "default_length")]
+    pub length: usize,
+    #[serde(default = "enabled")]
+    pub lowercase: bool,
+    #[serde(default = "enabled")]
+    pub uppercase: bool,
+    #[serde(default = "enabled")]
+    pub digits: bool,
+    #[serde(default = "enabled")]
+    pub symbols: bool,
+    /// Leave out characters such as `0` and `O` or `1`, `l` and `I`
+    #[serde(default)]
+    pub exclude_ambiguous: bool,
+    #[serde(default)]
+    pub min_lowercase: usize,
+    #[serde(default)]
+    pub min_uppercase: usize,
+    #[serde(default)]
+    pub min_digits: usize,
+    #[serde(default)]
+    pub min_symbols: usize,
+    /// Number of words of passphrases, which replace the characters
+    /// above when not zero
+    #[serde(default)]
+    pub words: usize,
+    #[serde(default = "default_separator")]
+    pub separator: String,
+}
+
+impl Policy {
+    /// Passwords of 24 characters of every class
+    pub fn builtin() -> Policy {
+        Policy {
+            length: default_length(),
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+            exclude_ambiguous: false,
+            min_lowercase: 0,
+            min_uppercase: 0,
+            min_digits: 0,
+            min_symbols: 0,
+            words: 0,
+            separator: default_separator(),
+        }
+    }
+    /// Passphrases of the given number of words separated by dashes
+    pub fn passphrase(words: usize) -> Policy {
+        let mut policy = Policy::builtin();
+        policy.words = words;
+        policy
+    }
+    pub fn is_passphrase(&self) -> bool {
+        self.words > 0
+    }
+    /// The characters of every class along with their name, whether
+    /// they are enabled and their minimum count
+    fn classes(&self) -> Vec<(&'static str, Vec<char>, bool, usize)> {
+        let charset = |characters: &str| -> Vec<char> {
+            characters
+                .chars()
+                .filter(|c| !(self.exclude_ambiguous && AMBIGUOUS.contains(*c)))
+                .collect()
+        };
+        vec![
+            (
+                "lowercase",
+                charset(LOWERCASE),
+                self.lowercase,
+                self.min_lowercase,
+            ),
+            (
+                "uppercase",
+                charset(UPPERCASE),
+                self.uppercase,
+                self.min_uppercase,
+            ),
+            ("digits", charset(DIGITS), self.digits, self.min_digits),
+            ("symbols", charset(SYMBOLS), self.symbols, self.min_symbols),
+        ]
+    }
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.is_passphrase() {
+            return Ok(());
+        }
+        let classes = self.classes();
+        if !classes.iter().any(|(_, _, enabled, _)| *enabled) {
+            return Err(Error::with_message(String::from(
+                "the policy enables no character class",
+            )));
+        }
+        if let Some((name, _, _, _)) = classes
+            .iter()
+            .find(|(_, _, enabled, minimum)| !enabled && *minimum > 0)
+        {
+            return Err(Error::with_message(format!(
+                "the policy requires {} that it does not enable",
+                name
+            )));
+        }
+        let minimums: usize = classes.iter().map(|(_, _, _, minimum)| minimum).sum();
+        if self.length == 0 || minimums > self.length {
+            return Err(Error::with_message(format!(
+                "the policy requires {} characters but has a length of {}",
+                minimums, self.length
+            )));
+        }
+        Ok(())
+    }
+    /// Bits of entropy of the values generated with this policy,
+    /// ignoring the minimum counts per class
+    pub fn entropy(&self) -> f64 {
+        if self.is_passphrase() {
+            return self.words as f64 * (wordlist().len() as f64).log2();
+        }
+        let pool: usize = self
+            .classes()
+            .iter()
+            .filter(|(_, _, enabled, _)| *enabled)
+            .map(|(_, characters, _, _)| characters.len())
+            .sum();
+        self.length as f64 * (pool as f64).log2()
+    }
+    /// A random value drawn from the operating system's generator
+    pub fn generate(&self) -> Result<SensitiveString, Error> {
+        self.validate()?;
+        let mut rng = OsRng;
+        if self.is_passphrase() {
+            let words = wordlist();
+            let chosen: Vec<&str> = (0..self.words)
+                .map(|_| words[rng.gen_range(0..words.len())])
+                .collect();
+            return Ok(Sensitive::new(chosen.join(&self.separator)));
+        }
+        let classes: Vec<(Vec<char>, usize)> = self
+            .classes()
+            .into_iter()
+            .filter(|(_, _, enabled, _)| *enabled)
+            .map(|(_, characters, _, minimum)| (characters, minimum))
+            .collect();
+        let pool: Vec<char> = classes
+            .iter()
+            .flat_map(|(characters, _)| characters.iter().cloned())
+            .collect();
+        let mut chosen: Vec<char> = Vec::with_capacity(self.length);
+        for (characters, minimum) in &classes {
+            for _ in 0..*minimum {
+                chosen.push(characters[rng.gen_range(0..characters.len())]);
+            }
+        }
+        while chosen.len() < self.length {
+            chosen.push(pool[rng.gen_range(0..pool.len())]);
+        }
+        chosen.shuffle(&mut rng);
+        let value = Sensitive::new(chosen.iter().collect::<String>());
+        chosen.zeroize();
+        Ok(value)
+    }
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Result<Policy, Error> {
+        match serde_json::from_str(data) {
+            Ok(policy) => Ok(policy),
+            Err(error) => Err(Error::with_message(format!(
+                "invalid generator policy {}: {}",
+                data, error
+            ))),
+        }
+    }
+}
+
+impl AES256Secret {
+    /// The name and policy the value of this secret was generated with
+    pub fn generator_policy(&self) -> Option<(String, Policy)> {
+        let attributes = self.attributes.as_ref()?;
+        let policy = Policy::from_json(attributes.get(POLICY_SPEC_ATTRIBUTE)?).ok()?;
+        let name = match attributes.get(POLICY_ATTRIBUTE) {
+            Some(name) => name.clone(),
+            None => String::from(DEFAULT_POLICY),
+        };
+        Some((name, policy))
+    }
+}
+
+impl AES256Tomb {
+    /// Stores a value generated with `policy` at `path`, keeping the
+    /// previous value in the history of existing secrets, and records
+    /// the policy in the attributes of the secret
+    pub fn generate_secret(
+        &mut self,
+        path: &str,
+        name: &str,
+        policy: &Policy,
+        key: &Key,
+    ) -> Result<AES256Secret, Error> {
+        let value = policy.generate()?;
+        let mut secret = self.add_secret_from_bytes(path, value.as_bytes().to_vec(), key)?;
+        let attributes = secret.attributes.get_or_insert_with(Default::default);
+        attributes.insert(String::from(POLICY_ATTRIBUTE), String::from(name));
+        attributes.insert(String::from(POLICY_SPEC_ATTRIBUTE), policy.to_json());
+        Ok(self.upsert_secret(secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wordlist, Policy, DEFAULT_POLICY};
+    use crate::aes256cbc::Key;
+    use crate::tomb::testing::new_tomb;
+    use k9::assert_equal;
+
+    #[test]
+    fn test_generate() {
+        let mut policy = Policy::builtin();
+        policy.length = 12;
+        policy.symbols = false;
+        policy.exclude_ambiguous = true;
+        policy.min_digits = 4;
+        let value = policy.generate().unwrap();
+        assert_equal!(value.len(), 12);
+        assert_equal!(
+            value.chars().filter(|c| c.is_ascii_digit()).count() >= 4,
+            true
+        );
+        assert_equal!(value.chars().all(|c| c.is_ascii_alphanumeric()), true);
+        assert_equal!(value.chars().any(|c| "0O1lI".contains(c)), false);
+
+        policy.min_symbols = 1;
+        assert_equal!(policy.generate().is_err(), true);
+
+        let passphrase = Policy::passphrase(5).generate().unwrap();
+        let words: Vec<&str> = passphrase.split('-').collect();
+        assert_equal!(words.len(), 5);
+        assert_equal!(words.iter().all(|word| wordlist().contains(word)), true);
+    }
+    #[test]
+    fn test_generate_secret_records_policy() {
+        let key = Key::generate();
+        let (_directory, mut tomb) = new_tomb(&key);
+        let policy = Policy::passphrase(4);
+        let secret = tomb
+            .generate_secret("netflix", DEFAULT_POLICY, &policy, &key)
+            .unwrap();
+        let (name, recorded) = secret.generator_policy().unwrap();
+        assert_equal!(name.as_str(), DEFAULT_POLICY);
+        assert_equal!(recorded, policy);
+
+        let previous = tomb.get_string("netflix", &key).unwrap();
+        tomb.generate_secret("netflix", &name, &recorded, &key)
+            .unwrap();
+        assert_equal!(tomb.get("netflix").unwrap().history.len(), 1);
+        assert_equal!(
+            tomb.get_version_string("netflix", 1, &key)
+                .unwrap()
+                .as_str(),
+            previous.as_str()
+        );
+    }
+}
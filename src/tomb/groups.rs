@@ -0,0 +1,357 @@
+//! Groups of secrets encrypted with their own data key
+//!
+//! The secrets of a shared group, for example every secret under
+//! `/work` as returned by `AES256Secret::group`, are encrypted with a
+//! random group key rather than the key of the tomb. The group key is
+//! encrypted with the key of the tomb, whose holders keep access to
+//! every secret, and wrapped for the members of the group like the data
+//! key of tombs with `members`, who can only decrypt the secrets of the
+//! groups they belong to.
+use super::members::{unwrap_for, unwrap_key, wrap_key, Member};
+use super::{rekey_secrets, AES256Tomb, Error};
+use crate::aes256cbc::{Digest, Key};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SharedGroup {
+    /// Digest of the group key, which its secrets are encrypted with
+    pub digest: Digest,
+    /// base64 of the group key encrypted with the key of the tomb
+    pub data_key: String,
+    /// Recipients the group key is wrapped for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<Member>,
+}
+
+/// The name of a group as returned by `AES256Secret::group`, `/work`
+/// for `work`, `work/` and `/work`
+pub fn normalize_group(group: &str) -> String {
+    format!("/{}", group.trim_matches('/'))
+}
+
+/// Whether the secret at `path` is under `group`, at any depth
+fn in_group(path: &str, group: &str) -> bool {
+    normalize_group(path).starts_with(&format!("{}/", group))
+}
+
+fn group_not_found(group: &str) -> Error {
+    Error::with_message(format!(
+        "{}{}",
+        style("group is not shared: ").color256(198),
+        style(group).color256(190),
+    ))
+}
+
+impl AES256Tomb {
+    /// The shared group the secret at `path` belongs to
+    pub fn shared_group(&self, path: &str) -> Option<&str> {
+        self.groups
+            .keys()
+            .find(|group| in_group(path, group))
+            .map(|group| group.as_str())
+    }
+    /// Whether the secret at `path` can be decrypted with the keys
+    /// unlocked by `import_with_key` or `unlock_groups`
+    pub fn can_access(&self, path: &str) -> bool {
+        match self.shared_group(path) {
+            Some(group) => self.group_keys.contains_key(group),
+            None => !self.group_only,
+        }
+    }
+    /// Decrypts the keys of the groups `key` can access: every group
+    /// with the key of the tomb, those it is a member of otherwise.
+    /// Returns whether any group was unlocked
+    pub fn unlock_groups(&mut self, key: &Key) -> Result<bool, Error> {
+        let mut unlocked = BTreeMap::new();
+        for (name, group) in &self.groups {
+            if self.group_keys.contains_key(name) {
+                continue;
+            }
+            let group_key = if key.digest() == self.digest {
+                Some(unwrap_key(
+                    &group.data_key,
+                    key,
+                    &format!("group {}", name),
+                )?)
+            } else {
+                unwrap_for(&group.members, key)?
+            };
+            if let Some(group_key) = group_key {
+                unlocked.insert(name.clone(), Rc::new(group_key));
+            }
+        }
+        let any = !unlocked.is_empty();
+        self.group_keys.extend(unlocked);
+        Ok(any)
+    }
+    /// The key the secret at `path` is encrypted with: the key of its
+    /// shared group or the given key of the tomb
+    pub fn key_for<'a>(&'a self, path: &str, key: &'a Key) -> Result<&'a Key, Error> {
+        match self.shared_group(path) {
+            Some(group) => match self.group_keys.get(group) {
+                Some(group_key) => Ok(group_key.as_ref()),
+                None => Err(Error::with_message(format!(
+                    "{}{}",
+                    style("the key has no access to the group ").color256(198),
+                    style(group).color256(190),
+                ))),
+            },
+            None if self.group_only => Err(Error::with_message(format!(
+                "{}{}",
+                style("the key only has access to shared groups: ").color256(198),
+                style(path).color256(190),
+            ))),
+            None => Ok(key),
+        }
+    }
+    fn ensure_tomb_key(&self, key: &Key) -> Result<(), Error> {
+        if key.digest() != self.digest {
+            return Err(Error::with_message(format!(
+                "{}",
+                style("the key of the tomb is required to manage shared groups").color256(198)
+            )));
+        }
+        Ok(())
+    }
+    fn group_key(&self, group: &str) -> Result<Rc<Key>, Error> {
+        if !self.groups.contains_key(group) {
+            return Err(group_not_found(group));
+        }
+        match self.group_keys.get(group) {
+            Some(group_key) => Ok(group_key.clone()),
+            None => Err(Error::with_message(format!(
+                "{}{}",
+                style("the key has no access to the group ").color256(198),
+                style(group).color256(190),
+            ))),
+        }
+    }
+    /// Moves the secrets under `group` to a new group key encrypted
+    /// with the key of the tomb, members are added with `add_group_member`
+    pub fn share_group(&mut self, group: &str, key: &Key) -> Result<(), Error> {
+        let group = normalize_group(group);
+        self.ensure_tomb_key(key)?;
+        if group == "/" {
+            return Err(Error::with_message(String::from(
+                "the whole tomb is shared with members rather than as a group",
+            )));
+        }
+        if self.sealed {
+            return Err(Error::with_message(String::from(
+                "groups of tombs with sealed metadata cannot be shared",
+            )));
+        }
+        if self.groups.contains_key(&group) {
+            return Err(Error::with_message(format!(
+                "{}{}",
+                style("group is already shared: ").color256(198),
+                style(&group).color256(190),
+            )));
+        }
+        if let Some(other) = self
+            .groups
+            .keys()
+            .find(|other| in_group(other, &group) || in_group(&group, other))
+        {
+            return Err(Error::with_message(format!(
+                "{}{}{}{}",
+                style("group ").color256(198),
+                style(&group).color256(190),
+                style(" overlaps the shared group ").color256(198),
+                style(other).color256(190),
+            )));
+        }
+        let group_key = Key::generate();
+        let filter = |path: &str| in_group(path, &group);
+        let data = rekey_secrets(&self.data, key, &group_key, filter)?;
+        let trash = rekey_secrets(&self.trash, key, &group_key, filter)?;
+        let shared = SharedGroup {
+            digest: group_key.digest(),
+            data_key: wrap_key(&group_key, key)?,
+            members: Vec::new(),
+        };
+        self.data = data;
+        self.trash = trash;
+        self.groups.insert(group.clone(), shared);
+        self.group_keys.insert(group, Rc::new(group_key));
+        Ok(())
+    }
+    /// Moves the secrets of a shared group back under the key of the tomb
+    pub fn unshare_group(&mut self, group: &str, key: &Key) -> Result<(), Error> {
+        let group = normalize_group(group);
+        self.ensure_tomb_key(key)?;
+        self.unlock_groups(key)?;
+        let group_key = self.group_key(&group)?;
+        let filter = |path: &str| in_group(path, &group);
+        let data = rekey_secrets(&self.data, &group_key, key, filter)?;
+        let trash = rekey_secrets(&self.trash, &group_key, key, filter)?;
+        self.data = data;
+        self.trash = trash;
+        self.groups.remove(&group);
+        self.group_keys.remove(&group);
+        Ok(())
+    }
+    /// Wraps the key of a shared group for another member
+    pub fn add_group_member(
+        &mut self,
+        group: &str,
+        name: &str,
+        public_key: &str,
+    ) -> Result<(), Error> {
+        let group = normalize_group(group);
+        let group_key = self.group_key(&group)?;
+        let shared = self.groups.get_mut(&group).unwrap();
+        if let Some(member) = shared
+            .members
+            .iter()
+            .find(|member| member.name == name || member.public_key == public_key)
+        {
+            return Err(Error::with_message(format!(
+                "{}{}{}{}",
+                style("group ").color256(198),
+                style(&group).color256(190),
+                style(" already has the member ").color256(198),
+                style(&member.name).color256(190),
+            )));
+        }
+        shared
+            .members
+            .push(Member::wrap(name, public_key, &group_key)?);
+        Ok(())
+    }
+    /// Removes a member of a shared group and rotates its key
+    pub fn remove_group_member(&mut self, group: &str, name: &str, key: &Key) -> Result<(), Error> {
+        let group = normalize_group(group);
+        self.ensure_tomb_key(key)?;
+        self.unlock_groups(key)?;
+        let shared = match self.groups.get_mut(&group) {
+            Some(shared) => shared,
+            None => return Err(group_not_found(&group)),
+        };
+        let count = shared.members.len();
+        shared.members.retain(|member| member.name != name);
+        if shared.members.len() == count {
+            return Err(Error::with_message(format!(
+                "{}{}{}{}",
+                style("group ").color256(198),
+                style(&group).color256(190),
+                style(" has no member ").color256(198),
+                style(name).color256(190),
+            )));
+        }
+        self.rotate_group(&group, key)
+    }
+    /// Replaces the key of a shared group, which is wrapped again for
+    /// its remaining members
+    fn rotate_group(&mut self, group: &str, key: &Key) -> Result<(), Error> {
+        let old = self.group_key(group)?;
+        let rotated = Key::generate();
+        let filter = |path: &str| in_group(path, group);
+        let data = rekey_secrets(&self.data, &old, &rotated, filter)?;
+        let trash = rekey_secrets(&self.trash, &old, &rotated, filter)?;
+        let shared = self.groups.get(group).unwrap();
+        let mut members = Vec::new();
+        for member in &shared.members {
+            let mut rewrapped = Member::wrap(&member.name, &member.public_key, &rotated)?;
+            rewrapped.added_at = member.added_at;
+            members.push(rewrapped);
+        }
+        let shared = SharedGroup {
+            digest: rotated.digest(),
+            data_key: wrap_key(&rotated, key)?,
+            members,
+        };
+        self.data = data;
+        self.trash = trash;
+        self.groups.insert(String::from(group), shared);
+        self.group_keys
+            .insert(String::from(group), Rc::new(rotated));
+        Ok(())
+    }
+    /// Rotates the key of every shared group, so that a member removed
+    /// from the tomb cannot read the groups it had access to either
+    pub(super) fn rotate_groups(&mut self, key: &Key) -> Result<(), Error> {
+        self.unlock_groups(key)?;
+        let groups: Vec<String> = self.groups.keys().cloned().collect();
+        for group in groups {
+            self.rotate_group(&group, key)?;
+        }
+        Ok(())
+    }
+    /// The shared groups with their keys encrypted with a new key of
+    /// the tomb, see `AES256Tomb::rekey`
+    pub(super) fn rekey_groups(
+        &self,
+        old: &Key,
+        new: &Key,
+    ) -> Result<BTreeMap<String, SharedGroup>, Error> {
+        let mut groups = BTreeMap::new();
+        for (name, group) in &self.groups {
+            let group_key = unwrap_key(&group.data_key, old, &format!("group {}", name))?;
+            let mut group = group.clone();
+            group.data_key = wrap_key(&group_key, new)?;
+            groups.insert(name.clone(), group);
+        }
+        Ok(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::{Identity, Key};
+    use crate::tomb::testing::new_tomb;
+    use k9::assert_equal;
+
+    #[test]
+    fn test_shared_groups() {
+        let alice = Key::generate();
+        let bob = Identity::generate();
+        let (_directory, mut tomb) = new_tomb(&alice);
+        tomb.add_secret("work/aws", String::from("123456"), &alice)
+            .unwrap();
+        tomb.add_secret("personal/netflix", String::from("987654"), &alice)
+            .unwrap();
+
+        tomb.share_group("work", &alice).unwrap();
+        assert_equal!(tomb.share_group("/work/", &alice).is_err(), true);
+        assert_equal!(tomb.shared_group("work/aws"), Some("/work"));
+        assert_equal!(
+            tomb.get("work/aws").unwrap().digest == alice.digest(),
+            false
+        );
+        assert_equal!(
+            tomb.get_string("work/aws", &alice).unwrap().as_str(),
+            "123456"
+        );
+        tomb.add_group_member("/work", "bob", &bob.recipient)
+            .unwrap();
+
+        // bob only unlocks the groups he is a member of
+        let bob = Key::generate().with_identity(bob);
+        let mut shared = tomb.clone();
+        shared.group_keys.clear();
+        shared.group_only = true;
+        assert_equal!(shared.unlock_groups(&bob).unwrap(), true);
+        assert_equal!(
+            shared.get_string("work/aws", &bob).unwrap().as_str(),
+            "123456"
+        );
+        assert_equal!(shared.get_string("personal/netflix", &bob).is_err(), true);
+        assert_equal!(shared.can_access("personal/netflix"), false);
+
+        tomb.remove_group_member("/work", "bob", &alice).unwrap();
+        let mut shared = tomb.clone();
+        shared.group_keys.clear();
+        assert_equal!(shared.unlock_groups(&bob).unwrap(), false);
+
+        tomb.unshare_group("work", &alice).unwrap();
+        assert_equal!(tomb.get("work/aws").unwrap().digest, alice.digest());
+        assert_equal!(
+            tomb.get_string("work/aws", &alice).unwrap().as_str(),
+            "123456"
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Tombs shared between several keys
+//!
+//! The secrets of a tomb with members are encrypted with a random data
+//! key, which is wrapped for every member with a key agreed between an
+//! ephemeral key and the recipient of the member: the public key of
+//! their `Identity`, or the `Key::public_key` of keys without one. Only
+//! public keys are needed to add members or to rotate the data key
+//! when one of them is removed.
+use super::{AES256Tomb, Error};
+use crate::aes256cbc::Key;
+use crate::config::YamlFile;
+use crate::ioutils::{b64decode, b64encode};
+use crate::sensitive::Sensitive;
+use chrono::prelude::*;
+use console::style;
+use serde::{Deserialize, Serialize};
+
+/// A recipient the data key of a tomb is wrapped for
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub name: String,
+    /// `Key::recipient` of the member
+    pub public_key: String,
+    /// Public key of the ephemeral key the data key was wrapped with
+    pub ephemeral_key: String,
+    /// base64 of the data key encrypted with the agreed key
+    pub data_key: String,
+    pub added_at: DateTime<Utc>,
+}
+
+impl Member {
+    pub(super) fn wrap(name: &str, public_key: &str, data_key: &Key) -> Result<Member, Error> {
+        let ephemeral = Key::generate();
+        let kek = map_error(ephemeral.agree(public_key))?;
+        Ok(Member {
+            name: String::from(name),
+            public_key: String::from(public_key),
+            ephemeral_key: map_error(ephemeral.public_key())?,
+            data_key: wrap_key(data_key, &kek)?,
+            added_at: Utc::now(),
+        })
+    }
+    /// Decrypts the data key with the key agreed between the
+    /// recipient and `ephemeral_key`
+    pub(super) fn unwrap(&self, kek: Key) -> Result<Key, Error> {
+        unwrap_key(&self.data_key, &kek, &format!("member {}", self.name))
+    }
+}
+
+/// base64 of a data key encrypted with a key-encryption key
+pub(super) fn wrap_key(data_key: &Key, kek: &Key) -> Result<String, Error> {
+    let yaml = Sensitive::new(map_error(data_key.to_yaml())?);
+    Ok(b64encode(&map_error(kek.encrypt(yaml.as_bytes()))?))
+}
+/// Decrypts a data key encrypted by `wrap_key`, `owner` names whose
+/// data key it is in errors
+pub(super) fn unwrap_key(data_key: &str, kek: &Key, owner: &str) -> Result<Key, Error> {
+    let cyphertext = map_error(b64decode(data_key.as_bytes()))?;
+    let yaml = match kek.decrypt(&cyphertext) {
+        Ok(yaml) => yaml,
+        Err(error) => {
+            return Err(Error::with_message(format!(
+                "{}{}{}",
+                style("cannot unwrap the data key of ").color256(198),
+                style(owner).color256(190),
+                style(format!("\n\t{}", error)).color256(197),
+            )))
+        }
+    };
+    match yaml.into_string() {
+        Ok(yaml) => map_error(Key::from_yaml(yaml.to_string())),
+        Err(error) => Err(Error::with_message(format!(
+            "data key is not valid utf-8: {}",
+            error
+        ))),
+    }
+}
+
+impl AES256Tomb {
+    pub fn has_members(&self) -> bool {
+        !self.members.is_empty()
+    }
+    pub fn member(&self, name: &str) -> Option<&Member> {
+        self.members.iter().find(|member| member.name == name)
+    }
+    fn recipient(&self, public_key: &str) -> Option<&Member> {
+        find_recipient(&self.members, public_key)
+    }
+    /// The data key the secrets are encrypted with when the given key
+    /// is one of the members, `None` for tombs without members and when
+    /// the given key is the data key already
+    pub fn unlock_data_key(&self, key: &Key) -> Result<Option<Key>, Error> {
+        if !self.has_members() || key.digest() == self.digest {
+            return Ok(None);
+        }
+        match unwrap_for(&self.members, key)? {
+            Some(data_key) => Ok(Some(data_key)),
+            None => Err(Error::with_message(format!(
+                "{}",
+                style("the key is not a member of this tomb").color256(198)
+            ))),
+        }
+    }
+    /// Moves the secrets of a tomb without members under a new data
+    /// key, wrapped for the recipient of `key` as the member `name`
+    pub fn enable_members(&mut self, name: &str, key: &Key) -> Result<Key, Error> {
+        if self.has_members() {
+            return Err(Error::with_message(String::from(
+                "the tomb already has members",
+            )));
+        }
+        let data_key = Key::generate();
+        let public_key = map_error(key.recipient())?;
+        self.rekey(key, &data_key)?;
+        self.members
+            .push(Member::wrap(name, &public_key, &data_key)?);
+        Ok(data_key)
+    }
+    /// Wraps the data key for another member
+    pub fn add_member(
+        &mut self,
+        name: &str,
+        public_key: &str,
+        data_key: &Key,
+    ) -> Result<(), Error> {
+        if self.member(name).is_some() {
+            return Err(Error::with_message(format!(
+                "{}{}",
+                style("member already exists: ").color256(198),
+                style(name).color256(190),
+            )));
+        }
+        if let Some(member) = self.recipient(public_key) {
+            return Err(Error::with_message(format!(
+                "{}{}",
+                style("the key is already a member as ").color256(198),
+                style(&member.name).color256(190),
+            )));
+        }
+        self.members.push(Member::wrap(name, public_key, data_key)?);
+        Ok(())
+    }
+    /// Removes a member and rotates the data key, which is wrapped
+    /// again for the remaining members. Returns the new data key
+    pub fn remove_member(&mut self, name: &str, data_key: &Key) -> Result<Key, Error> {
+        if self.member(name).is_none() {
+            return Err(Error::with_message(format!(
+                "{}{}",
+                style("member not found: ").color256(198),
+                style(name).color256(190),
+            )));
+        }
+        if self.members.len() == 1 {
+            return Err(Error::with_message(String::from(
+                "cannot remove the last member of the tomb",
+            )));
+        }
+        let rotated = Key::generate();
+        self.rekey(data_key, &rotated)?;
+        self.rotate_groups(&rotated)?;
+        let mut members = Vec::new();
+        for member in &self.members {
+            if member.name != name {
+                let mut rewrapped = Member::wrap(&member.name, &member.public_key, &rotated)?;
+                rewrapped.added_at = member.added_at;
+                members.push(rewrapped);
+            }
+        }
+        self.members = members;
+        Ok(rotated)
+    }
+}
+
+fn find_recipient<'a>(members: &'a [Member], public_key: &str) -> Option<&'a Member> {
+    members
+        .iter()
+        .find(|member| member.public_key == public_key)
+}
+/// Unwraps the data key of the member `key` is the recipient of, by
+/// its identity or its own public key, `None` when it is no member
+pub(super) fn unwrap_for(members: &[Member], key: &Key) -> Result<Option<Key>, Error> {
+    if let Some(identity) = &key.identity {
+        if let Some(member) = find_recipient(members, &identity.recipient) {
+            let kek = map_error(identity.agree(&member.ephemeral_key))?;
+            return Ok(Some(member.unwrap(kek)?));
+        }
+    }
+    let public_key = map_error(key.public_key())?;
+    match find_recipient(members, &public_key) {
+        Some(member) => {
+            let kek = map_error(key.agree(&member.ephemeral_key))?;
+            Ok(Some(member.unwrap(kek)?))
+        }
+        None => Ok(None),
+    }
+}
+pub(super) fn map_error<T, E: std::fmt::Display>(result: Result<T, E>) -> Result<T, Error> {
+    result.map_err(|error| Error::with_message(format!("{}", error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::{Identity, Key};
+    use crate::tomb::testing::new_tomb;
+    use k9::assert_equal;
+
+    #[test]
+    fn test_members() {
+        let alice = Key::generate();
+        let bob = Key::generate();
+        let carol = Key::generate();
+        let (_directory, mut tomb) = new_tomb(&alice);
+        tomb.add_secret("aws", String::from("123456"), &alice)
+            .unwrap();
+        assert_equal!(tomb.unlock_data_key(&alice).unwrap().is_none(), true);
+
+        let data_key = tomb.enable_members("alice", &alice).unwrap();
+        tomb.add_member("bob", &bob.public_key().unwrap(), &data_key)
+            .unwrap();
+        assert_equal!(
+            tomb.add_member("bob", &carol.public_key().unwrap(), &data_key)
+                .is_err(),
+            true
+        );
+        assert_equal!(tomb.unlock_data_key(&carol).is_err(), true);
+
+        let unlocked = tomb.unlock_data_key(&bob).unwrap().unwrap();
+        assert_equal!(unlocked.digest(), data_key.digest());
+        assert_equal!(
+            tomb.get_string("aws", &unlocked).unwrap().as_str(),
+            "123456"
+        );
+
+        let rotated = tomb.remove_member("bob", &data_key).unwrap();
+        assert_equal!(tomb.unlock_data_key(&bob).is_err(), true);
+        let unlocked = tomb.unlock_data_key(&alice).unwrap().unwrap();
+        assert_equal!(unlocked.digest(), rotated.digest());
+        assert_equal!(
+            tomb.get_string("aws", &unlocked).unwrap().as_str(),
+            "123456"
+        );
+        assert_equal!(tomb.get_string("aws", &data_key).is_err(), true);
+        assert_equal!(tomb.remove_member("alice", &rotated).is_err(), true);
+    }
+    #[test]
+    fn test_identity_members() {
+        let alice = Key::generate().with_identity(Identity::generate());
+        let bob = Identity::generate();
+        let (_directory, mut tomb) = new_tomb(&alice);
+        tomb.add_secret("aws", String::from("123456"), &alice)
+            .unwrap();
+
+        let data_key = tomb.enable_members("alice", &alice).unwrap();
+        assert_equal!(
+            tomb.member("alice").unwrap().public_key,
+            alice.identity.as_ref().unwrap().recipient
+        );
+        tomb.add_member("bob", &bob.recipient, &data_key).unwrap();
+
+        // bob opens the tomb with any key, as long as it carries his identity
+        let bob = Key::generate().with_identity(bob);
+        let unlocked = tomb.unlock_data_key(&bob).unwrap().unwrap();
+        assert_equal!(
+            tomb.get_string("aws", &unlocked).unwrap().as_str(),
+            "123456"
+        );
+        assert_equal!(tomb.unlock_data_key(&Key::generate()).is_err(), true);
+    }
+}
@@ -0,0 +1,433 @@
+//! Three-way merge of divergent tomb files
+//!
+//! Secrets are merged per key of `AES256Tomb.data`: a secret changed
+//! on one side only is taken from that side and a secret changed on
+//! both sides is a conflict, unless both sides stored the same
+//! contents, in which case the most recently updated one is kept.
+//!
+//! Values are compared by ciphertext, which differs between equal
+//! plaintexts encrypted with different IVs, so the key is only
+//! required to tell apart secrets whose metadata is the same but
+//! whose values differ, see `Merge::compare_values`. Secrets of
+//! shared groups are decrypted with the key of their group.
+//!
+//! The members, shared groups and key derivation config of the tomb
+//! are merged as a whole: the side that changed them wins, and the
+//! merge is refused when both sides changed them differently.
+use super::groups::SharedGroup;
+use super::members::Member;
+use super::{AES256Secret, AES256Tomb, Error};
+use crate::aes256cbc::{Config as AesConfig, Key};
+use console::style;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+/// A secret changed differently on both sides since the base
+#[derive(Clone)]
+pub struct Conflict {
+    pub key: String,
+    pub base: Option<AES256Secret>,
+    pub ours: Option<AES256Secret>,
+    pub theirs: Option<AES256Secret>,
+}
+
+impl Conflict {
+    pub fn path(&self) -> String {
+        match (&self.ours, &self.theirs, &self.base) {
+            (Some(secret), _, _) | (_, Some(secret), _) | (_, _, Some(secret)) => {
+                secret.path.clone()
+            }
+            _ => self.key.clone(),
+        }
+    }
+    pub fn describe(&self) -> String {
+        match (&self.base, &self.ours, &self.theirs) {
+            (_, None, _) => String::from("deleted in ours, modified in theirs"),
+            (_, _, None) => String::from("modified in ours, deleted in theirs"),
+            (None, _, _) => String::from("added in both"),
+            _ => String::from("modified in both"),
+        }
+    }
+    /// The side updated last, secrets are preferred over deletions
+    pub fn newest(&self) -> Side {
+        match (&self.ours, &self.theirs) {
+            (Some(ours), Some(theirs)) if theirs.updated_at > ours.updated_at => Side::Theirs,
+            (None, Some(_)) => Side::Theirs,
+            _ => Side::Ours,
+        }
+    }
+    fn take(&self, side: Side) -> Option<AES256Secret> {
+        match side {
+            Side::Ours => self.ours.clone(),
+            Side::Theirs => self.theirs.clone(),
+        }
+    }
+    /// Whether both sides hold secrets that only differ by their
+    /// encrypted values, which may still decrypt to the same plaintext
+    fn needs_key(&self) -> bool {
+        match (&self.ours, &self.theirs) {
+            (Some(ours), Some(theirs)) => same_metadata(ours, theirs) && ours.value != theirs.value,
+            _ => false,
+        }
+    }
+}
+
+pub struct Merge {
+    pub data: BTreeMap<String, AES256Secret>,
+    pub trash: BTreeMap<String, AES256Secret>,
+    pub conflicts: Vec<Conflict>,
+    pub members: Vec<Member>,
+    pub groups: BTreeMap<String, SharedGroup>,
+    pub config: AesConfig,
+}
+
+impl Merge {
+    /// Merges the secrets of `ours` and `theirs`, which must be
+    /// encrypted with the same key, against their common `base`
+    pub fn new(base: &AES256Tomb, ours: &AES256Tomb, theirs: &AES256Tomb) -> Result<Merge, Error> {
+        if ours.digest != theirs.digest {
+            return Err(Error::with_message(String::from(
+                "cannot merge tombs encrypted with different keys",
+            )));
+        }
+        let keys: BTreeSet<&String> = base
+            .data
+            .keys()
+            .chain(ours.data.keys())
+            .chain(theirs.data.keys())
+            .collect();
+        let mut merge = Merge {
+            data: BTreeMap::new(),
+            trash: merge_trash(base, ours, theirs),
+            conflicts: Vec::new(),
+            members: merge_setting("members", &base.members, &ours.members, &theirs.members)?
+                .clone(),
+            groups: merge_setting("shared groups", &base.groups, &ours.groups, &theirs.groups)?
+                .clone(),
+            config: merge_setting("key config", &base.config, &ours.config, &theirs.config)?
+                .clone(),
+        };
+        for key in keys {
+            let base = base.data.get(key);
+            let ours = ours.data.get(key);
+            let theirs = theirs.data.get(key);
+            let merged = if ours == theirs || theirs == base {
+                ours
+            } else if ours == base {
+                theirs
+            } else {
+                match (ours, theirs) {
+                    (Some(o), Some(t)) if same_metadata(o, t) && o.value == t.value => {
+                        if t.updated_at > o.updated_at {
+                            theirs
+                        } else {
+                            ours
+                        }
+                    }
+                    _ => {
+                        merge.conflicts.push(Conflict {
+                            key: key.clone(),
+                            base: base.cloned(),
+                            ours: ours.cloned(),
+                            theirs: theirs.cloned(),
+                        });
+                        continue;
+                    }
+                }
+            };
+            if let Some(secret) = merged {
+                merge.data.insert(key.clone(), secret.clone());
+            }
+        }
+        Ok(merge)
+    }
+    /// Whether `compare_values` may resolve some of the conflicts
+    pub fn needs_key(&self) -> bool {
+        self.conflicts.iter().any(|conflict| conflict.needs_key())
+    }
+    /// Resolves the conflicts between secrets with the same metadata
+    /// whose values decrypt to the same plaintext. `key` is the key of
+    /// `tomb`, whose unlocked groups give the keys of shared secrets.
+    /// Conflicts in groups `key` has no access to are left unresolved
+    pub fn compare_values(&mut self, tomb: &AES256Tomb, key: &Key) -> Result<(), Error> {
+        let mut conflicts = Vec::new();
+        for conflict in std::mem::take(&mut self.conflicts) {
+            if conflict.needs_key() {
+                let ours = conflict.ours.as_ref().unwrap();
+                let theirs = conflict.theirs.as_ref().unwrap();
+                let key = match tomb.key_for(&ours.path, key) {
+                    Ok(key) => key,
+                    Err(_) => {
+                        conflicts.push(conflict);
+                        continue;
+                    }
+                };
+                if *ours.get_bytes(&ours.path, key)? == *theirs.get_bytes(&theirs.path, key)? {
+                    self.resolve(&conflict, conflict.newest());
+                    continue;
+                }
+            }
+            conflicts.push(conflict);
+        }
+        self.conflicts = conflicts;
+        Ok(())
+    }
+    /// Keeps one side of a conflict in the merged secrets
+    pub fn resolve(&mut self, conflict: &Conflict, side: Side) {
+        match conflict.take(side) {
+            Some(secret) => self.data.insert(conflict.key.clone(), secret),
+            None => self.data.remove(&conflict.key),
+        };
+    }
+    /// Replaces the secrets and settings of `ours` with the merged
+    /// ones, once every conflict was resolved. Group keys unlocked for
+    /// groups that changed are dropped
+    pub fn apply(&self, ours: &mut AES256Tomb) -> Result<(), Error> {
+        if !self.conflicts.is_empty() {
+            return Err(Error::with_message(format!(
+                "{} unresolved conflicts",
+                self.conflicts.len()
+            )));
+        }
+        ours.data = self.data.clone();
+        ours.trash = self.trash.clone();
+        ours.trash.retain(|key, _| !self.data.contains_key(key));
+        ours.members = self.members.clone();
+        ours.groups = self.groups.clone();
+        ours.config = self.config.clone();
+        let groups = &self.groups;
+        ours.group_keys.retain(|name, key| match groups.get(name) {
+            Some(group) => group.digest == key.digest(),
+            None => false,
+        });
+        Ok(())
+    }
+}
+
+/// Merges the deleted secrets of both sides, keeping the most
+/// recently deleted one when both sides deleted the same secret
+fn merge_trash(
+    base: &AES256Tomb,
+    ours: &AES256Tomb,
+    theirs: &AES256Tomb,
+) -> BTreeMap<String, AES256Secret> {
+    let keys: BTreeSet<&String> = base
+        .trash
+        .keys()
+        .chain(ours.trash.keys())
+        .chain(theirs.trash.keys())
+        .collect();
+    let mut trash = BTreeMap::new();
+    for key in keys {
+        let base = base.trash.get(key);
+        let ours = ours.trash.get(key);
+        let theirs = theirs.trash.get(key);
+        let merged = if ours == theirs || theirs == base {
+            ours
+        } else if ours == base {
+            theirs
+        } else {
+            match (ours, theirs) {
+                (Some(o), Some(t)) if t.deleted_at > o.deleted_at => theirs,
+                (None, _) => theirs,
+                _ => ours,
+            }
+        };
+        if let Some(secret) = merged {
+            trash.insert(key.clone(), secret.clone());
+        }
+    }
+    trash
+}
+
+/// Merges a setting of the tomb as a whole, refusing to pick a side
+/// when both changed it differently since the base
+fn merge_setting<'a, T: PartialEq>(
+    name: &str,
+    base: &'a T,
+    ours: &'a T,
+    theirs: &'a T,
+) -> Result<&'a T, Error> {
+    if ours == theirs || theirs == base {
+        Ok(ours)
+    } else if ours == base {
+        Ok(theirs)
+    } else {
+        Err(Error::with_message(format!(
+            "{}{}{}",
+            style("the ").color256(198),
+            style(name).color256(190),
+            style(" of the tomb changed on both sides, merge them by hand").color256(198),
+        )))
+    }
+}
+
+fn same_metadata(a: &AES256Secret, b: &AES256Secret) -> bool {
+    a.path == b.path
+        && a.notes == b.notes
+        && a.username == b.username
+        && a.url == b.url
+        && a.attributes == b.attributes
+        && a.sealed == b.sealed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Merge, Side};
+    use crate::aes256cbc::{Identity, Key};
+    use crate::tomb::testing::new_tomb;
+    use k9::assert_equal;
+
+    fn sorted_paths(merge: &Merge) -> Vec<String> {
+        let mut paths: Vec<String> = merge.data.values().map(|s| s.path.clone()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_merge_one_sided_changes() {
+        let key = Key::generate();
+        let (_directory, mut base) = new_tomb(&key);
+        base.add_secret("netflix", String::from("123456"), &key)
+            .unwrap();
+        base.add_secret("spotify", String::from("987654"), &key)
+            .unwrap();
+
+        let mut ours = base.clone();
+        ours.add_secret("email", String::from("abcdef"), &key)
+            .unwrap();
+        let mut theirs = base.clone();
+        theirs.delete_secret("spotify").unwrap();
+        theirs
+            .add_secret("netflix", String::from("654321"), &key)
+            .unwrap();
+
+        let merge = Merge::new(&base, &ours, &theirs).unwrap();
+        assert_equal!(merge.conflicts.len(), 0);
+        assert_equal!(merge.needs_key(), false);
+        assert_equal!(sorted_paths(&merge), vec!["email", "netflix"]);
+
+        merge.apply(&mut ours).unwrap();
+        assert_equal!(ours.get_string("netflix", &key).unwrap().as_str(), "654321");
+    }
+    #[test]
+    fn test_merge_conflicts() {
+        let key = Key::generate();
+        let (_directory, mut base) = new_tomb(&key);
+        base.add_secret("netflix", String::from("123456"), &key)
+            .unwrap();
+        base.add_secret("spotify", String::from("987654"), &key)
+            .unwrap();
+
+        let mut ours = base.clone();
+        ours.add_secret("netflix", String::from("same"), &key)
+            .unwrap();
+        ours.add_secret("spotify", String::from("ours"), &key)
+            .unwrap();
+        let mut theirs = base.clone();
+        theirs
+            .add_secret("netflix", String::from("same"), &key)
+            .unwrap();
+        theirs
+            .add_secret("spotify", String::from("theirs"), &key)
+            .unwrap();
+
+        let mut merge = Merge::new(&base, &ours, &theirs).unwrap();
+        assert_equal!(merge.conflicts.len(), 2);
+        assert_equal!(merge.needs_key(), true);
+        assert_equal!(merge.apply(&mut ours.clone()).is_err(), true);
+
+        merge.compare_values(&ours, &key).unwrap();
+        assert_equal!(merge.conflicts.len(), 1);
+        let conflict = merge.conflicts[0].clone();
+        assert_equal!(conflict.path(), "spotify");
+        assert_equal!(conflict.describe(), "modified in both");
+        assert_equal!(conflict.newest(), Side::Theirs);
+
+        merge.resolve(&conflict, Side::Ours);
+        merge.conflicts.clear();
+        merge.apply(&mut ours).unwrap();
+        assert_equal!(ours.get_string("spotify", &key).unwrap().as_str(), "ours");
+        assert_equal!(ours.get_string("netflix", &key).unwrap().as_str(), "same");
+    }
+    #[test]
+    fn test_merge_compares_values_of_members_and_shared_groups() {
+        let alice = Key::generate();
+        let (_directory, mut base) = new_tomb(&alice);
+        base.add_secret("netflix", String::from("123456"), &alice)
+            .unwrap();
+        base.add_secret("work/aws", String::from("987654"), &alice)
+            .unwrap();
+        let data_key = base.enable_members("alice", &alice).unwrap();
+        base.share_group("work", &data_key).unwrap();
+
+        let mut ours = base.clone();
+        let mut theirs = base.clone();
+        for tomb in [&mut ours, &mut theirs] {
+            tomb.add_secret("netflix", String::from("same"), &data_key)
+                .unwrap();
+            tomb.add_secret("work/aws", String::from("same"), &data_key)
+                .unwrap();
+        }
+
+        let mut merge = Merge::new(&base, &ours, &theirs).unwrap();
+        assert_equal!(merge.conflicts.len(), 2);
+        assert_equal!(merge.needs_key(), true);
+
+        // as loaded from disk: only the member key of alice is known
+        ours.group_keys.clear();
+        let unlocked = ours.unlock(&alice).unwrap().unwrap();
+        assert_equal!(unlocked.digest(), data_key.digest());
+        merge.compare_values(&ours, &unlocked).unwrap();
+        assert_equal!(merge.conflicts.len(), 0);
+
+        merge.apply(&mut ours).unwrap();
+        assert_equal!(
+            ours.get_string("work/aws", &data_key).unwrap().as_str(),
+            "same"
+        );
+    }
+    #[test]
+    fn test_merge_settings() {
+        let alice = Key::generate();
+        let (_directory, mut base) = new_tomb(&alice);
+        base.add_secret("work/aws", String::from("123456"), &alice)
+            .unwrap();
+        let data_key = base.enable_members("alice", &alice).unwrap();
+
+        let mut ours = base.clone();
+        ours.add_secret("netflix", String::from("987654"), &data_key)
+            .unwrap();
+        let mut theirs = base.clone();
+        theirs
+            .add_member("bob", &Identity::generate().recipient, &data_key)
+            .unwrap();
+        theirs.share_group("work", &data_key).unwrap();
+
+        let merge = Merge::new(&base, &ours, &theirs).unwrap();
+        assert_equal!(merge.conflicts.len(), 0);
+        merge.apply(&mut ours).unwrap();
+        assert_equal!(ours.member("bob").is_some(), true);
+        assert_equal!(ours.shared_group("work/aws"), Some("/work"));
+        assert_equal!(ours.group_keys.is_empty(), true);
+        assert_equal!(ours.get("netflix").is_ok(), true);
+
+        // members changed differently on both sides
+        let mut ours = base.clone();
+        ours.add_member("carol", &Identity::generate().recipient, &data_key)
+            .unwrap();
+        assert_equal!(Merge::new(&base, &ours, &theirs).is_err(), true);
+    }
+    #[test]
+    fn test_merge_requires_the_same_key() {
+        let key = Key::generate();
+        let (_directory, base) = new_tomb(&key);
+        let (_other, theirs) = new_tomb(&Key::generate());
+        assert_equal!(Merge::new(&base, &base, &theirs).is_err(), true);
+    }
+}
@@ -1,22 +1,46 @@
+pub mod container;
+pub mod generator;
+pub mod groups;
 pub mod logging;
-use crate::aes256cbc::{Config as AesConfig, Digest, Key};
+pub mod members;
+pub mod merge;
+#[cfg(test)]
+mod testing;
+pub mod verify;
+use crate::aes256cbc::{hmac_256_digest, Config as AesConfig, Digest, Error as AesError, Key};
 
 use crate::core::version;
 use crate::{
     config::{YamlFile, YamlFileError},
-    ioutils::{b64decode, b64encode},
+    ioutils::{
+        absolute_path, b64decode, b64encode, backup_filename, list_backups, lock_file,
+        rotate_backups, write_atomic, FileLock,
+    },
     logger,
+    migrations::{
+        format_version, migrate_file, migrate_yaml, tomb_format_version, DocumentKind,
+        TOMB_AUTHENTICATED_VERSION, TOMB_FORMAT_VERSION,
+    },
+    sensitive::{Sensitive, SensitiveBytes, SensitiveString},
 };
-use chrono::prelude::*;
+use chrono::{prelude::*, Duration};
 use console::style;
+use container::{is_container, Container};
 use fnmatch_regex::glob_to_regex;
+use groups::SharedGroup;
 use logging::*;
 use md5;
+use members::Member;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use shellexpand;
 use std::collections::BTreeMap;
-use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use std::{fmt, fs};
 pub const TOMB_FILE: &'static str = "~/.tomb.yaml";
+pub const TOMB_BACKUPS: usize = 5;
+pub const TOMB_HISTORY: usize = 10;
 
 pub fn default_tomb_filename() -> String {
     match std::env::var("TOMB_FILE") {
@@ -25,9 +49,98 @@ pub fn default_tomb_filename() -> String {
         Err(_err) => String::from(TOMB_FILE),
     }
 }
+/// How many previous generations of the tomb file are kept as
+/// `.tomb.yaml.bak.N`, customizable via `TOMB_BACKUPS`
+pub fn default_backup_generations() -> usize {
+    match std::env::var("TOMB_BACKUPS") {
+        Ok(generations) => generations.parse().unwrap_or(TOMB_BACKUPS),
+        Err(_err) => TOMB_BACKUPS,
+    }
+}
+/// How many previous values of each secret are kept, customizable
+/// via `TOMB_HISTORY` and the `history_retention` of `TombConfig`
+pub fn default_history_retention() -> usize {
+    match std::env::var("TOMB_HISTORY") {
+        Ok(retention) => retention.parse().unwrap_or(TOMB_HISTORY),
+        Err(_err) => TOMB_HISTORY,
+    }
+}
 pub fn path_to_md5(path: &str) -> String {
     format!("{:x}", md5::compute(String::from(path).as_bytes()))
 }
+/// The map key of a secret in a tomb with sealed metadata, unlike
+/// `path_to_md5` it cannot be guessed without the key
+pub fn path_to_hmac(path: &str, key: &Key) -> Result<String, Error> {
+    let mac = match key.mac_bytes() {
+        Ok(mac) => mac,
+        Err(error) => return Err(Error::with_message(format!("{}", error))),
+    };
+    match hmac_256_digest(mac, path.as_bytes()) {
+        Ok(digest) => Ok(digest.iter().map(|b| format!("{:02x}", b)).collect()),
+        Err(error) => Err(Error::with_message(format!("{}", error))),
+    }
+}
+
+fn matching_secrets(secrets: &BTreeMap<String, AES256Secret>, regex: &Regex) -> Vec<AES256Secret> {
+    let mut result = Vec::new();
+    for (md5key, secret) in secrets {
+        let path = secret.path.clone();
+        if regex.is_match(&path) && (md5key.eq(&secret.key()) || md5key.eq(&path)) {
+            result.push(secret.clone());
+        }
+    }
+    result
+}
+fn unseal_secrets(
+    secrets: BTreeMap<String, AES256Secret>,
+    key: &Key,
+) -> Result<BTreeMap<String, AES256Secret>, Error> {
+    let mut unsealed = BTreeMap::new();
+    for (md5key, mut secret) in secrets {
+        if secret.is_sealed() {
+            secret.unseal(key)?;
+            unsealed.insert(secret.key(), secret);
+        } else {
+            unsealed.insert(md5key, secret);
+        }
+    }
+    Ok(unsealed)
+}
+fn seal_secrets(
+    secrets: &BTreeMap<String, AES256Secret>,
+    key: &Key,
+) -> Result<BTreeMap<String, AES256Secret>, Error> {
+    let mut sealed = BTreeMap::new();
+    for secret in secrets.values() {
+        let mut secret = secret.clone();
+        let hmackey = path_to_hmac(&secret.path, key)?;
+        secret.seal(key)?;
+        sealed.insert(hmackey, secret);
+    }
+    Ok(sealed)
+}
+/// Re-encrypts the secrets whose path matches `filter`
+fn rekey_secrets<F: Fn(&str) -> bool>(
+    secrets: &BTreeMap<String, AES256Secret>,
+    old: &Key,
+    new: &Key,
+    filter: F,
+) -> Result<BTreeMap<String, AES256Secret>, Error> {
+    let mut rekeyed = BTreeMap::new();
+    for (md5key, secret) in secrets {
+        let mut secret = secret.clone();
+        if filter(&secret.path) {
+            secret.rekey(old, new)?;
+        }
+        rekeyed.insert(md5key.clone(), secret);
+    }
+    Ok(rekeyed)
+}
+
+/// Identifies the contents of a tomb file, see `AES256Tomb::base`
+fn content_hash(data: &str) -> String {
+    format!("{:x}", md5::compute(data.as_bytes()))
+}
 
 #[derive(Debug, Clone)]
 pub struct Error {
@@ -68,10 +181,41 @@ pub struct AES256Secret {
     pub attributes: Option<BTreeMap<String, String>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// base64 of the encrypted `SecretMetadata` when the tomb is sealed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<String>,
+    /// Previous values, newest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<SecretVersion>,
+    /// When the secret was moved to the trash of its tomb
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Whether its values may predate authentication tags, set for the
+    /// secrets of tombs below `TOMB_AUTHENTICATED_VERSION`
+    #[serde(skip)]
+    pub untagged: bool,
+}
+
+/// A previous value of an `AES256Secret`, encrypted with the same key
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SecretVersion {
+    pub value: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// The fields of an `AES256Secret` that are encrypted in sealed tombs
+#[derive(Serialize, Deserialize)]
+struct SecretMetadata {
+    path: String,
+    notes: Option<String>,
+    username: Option<String>,
+    url: Option<String>,
+    attributes: Option<BTreeMap<String, String>>,
 }
+
 impl AES256Secret {
     /// Creates a new tomb based on a key
-    pub fn new(path: String, value: Vec<u8>, key: Key) -> AES256Secret {
+    pub fn new(path: String, value: Vec<u8>, key: &Key) -> AES256Secret {
         AES256Secret {
             digest: key.digest(),
             path,
@@ -82,6 +226,10 @@ impl AES256Secret {
             attributes: Some(BTreeMap::new()),
             created_at: Utc::now(),
             updated_at: Utc::now(),
+            sealed: None,
+            history: Vec::new(),
+            deleted_at: None,
+            untagged: false,
         }
     }
     pub fn key(&self) -> String {
@@ -138,10 +286,152 @@ impl AES256Secret {
         self.set_url(url);
         self.clone()
     }
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        self.attributes
+            .get_or_insert_with(Default::default)
+            .insert(String::from(name), String::from(value));
+    }
+    /// Removes an attribute, returning whether the secret had it
+    pub fn unset_attribute(&mut self, name: &str) -> bool {
+        match self.attributes.as_mut() {
+            Some(attributes) => attributes.remove(name).is_some(),
+            None => false,
+        }
+    }
+    /// Names of the fields of `field`, besides `attributes.<name>`
+    pub const FIELDS: [&'static str; 8] = [
+        "path",
+        "name",
+        "group",
+        "username",
+        "url",
+        "notes",
+        "created_at",
+        "updated_at",
+    ];
+    /// The metadata field called `name`, one of `FIELDS` or
+    /// `attributes.<name>`, or `None` when the secret does not set it
+    pub fn field(&self, name: &str) -> Result<Option<String>, Error> {
+        match name {
+            "path" => Ok(Some(self.path.clone())),
+            "name" => Ok(Some(self.name())),
+            "group" => Ok(Some(self.group())),
+            "username" => Ok(self.username.clone()),
+            "url" => Ok(self.url.clone()),
+            "notes" => Ok(self.notes.clone()),
+            "created_at" => Ok(Some(self.created_at.to_rfc3339())),
+            "updated_at" => Ok(Some(self.updated_at.to_rfc3339())),
+            _ => match name.strip_prefix("attributes.") {
+                Some(attribute) => Ok(self
+                    .attributes
+                    .as_ref()
+                    .and_then(|attributes| attributes.get(attribute))
+                    .cloned()),
+                None => Err(Error::with_message(format!(
+                    "{}{}{}",
+                    style("unknown field ").color256(198),
+                    style(name).color256(190),
+                    style(format!(
+                        ", available fields: {}, attributes.<name>",
+                        AES256Secret::FIELDS.join(", ")
+                    ))
+                    .color256(198),
+                ))),
+            },
+        }
+    }
+    pub fn is_sealed(&self) -> bool {
+        self.sealed.is_some()
+    }
+    /// Encrypts path, notes, username, url and attributes with the key
+    /// and blanks them out
+    pub fn seal(&mut self, key: &Key) -> Result<(), Error> {
+        let metadata = SecretMetadata {
+            path: std::mem::take(&mut self.path),
+            notes: self.notes.take(),
+            username: self.username.take(),
+            url: self.url.take(),
+            attributes: self.attributes.take(),
+        };
+        let yaml = match serde_yaml::to_string(&metadata) {
+            Ok(yaml) => Sensitive::new(yaml),
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "failed to encode metadata of secret {}: {}",
+                    metadata.path, error
+                )))
+            }
+        };
+        match key.encrypt(yaml.as_bytes()) {
+            Ok(cyphertext) => {
+                self.sealed = Some(b64encode(&cyphertext));
+                Ok(())
+            }
+            Err(error) => Err(Error::with_message(format!(
+                "{}{}{}{}",
+                style("cannot seal metadata of secret ").color256(198),
+                style(&metadata.path).color256(190),
+                style(" with the provided key.").color256(198),
+                style(format!("\n\t{:?}", error)).color256(197),
+            ))),
+        }
+    }
+    /// Decrypts the sealed metadata back into path, notes, username,
+    /// url and attributes
+    pub fn unseal(&mut self, key: &Key) -> Result<(), Error> {
+        let sealed = match &self.sealed {
+            Some(sealed) => sealed,
+            None => return Ok(()),
+        };
+        let cyphertext = match b64decode(sealed.as_bytes()) {
+            Ok(cyphertext) => cyphertext,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "parse base64 sealed metadata: {}",
+                    error
+                )))
+            }
+        };
+        let yaml = match key.decrypt(&cyphertext) {
+            Ok(plaintext) => match plaintext.into_string() {
+                Ok(yaml) => yaml,
+                Err(error) => {
+                    return Err(Error::with_message(format!(
+                        "sealed metadata is not valid utf-8: {}",
+                        error
+                    )))
+                }
+            },
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "{}{}",
+                    style("cannot unseal secret metadata with the provided key.").color256(198),
+                    style(format!("\n\t{:?}", error)).color256(197),
+                )))
+            }
+        };
+        let metadata: SecretMetadata = match serde_yaml::from_str(&yaml) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "failed to decode sealed metadata: {}",
+                    error
+                )))
+            }
+        };
+        self.path = metadata.path;
+        self.notes = metadata.notes;
+        self.username = metadata.username;
+        self.url = metadata.url;
+        self.attributes = metadata.attributes;
+        self.sealed = None;
+        Ok(())
+    }
     pub fn value_bytes(&self) -> Vec<u8> {
         b64decode(&self.value.as_bytes()).unwrap()
     }
-    pub fn update(&mut self, path: String, plaintext: Vec<u8>, key: Key) -> Result<(), Error> {
+    pub fn update(&mut self, path: String, plaintext: Vec<u8>, key: &Key) -> Result<(), Error> {
+        let plaintext = Sensitive::new(plaintext);
         self.digest = key.digest();
         self.path = path.clone();
         let cyphertext = match key.encrypt(&plaintext) {
@@ -156,19 +446,169 @@ impl AES256Secret {
                 )));
             }
         };
-        self.value = b64encode(&cyphertext);
+        self.history.insert(
+            0,
+            SecretVersion {
+                value: std::mem::replace(&mut self.value, b64encode(&cyphertext)),
+                updated_at: self.updated_at,
+            },
+        );
         self.updated_at = Utc::now();
         Ok(())
     }
-    pub fn get_base64_string(&self, path: &str, key: Key) -> Result<String, Error> {
+    /// Drops the oldest previous values beyond `retention`
+    pub fn prune_history(&mut self, retention: usize) {
+        self.history.truncate(retention);
+    }
+    /// The value of the given version, 0 being the current one and 1
+    /// the one it replaced
+    pub fn get_version_bytes(
+        &self,
+        path: &str,
+        version: usize,
+        key: &Key,
+    ) -> Result<SensitiveBytes, Error> {
+        if version == 0 {
+            return self.get_bytes(path, key);
+        }
+        let previous = match self.history.get(version - 1) {
+            Some(previous) => previous,
+            None => {
+                return Err(Error::with_message(format!(
+                    "{}{}{}",
+                    style(format!("version {} not found in the history of ", version))
+                        .color256(198),
+                    style(path).color256(190),
+                    style(format!(", it has {} previous versions", self.history.len()))
+                        .color256(198),
+                )))
+            }
+        };
+        let cyphertext = match b64decode(previous.value.as_bytes()) {
+            Ok(cyphertext) => cyphertext,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "parse base64 version {} of {}: {}",
+                    version, path, error
+                )))
+            }
+        };
+        match self.decrypt(&cyphertext, key) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(error) => Err(Error::with_message(format!(
+                "{}{}{}",
+                style(format!("cannot decrypt version {} of secret ", version)).color256(198),
+                style(path).color256(190),
+                style(format!(" with the provided key.\n\t{:?}", error)).color256(198),
+            ))),
+        }
+    }
+    /// Decrypts a value of this secret, refusing values without an
+    /// authentication tag unless the secret predates them
+    fn decrypt(&self, cyphertext: &[u8], key: &Key) -> Result<SensitiveBytes, AesError> {
+        match self.untagged {
+            true => key.decrypt_untagged(cyphertext),
+            false => key.decrypt(cyphertext),
+        }
+    }
+    /// Re-encrypts the values that predate authentication tags with
+    /// the same key, so that untagged values are refused from now on
+    pub fn authenticate(&mut self, key: &Key) -> Result<(), Error> {
+        if !self.untagged {
+            return Ok(());
+        }
+        let retag = |value: &str| -> Result<String, Error> {
+            let cyphertext = match b64decode(value.as_bytes()) {
+                Ok(cyphertext) => cyphertext,
+                Err(error) => {
+                    return Err(Error::with_message(format!(
+                        "parse base64 value of {}: {}",
+                        self.path, error
+                    )))
+                }
+            };
+            if key.is_tagged(&cyphertext) {
+                return Ok(String::from(value));
+            }
+            let retagged = key
+                .decrypt_untagged(&cyphertext)
+                .and_then(|plaintext| key.encrypt(&plaintext));
+            match retagged {
+                Ok(cyphertext) => Ok(b64encode(&cyphertext)),
+                Err(error) => Err(Error::with_message(format!(
+                    "{}{}{}",
+                    style("cannot authenticate the values of secret ").color256(198),
+                    style(&self.path).color256(190),
+                    style(format!("\n\t{}", error)).color256(197),
+                ))),
+            }
+        };
+        let value = retag(&self.value)?;
+        let mut history = Vec::new();
+        for previous in &self.history {
+            history.push(SecretVersion {
+                value: retag(&previous.value)?,
+                updated_at: previous.updated_at,
+            });
+        }
+        self.value = value;
+        self.history = history;
+        self.untagged = false;
+        Ok(())
+    }
+    /// Re-encrypts the value with another key, keeping the timestamps
+    pub fn rekey(&mut self, old: &Key, new: &Key) -> Result<(), Error> {
+        if self.is_sealed() {
+            return Err(Error::with_message(String::from(
+                "cannot rekey a secret with sealed metadata, unseal it first",
+            )));
+        }
+        let plaintext = self.get_bytes(&self.path, old)?;
+        let cyphertext = match new.encrypt(&plaintext) {
+            Ok(cypher) => cypher,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "{}{}{}{}",
+                    style("cannot encrypt data for path ").color256(198),
+                    style(&self.path).color256(190),
+                    style(" with the new key.").color256(198),
+                    style(format!("\n\t{:?}", error)).color256(197),
+                )));
+            }
+        };
+        let mut history = Vec::new();
+        for (index, previous) in self.history.iter().enumerate() {
+            let plaintext = self.get_version_bytes(&self.path, index + 1, old)?;
+            match new.encrypt(&plaintext) {
+                Ok(cypher) => history.push(SecretVersion {
+                    value: b64encode(&cypher),
+                    updated_at: previous.updated_at,
+                }),
+                Err(error) => {
+                    return Err(Error::with_message(format!(
+                        "cannot encrypt version {} of {} with the new key: {:?}",
+                        index + 1,
+                        self.path,
+                        error
+                    )))
+                }
+            }
+        }
+        self.digest = new.digest();
+        self.value = b64encode(&cyphertext);
+        self.history = history;
+        self.untagged = false;
+        Ok(())
+    }
+    pub fn get_base64_string(&self, path: &str, key: &Key) -> Result<SensitiveString, Error> {
         match self.get_bytes(path, key) {
-            Ok(bytes) => Ok(b64encode(&bytes)),
+            Ok(bytes) => Ok(Sensitive::new(b64encode(&bytes))),
             Err(error) => Err(error),
         }
     }
-    pub fn get_string(&self, path: &str, key: Key) -> Result<String, Error> {
+    pub fn get_string(&self, path: &str, key: &Key) -> Result<SensitiveString, Error> {
         match self.get_bytes(path, key) {
-            Ok(bytes) => match String::from_utf8(bytes) {
+            Ok(bytes) => match bytes.into_string() {
                 Ok(value) => Ok(value),
                 Err(error) => {
                     return Err(Error::with_message(format!(
@@ -183,14 +623,14 @@ impl AES256Secret {
             Err(error) => Err(error),
         }
     }
-    pub fn get_bytes(&self, path: &str, key: Key) -> Result<Vec<u8>, Error> {
+    pub fn get_bytes(&self, path: &str, key: &Key) -> Result<SensitiveBytes, Error> {
         if String::from(path) != self.path {
             return Err(Error::with_message(format!(
                 "path {} does not match {}",
                 path, self.path
             )));
         }
-        match key.decrypt(&self.value_bytes()) {
+        match self.decrypt(&self.value_bytes(), key) {
             Ok(plaintext) => Ok(plaintext),
             Err(error) => {
                 return Err(Error::with_message(format!(
@@ -205,29 +645,378 @@ impl AES256Secret {
     }
 }
 
+/// How a tomb is stored on disk
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TombFormat {
+    /// yaml with every secret value encrypted individually
+    #[default]
+    Yaml,
+    /// the whole yaml encrypted in an armored `Container`
+    Container,
+}
+
 #[derive(PartialEq, Clone, Serialize, Deserialize)]
 pub struct AES256Tomb {
+    #[serde(default = "tomb_format_version")]
+    pub format_version: u32,
     pub digest: Digest,
     pub config: AesConfig,
     pub filepath: Option<String>,
     pub data: BTreeMap<String, AES256Secret>,
     pub version: Option<String>,
+    /// Whether the metadata of secrets is encrypted at rest, such
+    /// tombs can only be saved and reloaded with the key
+    #[serde(default)]
+    pub sealed: bool,
+    /// Detected by `import_with_key` and honored by `save_with_key`
+    #[serde(skip)]
+    pub format: TombFormat,
+    /// md5 of the tomb file this tomb was loaded from, saves are
+    /// rejected once another process has changed the file since
+    #[serde(skip)]
+    pub base: Option<String>,
+    /// How many previous values of each secret are kept by `add_secret`
+    #[serde(skip, default = "default_history_retention")]
+    pub history_retention: usize,
+    /// Deleted secrets, indexed like `data` until they are purged
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trash: BTreeMap<String, AES256Secret>,
+    /// Keys the data key is wrapped for, see `members`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<Member>,
+    /// Sub-trees encrypted with their own key, see `groups`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, SharedGroup>,
+    /// Keys of the shared groups unlocked by `import_with_key`
+    #[serde(skip)]
+    pub group_keys: BTreeMap<String, Rc<Key>>,
+    /// Whether the tomb was opened by a key that is only a member of
+    /// some of its groups, which cannot decrypt the other secrets
+    #[serde(skip)]
+    pub group_only: bool,
 }
 impl YamlFile<Error> for AES256Tomb {
+    fn document_kind() -> Option<DocumentKind> {
+        Some(DocumentKind::Tomb)
+    }
+    /// Deserializes a yaml tomb, containers require the key and are
+    /// loaded with `AES256Tomb::import_with_key`
+    fn from_yaml<'a>(data: String) -> Result<AES256Tomb, Error> {
+        if is_container(&data) {
+            return Err(Error::with_message(String::from(
+                "the tomb file is an encrypted container, the key is required to open it",
+            )));
+        }
+        let data = AES256Tomb::migrate(&data)?.unwrap_or(data);
+        match serde_yaml::from_str::<AES256Tomb>(&data) {
+            Ok(mut tomb) => {
+                tomb.flag_untagged();
+                Ok(tomb)
+            }
+            Err(error) => Err(Error::with_message(format!(
+                "failed to deserialize yaml config: {}",
+                error
+            ))),
+        }
+    }
     fn default() -> Result<AES256Tomb, Error> {
         AES256Tomb::import(default_tomb_filename().as_str())
     }
+    /// Loads a yaml tomb, remembering the contents it was loaded from
+    fn import(filename: &str) -> Result<AES256Tomb, Error> {
+        let data = AES256Tomb::read_migrated(filename)?;
+        let base = content_hash(&data);
+        let mut tomb = AES256Tomb::from_yaml(data)?;
+        tomb.base = Some(base);
+        Ok(tomb)
+    }
 }
 
 impl AES256Tomb {
     /// Creates a new tomb based on a key
-    pub fn new(filepath: &str, key: Key, config: AesConfig) -> AES256Tomb {
+    pub fn new(filepath: &str, key: &Key, config: AesConfig) -> AES256Tomb {
         AES256Tomb {
+            format_version: TOMB_FORMAT_VERSION,
             digest: key.digest(),
             data: BTreeMap::new(),
             filepath: Some(String::from(filepath)),
             version: Some(version()),
             config,
+            sealed: false,
+            format: TombFormat::Yaml,
+            base: None,
+            history_retention: default_history_retention(),
+            trash: BTreeMap::new(),
+            members: Vec::new(),
+            groups: BTreeMap::new(),
+            group_keys: BTreeMap::new(),
+            group_only: false,
+        }
+    }
+    fn read_file(filename: &str) -> Result<String, Error> {
+        let filename = absolute_path(filename);
+        match fs::read_to_string(&filename) {
+            Ok(data) => Ok(data),
+            Err(error) => Err(Error::with_message(format!(
+                "{}{}{}",
+                style("failed to read file ").color256(198),
+                style(&filename).color256(190),
+                style(format!("\n\t{}", error)).color256(197),
+            ))),
+        }
+    }
+    /// Upgrades the yaml of a tomb written by an older version
+    fn migrate(yaml: &str) -> Result<Option<String>, Error> {
+        match migrate_yaml(DocumentKind::Tomb, yaml) {
+            Ok(migrated) => Ok(migrated),
+            Err(error) => Err(Error::with_message(format!("{}", error))),
+        }
+    }
+    /// Reads a tomb file, migrating yaml tombs written by an older
+    /// version in place first. Containers are migrated in memory once
+    /// opened and upgraded on their next save
+    fn read_migrated(filename: &str) -> Result<String, Error> {
+        let data = AES256Tomb::read_file(filename)?;
+        if is_container(&data) {
+            return Ok(data);
+        }
+        let version = match format_version(DocumentKind::Tomb, &data) {
+            Ok(version) => version,
+            Err(error) => return Err(Error::with_message(format!("{}", error))),
+        };
+        if version >= DocumentKind::Tomb.migrated_version() {
+            return Ok(data);
+        }
+        let migrated = match lock_file(filename) {
+            Ok(_lock) => migrate_file(DocumentKind::Tomb, filename),
+            Err(error) => return Err(Error::with_message(format!("{}", error))),
+        };
+        match migrated {
+            Ok(_) => AES256Tomb::read_file(filename),
+            Err(error) => Err(Error::with_message(format!("{}", error))),
+        }
+    }
+    /// Marks the secrets of tombs below `TOMB_AUTHENTICATED_VERSION`,
+    /// whose values may predate authentication tags
+    fn flag_untagged(&mut self) {
+        if self.format_version >= TOMB_AUTHENTICATED_VERSION {
+            return;
+        }
+        for secret in self.data.values_mut().chain(self.trash.values_mut()) {
+            secret.untagged = true;
+        }
+    }
+    /// Re-encrypts the values that predate authentication tags and
+    /// upgrades the tomb to `TOMB_AUTHENTICATED_VERSION`, from which
+    /// untagged values are refused as tampered with. Tombs opened by
+    /// members of shared groups only are left as they are.
+    pub fn authenticate(&mut self, key: &Key) -> Result<(), Error> {
+        if self.format_version >= TOMB_AUTHENTICATED_VERSION || self.group_only {
+            return Ok(());
+        }
+        let mut data = self.data.clone();
+        let mut trash = self.trash.clone();
+        for secret in data.values_mut().chain(trash.values_mut()) {
+            secret.authenticate(self.key_for(&secret.path, key)?)?;
+        }
+        self.data = data;
+        self.trash = trash;
+        self.format_version = TOMB_FORMAT_VERSION;
+        Ok(())
+    }
+    /// Decrypts the yaml tomb stored in a container
+    fn open_container(data: &str, key: &Key) -> Result<Sensitive<String>, Error> {
+        match Container::parse(data)?.open(key)?.into_string() {
+            Ok(yaml) => Ok(yaml),
+            Err(error) => Err(Error::with_message(format!(
+                "tomb container is not valid utf-8: {}",
+                error
+            ))),
+        }
+    }
+    /// The format version a tomb file was written with, without
+    /// migrating it. `None` for containers when no key is given
+    pub fn file_format_version(filename: &str, key: Option<&Key>) -> Result<Option<u32>, Error> {
+        let data = AES256Tomb::read_file(filename)?;
+        let version = match (is_container(&data), key) {
+            (true, None) => return Ok(None),
+            (true, Some(key)) => {
+                format_version(DocumentKind::Tomb, &AES256Tomb::open_container(&data, key)?)
+            }
+            (false, _) => format_version(DocumentKind::Tomb, &data),
+        };
+        match version {
+            Ok(version) => Ok(Some(version)),
+            Err(error) => Err(Error::with_message(format!("{}", error))),
+        }
+    }
+    /// The key derivation config of a tomb file in either format,
+    /// read without the key so that it can be derived from a password
+    pub fn import_config(filename: &str) -> Result<AesConfig, Error> {
+        AES256Tomb::parse_config(AES256Tomb::read_migrated(filename)?)
+    }
+    /// Reads the key derivation config of a tomb file like
+    /// `import_config`, without migrating the file
+    pub fn read_config(filename: &str) -> Result<AesConfig, Error> {
+        AES256Tomb::parse_config(AES256Tomb::read_file(filename)?)
+    }
+    fn parse_config(data: String) -> Result<AesConfig, Error> {
+        if is_container(&data) {
+            Ok(Container::parse(&data)?.config)
+        } else {
+            Ok(AES256Tomb::from_yaml(data)?.config)
+        }
+    }
+    /// Loads a yaml tomb like `import` without writing to the file,
+    /// tombs written by an older version are only migrated in memory
+    pub fn read(filename: &str) -> Result<AES256Tomb, Error> {
+        let data = AES256Tomb::read_file(filename)?;
+        let base = content_hash(&data);
+        let mut tomb = AES256Tomb::from_yaml(data)?;
+        tomb.base = Some(base);
+        Ok(tomb)
+    }
+    /// Loads a tomb file like `import_with_key` without writing to the
+    /// file, tombs written by an older version are only migrated in memory
+    pub fn read_with_key(filename: &str, key: &Key) -> Result<AES256Tomb, Error> {
+        AES256Tomb::parse_with_key(AES256Tomb::read_file(filename)?, key)
+    }
+    /// Loads a tomb file in either format and unseals its metadata
+    pub fn import_with_key(filename: &str, key: &Key) -> Result<AES256Tomb, Error> {
+        AES256Tomb::parse_with_key(AES256Tomb::read_migrated(filename)?, key)
+    }
+    fn parse_with_key(data: String, key: &Key) -> Result<AES256Tomb, Error> {
+        let base = content_hash(&data);
+        let mut tomb = if is_container(&data) {
+            let yaml = AES256Tomb::open_container(&data, key)?;
+            let yaml = match AES256Tomb::migrate(&yaml)? {
+                Some(migrated) => Sensitive::new(migrated),
+                None => yaml,
+            };
+            let mut tomb: AES256Tomb = match serde_yaml::from_str(&yaml) {
+                Ok(tomb) => tomb,
+                Err(error) => {
+                    return Err(Error::with_message(format!(
+                        "failed to deserialize tomb container: {}",
+                        error
+                    )))
+                }
+            };
+            tomb.format = TombFormat::Container;
+            tomb.flag_untagged();
+            tomb
+        } else {
+            AES256Tomb::from_yaml(data)?
+        };
+        let data_key = tomb.unlock(key)?;
+        if !tomb.group_only {
+            tomb.unseal(data_key.as_ref().unwrap_or(key))?;
+        }
+        tomb.base = Some(base);
+        Ok(tomb)
+    }
+    /// Unlocks the keys of the shared groups `key` has access to and
+    /// returns the data key when `key` is one of the members. Keys that
+    /// are only members of some groups flag the tomb as `group_only`
+    pub fn unlock(&mut self, key: &Key) -> Result<Option<Key>, Error> {
+        let data_key = match self.unlock_data_key(key) {
+            Ok(data_key) => data_key,
+            Err(error) => match self.unlock_groups(key)? {
+                true => {
+                    self.group_only = true;
+                    None
+                }
+                false => return Err(error),
+            },
+        };
+        let tomb_key = data_key.as_ref().unwrap_or(key);
+        if !self.group_only && tomb_key.digest() != self.digest && self.unlock_groups(key)? {
+            self.group_only = true;
+        }
+        if !self.group_only {
+            self.unlock_groups(tomb_key)?;
+        }
+        Ok(data_key)
+    }
+    /// Stores the tomb in its `format`, sealing its metadata if needed
+    pub fn export_with_key(&self, filename: &str, key: &Key) -> Result<String, Error> {
+        if self.sealed && !self.groups.is_empty() {
+            return Err(Error::with_message(String::from(
+                "the metadata of tombs with shared groups cannot be sealed",
+            )));
+        }
+        let tomb = match self.sealed {
+            true => self.sealed_copy(key)?,
+            false => self.clone(),
+        };
+        match self.format {
+            TombFormat::Yaml => tomb.export(filename),
+            TombFormat::Container if !self.groups.is_empty() => Err(Error::with_message(String::from(
+                "tombs with shared groups are stored as yaml, as group members open them with their own key",
+            ))),
+            TombFormat::Container if self.has_members() => Err(Error::with_message(String::from(
+                "tombs with members are stored as yaml, as every member opens them with their own key",
+            ))),
+            TombFormat::Container => {
+                let yaml = Sensitive::new(tomb.to_yaml()?);
+                let container = Container::seal(&self.config, yaml.as_bytes(), key)?;
+                let filename = absolute_path(filename);
+                match write_atomic(&filename, container.armor().as_bytes()) {
+                    Ok(_) => Ok(filename),
+                    Err(error) => Err(Error::with_message(format!("{}", error))),
+                }
+            }
+        }
+    }
+    /// Keeps the current contents of the tomb file as its newest backup
+    fn backup(filename: &str) -> Result<(), Error> {
+        match rotate_backups(filename, default_backup_generations()) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(Error::with_message(format!("{}", error))),
+        }
+    }
+    /// Locks the tomb file for the duration of a save and rejects it
+    /// when the file changed since this tomb was loaded from it
+    fn lock_for_save(&self, filename: &str) -> Result<FileLock, Error> {
+        let lock = match lock_file(filename) {
+            Ok(lock) => lock,
+            Err(error) => return Err(Error::with_message(format!("{}", error))),
+        };
+        let current = match Path::new(&absolute_path(filename)).exists() {
+            true => Some(content_hash(&AES256Tomb::read_file(filename)?)),
+            false => None,
+        };
+        if current != self.base {
+            return Err(Error::with_message(format!(
+                "{}{}{}",
+                style("tomb file ").color256(198),
+                style(absolute_path(filename)).color256(190),
+                style(" was modified by another process, reload it and try again").color256(198),
+            )));
+        }
+        Ok(lock)
+    }
+    /// The generations and paths of the backups of a tomb file, newest first
+    pub fn backups(filename: &str) -> Vec<(usize, String)> {
+        list_backups(filename)
+    }
+    /// Replaces a tomb file with one of its backups, which keeps the
+    /// replaced contents as the newest backup in turn
+    pub fn restore_backup(filename: &str, generation: usize) -> Result<String, Error> {
+        let backup = backup_filename(filename, generation);
+        let data = AES256Tomb::read_file(&backup)?;
+        if !is_container(&data) {
+            AES256Tomb::from_yaml(data.clone())?;
+        }
+        let _lock = match lock_file(filename) {
+            Ok(lock) => lock,
+            Err(error) => return Err(Error::with_message(format!("{}", error))),
+        };
+        AES256Tomb::backup(filename)?;
+        let filename = absolute_path(filename);
+        match write_atomic(&filename, data.as_bytes()) {
+            Ok(_) => Ok(filename),
+            Err(error) => Err(Error::with_message(format!("{}", error))),
         }
     }
     pub fn set_filepath(&mut self, path: &str) {
@@ -238,13 +1027,94 @@ impl AES256Tomb {
         dolly.set_filepath(path);
         dolly
     }
+    /// Encrypt the metadata of secrets from now on, see `save_with_key`
+    pub fn set_sealed(&mut self, sealed: bool) {
+        self.sealed = sealed
+    }
+    /// Decrypts the metadata of all sealed secrets and indexes them by
+    /// path again, so that `get`, `list` and `delete_secret` work as
+    /// they do for unsealed tombs
+    pub fn unseal(&mut self, key: &Key) -> Result<(), Error> {
+        self.data = unseal_secrets(std::mem::take(&mut self.data), key)?;
+        self.trash = unseal_secrets(std::mem::take(&mut self.trash), key)?;
+        Ok(())
+    }
+    /// A copy of this tomb as stored on disk: metadata encrypted and
+    /// secrets indexed by `path_to_hmac`
+    pub fn sealed_copy(&self, key: &Key) -> Result<AES256Tomb, Error> {
+        let mut dolly = self.clone();
+        dolly.data = seal_secrets(&self.data, key)?;
+        dolly.trash = seal_secrets(&self.trash, key)?;
+        Ok(dolly)
+    }
+    /// Saves the tomb in its format, sealing its metadata when `sealed` is set
+    pub fn save_with_key(&mut self, key: &Key) -> Result<String, Error> {
+        let filepath = match self.filepath.clone() {
+            Some(filepath) => {
+                let _lock = self.lock_for_save(&filepath)?;
+                self.authenticate(key)?;
+                AES256Tomb::backup(&filepath)?;
+                let filepath = self.export_with_key(&filepath, key)?;
+                self.reload_with_key(key)?;
+                filepath
+            }
+            None => {
+                return Err(Error::with_message(format!("attempt to save tomb that does not have a filepath, falling back to TOMB_FILE: {}", default_tomb_filename())));
+            }
+        };
+        Ok(filepath)
+    }
+    /// Reloads the tomb from disk, unsealing its metadata
+    pub fn reload_with_key(&mut self, key: &Key) -> Result<(), Error> {
+        let filepath = match self.filepath.clone() {
+            Some(filepath) => filepath,
+            None => default_tomb_filename(),
+        };
+        let new = match AES256Tomb::import_with_key(&filepath, key) {
+            Ok(fresh_tomb) => fresh_tomb,
+            Err(error) => {
+                return Err(Error::with_message(format!(
+                    "failed to reload tomb from path {}: {}",
+                    filepath, error
+                )))
+            }
+        };
+        self.format_version = new.format_version;
+        self.data = new.data;
+        self.trash = new.trash;
+        self.members = new.members;
+        self.groups = new.groups;
+        self.group_keys = new.group_keys;
+        self.group_only = new.group_only;
+        self.base = new.base;
+        Ok(())
+    }
+    fn ensure_unsealed(&self) -> Result<(), Error> {
+        if self.sealed {
+            return Err(Error::with_message(String::from(
+                "the metadata of this tomb is sealed, the key is required to save or reload it",
+            )));
+        }
+        match self.format {
+            TombFormat::Container => Err(Error::with_message(String::from(
+                "this tomb is an encrypted container, the key is required to save or reload it",
+            ))),
+            TombFormat::Yaml => Ok(()),
+        }
+    }
     pub fn save(&mut self) -> Result<String, Error> {
+        self.ensure_unsealed()?;
         let filepath = match self.filepath.clone() {
-            Some(filepath) => self.export(&filepath)?,
+            Some(filepath) => filepath,
             None => {
                 return Err(Error::with_message(format!("attempt to save tomb that does not have a filepath, falling back to TOMB_FILE: {}", default_tomb_filename())));
             }
         };
+        // held until the saved file is loaded again, so that `base` is
+        // the hash of this save rather than of another process's
+        let _lock = self.lock_for_save(&filepath)?;
+        AES256Tomb::backup(&filepath)?;
+        let filepath = self.export(&filepath)?;
         let new = match AES256Tomb::import(&filepath) {
             Ok(fresh_tomb) => fresh_tomb,
             Err(error) => {
@@ -254,10 +1124,16 @@ impl AES256Tomb {
                 )))
             }
         };
+        self.format_version = new.format_version;
         self.data = new.data.clone();
+        self.trash = new.trash;
+        self.members = new.members;
+        self.groups = new.groups;
+        self.base = new.base;
         Ok(filepath.clone())
     }
     pub fn reload(&mut self) -> Result<(), Error> {
+        self.ensure_unsealed()?;
         let filepath = match self.filepath.clone() {
             Some(filepath) => {
                 // log_error(format!("tomb reloaded: {}", filepath));
@@ -277,7 +1153,12 @@ impl AES256Tomb {
                 )))
             }
         };
+        self.format_version = new.format_version;
         self.data = new.data.clone();
+        self.trash = new.trash;
+        self.members = new.members;
+        self.groups = new.groups;
+        self.base = new.base;
         //log_error(format!("reloaded tomb: {}", filepath));
         Ok(())
     }
@@ -292,28 +1173,109 @@ impl AES256Tomb {
                 )))
             }
         };
-        let mut result = Vec::new();
-        for (md5key, secret) in &self.data {
-            let path = secret.path.clone();
-            if regex.is_match(&path) && (md5key.eq(&secret.key()) || md5key.eq(&path)) {
-                result.push(secret.clone());
-            }
+        Ok(matching_secrets(&self.data, &regex))
+    }
+    /// The deleted secrets whose path matches the glob pattern
+    pub fn list_trash(&self, pattern: &str) -> Result<Vec<AES256Secret>, Error> {
+        match glob_to_regex(pattern) {
+            Ok(regex) => Ok(matching_secrets(&self.trash, &regex)),
+            Err(error) => Err(Error::with_message(format!(
+                "invalid pattern {}\n\t{}",
+                pattern, error
+            ))),
         }
-        Ok(result)
     }
 
+    /// Moves a secret to the trash, see `restore_secret` and `purge_trash`
     pub fn delete_secret(&mut self, path: &str) -> Result<(), Error> {
         let key = path_to_md5(path);
         match self.data.remove(&key) {
-            Some(_) => Ok(()),
+            Some(mut secret) => {
+                secret.deleted_at = Some(Utc::now());
+                self.trash.insert(key, secret);
+                Ok(())
+            }
             None => Err(Error::with_message(format!("key not found {}", path))),
         }
     }
+    /// Moves a deleted secret back from the trash
+    pub fn restore_secret(&mut self, path: &str) -> Result<AES256Secret, Error> {
+        let key = path_to_md5(path);
+        if self.data.contains_key(&key) {
+            return Err(Error::with_message(format!(
+                "{}{}{}",
+                style("cannot restore ").color256(198),
+                style(path).color256(190),
+                style(": a secret with the same path exists").color256(198),
+            )));
+        }
+        match self.trash.remove(&key) {
+            Some(mut secret) => {
+                secret.deleted_at = None;
+                Ok(self.upsert_secret(secret))
+            }
+            None => Err(Error::with_message(format!(
+                "{}{}",
+                style("key (path) not found in trash: ").color256(198),
+                style(path).color256(190),
+            ))),
+        }
+    }
+    /// Moves a secret to `new_path`, keeping its encrypted value, its
+    /// history and `created_at`
+    pub fn rename_secret(&mut self, path: &str, new_path: &str) -> Result<AES256Secret, Error> {
+        let key = path_to_md5(path);
+        let new_key = path_to_md5(new_path);
+        if !self.data.contains_key(&key) {
+            return Err(Error::with_message(format!(
+                "{}{}",
+                style("key (path) not found: ").color256(198),
+                style(path).color256(190),
+            )));
+        }
+        if self.data.contains_key(&new_key) {
+            return Err(Error::with_message(format!(
+                "{}{}{}",
+                style("cannot rename to ").color256(198),
+                style(new_path).color256(190),
+                style(": a secret with the same path exists").color256(198),
+            )));
+        }
+        // the value stays encrypted with the key of its shared group
+        let (group, new_group) = (self.shared_group(path), self.shared_group(new_path));
+        if group != new_group {
+            return Err(Error::with_message(format!(
+                "{}{}{}{}",
+                style("cannot move ").color256(198),
+                style(path).color256(190),
+                style(" in or out of the shared group ").color256(198),
+                style(group.or(new_group).unwrap_or_default()).color256(190),
+            )));
+        }
+        let mut secret = self.data.remove(&key).unwrap();
+        secret.path = String::from(new_path);
+        secret.updated_at = Utc::now();
+        Ok(self.upsert_secret(secret))
+    }
+    /// Permanently removes the secrets deleted longer than `older_than`
+    /// ago, returning them
+    pub fn purge_trash(&mut self, older_than: Duration) -> Vec<AES256Secret> {
+        let deadline = Utc::now() - older_than;
+        let mut purged = Vec::new();
+        self.trash.retain(|_, secret| match secret.deleted_at {
+            Some(deleted_at) if deleted_at > deadline => true,
+            _ => {
+                purged.push(secret.clone());
+                false
+            }
+        });
+        purged
+    }
     pub fn add_secret(
         &mut self,
         path: &str,
         plaintext: String,
-        key: Key,
+        key: &Key,
     ) -> Result<AES256Secret, Error> {
         self.add_secret_from_bytes(path, Vec::from(plaintext), key)
     }
@@ -327,8 +1289,15 @@ impl AES256Tomb {
         &mut self,
         path: &str,
         plaintext: Vec<u8>,
-        key: Key,
+        key: &Key,
     ) -> Result<AES256Secret, Error> {
+        let key = self.key_for(path, key)?;
+        if let Ok(mut secret) = self.get(path) {
+            secret.update(String::from(path), plaintext, key)?;
+            secret.prune_history(self.history_retention);
+            return Ok(self.upsert_secret(secret));
+        }
+        let plaintext = Sensitive::new(plaintext);
         let ciphertext = match key.encrypt(&plaintext) {
             Ok(cypher) => cypher,
             Err(error) => {
@@ -342,8 +1311,46 @@ impl AES256Tomb {
         Ok(self.upsert_secret(AES256Secret::new(String::from(path), ciphertext, key)))
     }
 
-    pub fn derive_key(&self, password: &str) -> Key {
-        Key::from_password(password, &self.config)
+    /// Re-encrypts every secret from the old key to the new one, the
+    /// secrets of shared groups keep their group key which is encrypted
+    /// with the new key instead.
+    ///
+    /// The tomb is left untouched if any secret fails to decrypt with
+    /// the old key, it is up to the caller to save it afterwards.
+    pub fn rekey(&mut self, old: &Key, new: &Key) -> Result<(), Error> {
+        let filter = |path: &str| self.shared_group(path).is_none();
+        let data = rekey_secrets(&self.data, old, new, filter)?;
+        let trash = rekey_secrets(&self.trash, old, new, filter)?;
+        let groups = self.rekey_groups(old, new)?;
+        self.data = data;
+        self.trash = trash;
+        self.groups = groups;
+        self.digest = new.digest();
+        Ok(())
+    }
+
+    /// Re-encrypts every secret with a key derived from `password` and
+    /// a new random salt, which also moves tombs whose salt is derived
+    /// from the password to a random one. Returns the new key
+    pub fn rekey_with_password(&mut self, old: &Key, password: &str) -> Result<Key, Error> {
+        let config = self.config.with_random_salt();
+        let new = match Key::from_password(password, &config) {
+            Ok(key) => key,
+            Err(error) => return Err(Error::with_message(format!("{}", error))),
+        };
+        self.rekey(old, &new)?;
+        self.config = config;
+        Ok(new)
+    }
+
+    pub fn derive_key(&self, password: &str) -> Result<Key, Error> {
+        match Key::from_password(password, &self.config) {
+            Ok(key) => Ok(key),
+            Err(error) => Err(Error::with_message(format!(
+                "cannot derive key from password: {}",
+                error
+            ))),
+        }
     }
 
     pub fn get(&self, path: &str) -> Result<AES256Secret, Error> {
@@ -367,58 +1374,86 @@ impl AES256Tomb {
             ))),
         }
     }
-    pub fn get_base64_string(&self, path: &str, key: Key) -> Result<String, Error> {
-        self.get(path)?.get_base64_string(path, key)
+    pub fn get_base64_string(&self, path: &str, key: &Key) -> Result<SensitiveString, Error> {
+        self.get(path)?
+            .get_base64_string(path, self.key_for(path, key)?)
     }
-    pub fn get_string(&self, path: &str, key: Key) -> Result<String, Error> {
-        self.get(path)?.get_string(path, key)
+    pub fn get_string(&self, path: &str, key: &Key) -> Result<SensitiveString, Error> {
+        self.get(path)?.get_string(path, self.key_for(path, key)?)
     }
-    pub fn get_bytes(&self, path: &str, key: Key) -> Result<Vec<u8>, Error> {
-        self.get(path)?.get_bytes(path, key)
+    pub fn get_bytes(&self, path: &str, key: &Key) -> Result<SensitiveBytes, Error> {
+        self.get(path)?.get_bytes(path, self.key_for(path, key)?)
+    }
+    /// The given version of a secret, see `AES256Secret::get_version_bytes`
+    pub fn get_version_string(
+        &self,
+        path: &str,
+        version: usize,
+        key: &Key,
+    ) -> Result<SensitiveString, Error> {
+        match self
+            .get(path)?
+            .get_version_bytes(path, version, self.key_for(path, key)?)?
+            .into_string()
+        {
+            Ok(plaintext) => Ok(plaintext),
+            Err(error) => Err(Error::with_message(format!(
+                "version {} of {} is not valid utf-8: {}",
+                version, path, error
+            ))),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::aes256cbc::Config as AesConfig;
-    use crate::aes256cbc::Key;
-    use crate::tomb::{AES256Secret, AES256Tomb};
+    use crate::aes256cbc::{Kdf, Key};
+    use crate::config::YamlFile;
+    use crate::ioutils::{b64decode, b64encode};
+    use crate::migrations::{
+        migration_backup_filename, TOMB_AUTHENTICATED_VERSION, TOMB_FORMAT_VERSION,
+    };
+    use crate::tomb::testing::TempDir;
+    use crate::tomb::{AES256Secret, AES256Tomb, TombFormat};
+    use chrono::Duration;
     use k9::assert_equal;
 
     fn generate_key() -> (Key, AesConfig) {
         let config = AesConfig::builtin(None);
         let password = "123456";
-        (Key::from_password(password, &config), config)
+        (Key::from_password(password, &config).unwrap(), config)
     }
     #[test]
     fn test_create_tomb_and_manage_secrets() {
         let (key, config) = generate_key();
 
-        let mut tomb = AES256Tomb::new("test-create-tomb.yaml", key.clone(), config);
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
         tomb.add_secret_from_bytes(
             "my-secret",
             Vec::from("some bytes"),
-            tomb.derive_key("123456"),
+            &tomb.derive_key("123456").unwrap(),
         )
         .expect("secret should be added");
         tomb.add_secret(
             "another-secret",
             String::from("more bytes"),
-            tomb.derive_key("123456"),
+            &tomb.derive_key("123456").unwrap(),
         )
         .expect("secret should be added");
 
         let plaintext = tomb
-            .get_bytes("my-secret", tomb.derive_key("123456"))
+            .get_bytes("my-secret", &tomb.derive_key("123456").unwrap())
             .expect("secret should have been stored by previous statement(s)");
 
-        assert_equal!(plaintext, Vec::from("some bytes"));
+        assert_equal!(*plaintext, Vec::from("some bytes"));
 
         let plaintext = tomb
-            .get_string("another-secret", tomb.derive_key("123456"))
+            .get_string("another-secret", &tomb.derive_key("123456").unwrap())
             .expect("secret should have been stored by previous statement(s)");
 
-        assert_equal!(plaintext, String::from("more bytes"));
+        assert_equal!(*plaintext, String::from("more bytes"));
 
         let secrets = tomb.list("*").expect("failed to list *");
         assert_equal!(secrets.len(), 2);
@@ -430,16 +1465,397 @@ mod tests {
         assert_equal!(last.len(), 1);
     }
     #[test]
+    fn test_sealed_metadata() {
+        let (key, config) = generate_key();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        tomb.set_sealed(true);
+        let mut secret = tomb
+            .add_secret("personal/netflix", String::from("123456"), &key)
+            .expect("secret should be added");
+        tomb.upsert_secret(secret.with_username(Some(String::from("myuser"))));
+
+        let sealed = tomb.sealed_copy(&key).unwrap();
+        let yaml = sealed.to_yaml().unwrap();
+        assert_equal!(yaml.contains("netflix"), false);
+        assert_equal!(yaml.contains("myuser"), false);
+        assert_equal!(sealed.list("*").unwrap().len(), 0);
+
+        let mut unsealed = AES256Tomb::from_yaml(yaml).unwrap();
+        assert_equal!(unsealed.save().is_err(), true);
+        unsealed.unseal(&key).unwrap();
+        let secret = unsealed.get("personal/netflix").unwrap();
+        assert_equal!(secret.username, Some(String::from("myuser")));
+        assert_equal!(
+            unsealed
+                .get_string("personal/netflix", &key)
+                .unwrap()
+                .as_str(),
+            "123456"
+        );
+        assert_equal!(unsealed.list("personal/*").unwrap().len(), 1);
+    }
+    #[test]
+    fn test_container_format() {
+        let (key, config) = generate_key();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        let filename = &directory.filename("tomb.yaml");
+        tomb.format = TombFormat::Container;
+        tomb.add_secret("personal/netflix", String::from("123456"), &key)
+            .expect("secret should be added");
+        tomb.save_with_key(&key).expect("container should be saved");
+
+        let armored = std::fs::read_to_string(filename).unwrap();
+        assert_equal!(armored.contains("netflix"), false);
+        assert_equal!(AES256Tomb::import(filename).is_err(), true);
+        assert_equal!(tomb.save().is_err(), true);
+
+        let opened = AES256Tomb::import_with_key(filename, &key).unwrap();
+        assert_equal!(opened.format, TombFormat::Container);
+        assert_equal!(
+            AES256Tomb::import_config(filename).unwrap() == opened.config,
+            true
+        );
+        assert_equal!(
+            opened
+                .get_string("personal/netflix", &key)
+                .unwrap()
+                .as_str(),
+            "123456"
+        );
+
+        // flip the first character of the last base64 line
+        let mut lines: Vec<String> = armored.lines().map(String::from).collect();
+        let last = lines.len() - 2;
+        let flipped = if lines[last].starts_with('A') {
+            "B"
+        } else {
+            "A"
+        };
+        lines[last].replace_range(..1, flipped);
+        std::fs::write(filename, lines.join("\n")).unwrap();
+        assert_equal!(AES256Tomb::import_with_key(filename, &key).is_err(), true);
+    }
+    #[test]
+    fn test_stale_save_is_rejected() {
+        let (key, config) = generate_key();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        let filename = &directory.filename("tomb.yaml");
+        tomb.add_secret("personal/netflix", String::from("123456"), &key)
+            .expect("secret should be added");
+        tomb.save().expect("tomb should be saved");
+
+        let mut ui = tomb.clone();
+        let mut cli = tomb.clone();
+        cli.add_secret("personal/spotify", String::from("987654"), &key)
+            .expect("secret should be added");
+        cli.save().expect("tomb should be saved");
+
+        ui.add_secret("personal/email", String::from("abcdef"), &key)
+            .expect("secret should be added");
+        assert_equal!(ui.save().is_err(), true);
+        assert_equal!(ui.save_with_key(&key).is_err(), true);
+
+        ui.reload().expect("tomb should be reloaded");
+        ui.add_secret("personal/email", String::from("abcdef"), &key)
+            .expect("secret should be added");
+        ui.save().expect("reloaded tomb should be saved");
+
+        let stored = AES256Tomb::import(filename).unwrap();
+        assert_equal!(stored.list("personal/*").unwrap().len(), 3);
+    }
+    #[test]
+    fn test_unversioned_tomb_is_migrated_on_import() {
+        let (key, config) = generate_key();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        let filename = &directory.filename("tomb.yaml");
+        tomb.add_secret("personal/netflix", String::from("123456"), &key)
+            .expect("secret should be added");
+        let yaml = tomb
+            .to_yaml()
+            .unwrap()
+            .replace(&format!("format_version: {}\n", TOMB_FORMAT_VERSION), "");
+        std::fs::write(filename, &yaml).unwrap();
+        assert_equal!(
+            AES256Tomb::file_format_version(filename, None).unwrap(),
+            Some(1)
+        );
+        let backup = migration_backup_filename(filename, 1);
+
+        // reading migrates in memory only
+        let read = AES256Tomb::read(filename).expect("tomb should be migrated in memory");
+        assert_equal!(read.format_version, TOMB_AUTHENTICATED_VERSION - 1);
+        assert_equal!(std::fs::read_to_string(filename).unwrap(), yaml);
+        assert_equal!(std::path::Path::new(&backup).exists(), false);
+
+        let mut stored = AES256Tomb::import(filename).expect("tomb should be migrated");
+        assert_equal!(stored.format_version, TOMB_AUTHENTICATED_VERSION - 1);
+        assert_equal!(
+            stored
+                .get_string("personal/netflix", &key)
+                .unwrap()
+                .as_str(),
+            "123456"
+        );
+        assert_equal!(
+            AES256Tomb::file_format_version(filename, None).unwrap(),
+            Some(TOMB_AUTHENTICATED_VERSION - 1)
+        );
+        // values are re-encrypted with the key to reach the current version
+        stored.save_with_key(&key).unwrap();
+        assert_equal!(
+            AES256Tomb::file_format_version(filename, None).unwrap(),
+            Some(TOMB_FORMAT_VERSION)
+        );
+        assert_equal!(std::fs::read_to_string(&backup).unwrap(), yaml);
+    }
+    #[test]
+    fn test_import_v1_tomb() {
+        let yaml = String::from(include_str!("fixtures/tomb-v1.yaml"));
+        let mut tomb = AES256Tomb::from_yaml(yaml).expect("tomb should be migrated");
+        assert_equal!(tomb.format_version, TOMB_AUTHENTICATED_VERSION - 1);
+        assert_equal!(tomb.config.kdf, Kdf::Pbkdf2);
+        assert_equal!(tomb.config.has_legacy_salt(), true);
+        assert_equal!(tomb.trash.len(), 0);
+
+        let key = tomb.derive_key("123456").unwrap();
+        assert_equal!(tomb.digest, key.digest());
+        let netflix = tomb.get("personal/netflix").unwrap();
+        assert_equal!(netflix.username, Some(String::from("myuser")));
+        assert_equal!(netflix.history.len(), 0);
+        assert_equal!(
+            tomb.get_string("personal/netflix", &key).unwrap().as_str(),
+            "123456"
+        );
+
+        tomb.authenticate(&key).unwrap();
+        assert_equal!(tomb.format_version, TOMB_FORMAT_VERSION);
+        let aws = tomb.get("work/aws").unwrap();
+        assert_equal!(key.is_tagged(&aws.value_bytes()), true);
+        assert_equal!(
+            tomb.get_string("work/aws", &key).unwrap().as_str(),
+            "987654"
+        );
+    }
+    #[test]
+    fn test_authenticate_untagged_values() {
+        let (key, config) = generate_key();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        let mut secret = tomb
+            .add_secret("personal/netflix", String::from("123456"), &key)
+            .unwrap();
+        secret.value = b64encode(&key.encrypt_untagged(b"123456").unwrap());
+        tomb.upsert_secret(secret);
+        assert_equal!(tomb.get_string("personal/netflix", &key).is_err(), true);
+
+        let yaml = tomb.to_yaml().unwrap().replace(
+            &format!("format_version: {}", TOMB_FORMAT_VERSION),
+            &format!("format_version: {}", TOMB_AUTHENTICATED_VERSION - 1),
+        );
+        let mut tomb = AES256Tomb::from_yaml(yaml).unwrap();
+        assert_equal!(
+            tomb.get_string("personal/netflix", &key).unwrap().as_str(),
+            "123456"
+        );
+        tomb.authenticate(&key).unwrap();
+        assert_equal!(tomb.format_version, TOMB_FORMAT_VERSION);
+        let mut secret = tomb.get("personal/netflix").unwrap();
+        assert_equal!(key.is_tagged(&secret.value_bytes()), true);
+        assert_equal!(
+            tomb.get_string("personal/netflix", &key).unwrap().as_str(),
+            "123456"
+        );
+
+        // stripped of magic, version and tag the value reads as legacy
+        let value = b64decode(secret.value.as_bytes()).unwrap();
+        secret.value = b64encode(&value[4..value.len() - 32]);
+        tomb.upsert_secret(secret);
+        let error = tomb.get_string("personal/netflix", &key).unwrap_err();
+        assert_equal!(error.message.contains("Tampered"), true);
+    }
+    #[test]
+    fn test_rekey() {
+        let (old, config) = generate_key();
+        let new = Key::generate();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&old, config);
+        tomb.add_secret("my-secret", String::from("some bytes"), &old)
+            .expect("secret should be added");
+        tomb.add_secret("another-secret", String::from("more bytes"), &old)
+            .expect("secret should be added");
+
+        let unchanged = tomb.clone();
+        assert_equal!(tomb.rekey(&new, &old).is_err(), true);
+        assert_equal!(tomb == unchanged, true);
+
+        tomb.rekey(&old, &new).expect("tomb should be rekeyed");
+        assert_equal!(tomb.digest, new.digest());
+        assert_equal!(tomb.get_string("my-secret", &old).is_err(), true);
+        assert_equal!(
+            tomb.get_string("another-secret", &new).unwrap().as_str(),
+            "more bytes"
+        );
+        assert_equal!(tomb.get("my-secret").unwrap().digest, new.digest());
+    }
+    #[test]
+    fn test_rekey_legacy_salt_with_the_same_password() {
+        let (key, config) = generate_key();
+        assert_equal!(config.has_legacy_salt(), true);
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        let filename = &directory.filename("tomb.yaml");
+        tomb.add_secret("my-secret", String::from("some bytes"), &key)
+            .expect("secret should be added");
+        tomb.save_with_key(&key).unwrap();
+
+        let new = tomb.rekey_with_password(&key, "123456").unwrap();
+        tomb.save_with_key(&new).unwrap();
+
+        let config = AES256Tomb::import_config(filename).unwrap();
+        assert_equal!(config.has_legacy_salt(), false);
+        let reopened = Key::from_password("123456", &config).unwrap();
+        assert_equal!(reopened.digest(), new.digest());
+        let stored = AES256Tomb::import_with_key(filename, &reopened).unwrap();
+        assert_equal!(
+            stored.get_string("my-secret", &reopened).unwrap().as_str(),
+            "some bytes"
+        );
+    }
+    #[test]
+    fn test_secret_history() {
+        let (old, config) = generate_key();
+        let new = Key::generate();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&old, config);
+        tomb.history_retention = 2;
+        for value in ["first", "second", "third", "fourth"] {
+            tomb.add_secret("netflix", String::from(value), &old)
+                .expect("secret should be added");
+        }
+        let secret = tomb.get("netflix").unwrap();
+        assert_equal!(secret.history.len(), 2);
+        assert_equal!(
+            tomb.get_version_string("netflix", 0, &old)
+                .unwrap()
+                .as_str(),
+            "fourth"
+        );
+        assert_equal!(
+            tomb.get_version_string("netflix", 2, &old)
+                .unwrap()
+                .as_str(),
+            "second"
+        );
+        assert_equal!(tomb.get_version_string("netflix", 3, &old).is_err(), true);
+
+        tomb.rekey(&old, &new).expect("tomb should be rekeyed");
+        assert_equal!(
+            tomb.get_version_string("netflix", 1, &new)
+                .unwrap()
+                .as_str(),
+            "third"
+        );
+    }
+    #[test]
+    fn test_trash() {
+        let (key, config) = generate_key();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        let filename = &directory.filename("tomb.yaml");
+        tomb.add_secret("personal/netflix", String::from("123456"), &key)
+            .expect("secret should be added");
+        tomb.add_secret("personal/spotify", String::from("987654"), &key)
+            .expect("secret should be added");
+
+        tomb.delete_secret("personal/netflix").unwrap();
+        tomb.delete_secret("personal/spotify").unwrap();
+        assert_equal!(tomb.list("*").unwrap().len(), 0);
+        assert_equal!(tomb.list_trash("personal/*").unwrap().len(), 2);
+
+        tomb.set_sealed(true);
+        tomb.save_with_key(&key).expect("tomb should be saved");
+        let armored = std::fs::read_to_string(filename).unwrap();
+        assert_equal!(armored.contains("netflix"), false);
+        let mut tomb = AES256Tomb::import_with_key(filename, &key).unwrap();
+
+        let restored = tomb.restore_secret("personal/netflix").unwrap();
+        assert_equal!(restored.deleted_at, None);
+        assert_equal!(
+            tomb.get_string("personal/netflix", &key).unwrap().as_str(),
+            "123456"
+        );
+        assert_equal!(tomb.restore_secret("personal/netflix").is_err(), true);
+
+        assert_equal!(tomb.purge_trash(Duration::days(1)).len(), 0);
+        let purged = tomb.purge_trash(Duration::zero());
+        assert_equal!(purged.len(), 1);
+        assert_equal!(purged[0].path, "personal/spotify");
+        assert_equal!(tomb.list_trash("*").unwrap().len(), 0);
+    }
+    #[test]
+    fn test_rename_secret() {
+        let (key, config) = generate_key();
+        let directory = TempDir::new();
+        let mut tomb = directory.tomb(&key, config);
+        tomb.add_secret("personal/netflix", String::from("123456"), &key)
+            .expect("secret should be added");
+        tomb.add_secret("personal/spotify", String::from("987654"), &key)
+            .expect("secret should be added");
+        let original = tomb.get("personal/netflix").unwrap();
+
+        assert_equal!(
+            tomb.rename_secret("personal/netflix", "personal/spotify")
+                .is_err(),
+            true
+        );
+        let renamed = tomb
+            .rename_secret("personal/netflix", "work/netflix")
+            .unwrap();
+        assert_equal!(renamed.value, original.value);
+        assert_equal!(renamed.created_at, original.created_at);
+        assert_equal!(tomb.get("personal/netflix").is_err(), true);
+        assert_equal!(
+            tomb.get_string("work/netflix", &key).unwrap().as_str(),
+            "123456"
+        );
+        assert_equal!(tomb.list("work/*").unwrap().len(), 1);
+    }
+    #[test]
     fn test_secret_group() {
         let (key, _) = generate_key();
 
         assert_equal!(
-            AES256Secret::new("/foo/bar".to_string(), Vec::new(), key.clone()).group(),
+            AES256Secret::new("/foo/bar".to_string(), Vec::new(), &key).group(),
             String::from("/foo")
         );
         assert_equal!(
-            AES256Secret::new("/bar".to_string(), Vec::new(), key.clone()).group(),
+            AES256Secret::new("/bar".to_string(), Vec::new(), &key).group(),
             String::from("/")
         );
     }
+    #[test]
+    fn test_secret_field() {
+        let (key, _) = generate_key();
+        let mut secret = AES256Secret::new("/work/aws".to_string(), Vec::new(), &key)
+            .with_username(Some(String::from("bob")));
+        secret
+            .attributes
+            .as_mut()
+            .unwrap()
+            .insert(String::from("region"), String::from("eu-west-1"));
+
+        assert_equal!(secret.field("username").unwrap(), Some(String::from("bob")));
+        assert_equal!(secret.field("group").unwrap(), Some(String::from("/work")));
+        assert_equal!(secret.field("url").unwrap(), None);
+        assert_equal!(
+            secret.field("attributes.region").unwrap(),
+            Some(String::from("eu-west-1"))
+        );
+        assert_equal!(secret.field("attributes.zone").unwrap(), None);
+        assert_equal!(secret.field("password").is_err(), true);
+    }
 }
@@ -0,0 +1,47 @@
+//! Fixtures shared by the tests of the tomb modules
+use super::AES256Tomb;
+use crate::aes256cbc::{Config as AesConfig, Key};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs};
+
+static TEMP_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory private to a test, removed along with the tomb, lock
+/// and backup files in it when dropped, even if the test panics
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = env::temp_dir().join(format!(
+            "tomb-test-{}-{}",
+            std::process::id(),
+            TEMP_DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+    pub fn filename(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().to_string()
+    }
+    /// Empty tomb stored as `tomb.yaml` in the directory
+    pub fn tomb(&self, key: &Key, config: AesConfig) -> AES256Tomb {
+        AES256Tomb::new(&self.filename("tomb.yaml"), key, config)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Empty tomb with the builtin config in a new temporary directory,
+/// which must outlive the tomb
+pub fn new_tomb(key: &Key) -> (TempDir, AES256Tomb) {
+    let directory = TempDir::new();
+    let tomb = directory.tomb(key, AesConfig::builtin(None));
+    (directory, tomb)
+}
@@ -0,0 +1,283 @@
+//! Integrity checks of the secrets of a tomb
+//!
+//! Secrets are indexed by `path_to_md5` of their path, entries whose
+//! map key does not match their path are skipped by
+//! `AES256Tomb::list` and shadow or duplicate other secrets.
+//! `AES256Tomb::verify` reports those along with values that cannot
+//! be decrypted with the key, and `AES256Tomb::repair` rekeys the map.
+use super::{path_to_md5, AES256Secret, AES256Tomb, SecretVersion};
+use crate::aes256cbc::Key;
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Problem {
+    /// The map key is not `path_to_md5` of the path
+    InconsistentKey,
+    /// Encrypted with another key than the one of the tomb
+    ForeignDigest,
+    /// The current value or one of the previous ones cannot be decrypted
+    Undecryptable(String),
+    /// The current value is not valid utf-8, so `tomb get` cannot print
+    /// it, which is expected of binary secrets
+    InvalidUtf8,
+    /// Another entry holds a secret with the same path
+    DuplicatePath,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Issue {
+    pub key: String,
+    pub path: String,
+    /// Whether the entry is in the trash rather than in the secrets
+    pub trashed: bool,
+    pub problem: Problem,
+}
+
+impl Issue {
+    pub fn describe(&self) -> String {
+        let problem = match &self.problem {
+            Problem::InconsistentKey => {
+                format!("is stored under the inconsistent key {}", self.key)
+            }
+            Problem::ForeignDigest => String::from("is encrypted with another key"),
+            Problem::Undecryptable(error) => format!("cannot be decrypted: {}", error),
+            Problem::InvalidUtf8 => String::from("is not valid utf-8"),
+            Problem::DuplicatePath => format!("is duplicated under the key {}", self.key),
+        };
+        match self.trashed {
+            true => format!("{} (trash) {}", self.path, problem),
+            false => format!("{} {}", self.path, problem),
+        }
+    }
+    /// Whether the tomb is inconsistent, rather than a secret only
+    /// being unprintable
+    pub fn is_failure(&self) -> bool {
+        self.problem != Problem::InvalidUtf8
+    }
+    /// Whether `AES256Tomb::repair` fixes it
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            self.problem,
+            Problem::InconsistentKey | Problem::DuplicatePath
+        )
+    }
+}
+
+impl AES256Tomb {
+    /// Checks every secret and trashed secret, the tomb is consistent
+    /// when no issues are returned
+    pub fn verify(&self, key: &Key) -> Vec<Issue> {
+        let mut issues = verify_secrets(self, &self.data, key, false);
+        issues.extend(verify_secrets(self, &self.trash, key, true));
+        issues
+    }
+    /// Indexes secrets by `path_to_md5` of their path, keeping the most
+    /// recently updated of duplicated secrets whose values are kept in
+    /// its history, and returns the issues that remain. Trashed secrets
+    /// with the path of a secret become previous values of it when
+    /// encrypted with the same key, and stay in the trash otherwise
+    pub fn repair(&mut self, key: &Key) -> Vec<Issue> {
+        self.data = rekey_map(std::mem::take(&mut self.data));
+        self.trash = rekey_map(std::mem::take(&mut self.trash));
+        let data = &mut self.data;
+        self.trash
+            .retain(|md5key, trashed| match data.get_mut(md5key) {
+                Some(secret) if secret.digest == trashed.digest => {
+                    keep_history(secret, trashed.clone());
+                    false
+                }
+                _ => true,
+            });
+        self.verify(key)
+    }
+}
+
+/// Secrets of shared groups are checked against the group key, and
+/// skipped when the key has no access to their group
+fn verify_secrets(
+    tomb: &AES256Tomb,
+    secrets: &BTreeMap<String, AES256Secret>,
+    key: &Key,
+    trashed: bool,
+) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut paths: BTreeMap<&str, Vec<&String>> = BTreeMap::new();
+    for (md5key, secret) in secrets {
+        paths.entry(secret.path.as_str()).or_default().push(md5key);
+        let issue = |problem: Problem| Issue {
+            key: md5key.clone(),
+            path: secret.path.clone(),
+            trashed,
+            problem,
+        };
+        if md5key != &secret.key() {
+            issues.push(issue(Problem::InconsistentKey));
+        }
+        let key = match tomb.key_for(&secret.path, key) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        if secret.digest != key.digest() {
+            issues.push(issue(Problem::ForeignDigest));
+        }
+        match secret.get_bytes(&secret.path, key) {
+            Ok(plaintext) => {
+                if std::str::from_utf8(&plaintext).is_err() {
+                    issues.push(issue(Problem::InvalidUtf8));
+                }
+            }
+            Err(error) => issues.push(issue(Problem::Undecryptable(error.message))),
+        }
+        for version in 1..=secret.history.len() {
+            if let Err(error) = secret.get_version_bytes(&secret.path, version, key) {
+                issues.push(issue(Problem::Undecryptable(error.message)));
+            }
+        }
+    }
+    for (path, keys) in paths {
+        if keys.len() < 2 {
+            continue;
+        }
+        for md5key in keys {
+            issues.push(Issue {
+                key: md5key.clone(),
+                path: String::from(path),
+                trashed,
+                problem: Problem::DuplicatePath,
+            });
+        }
+    }
+    issues
+}
+
+fn rekey_map(secrets: BTreeMap<String, AES256Secret>) -> BTreeMap<String, AES256Secret> {
+    let mut rekeyed: BTreeMap<String, AES256Secret> = BTreeMap::new();
+    for secret in secrets.into_values() {
+        let md5key = path_to_md5(&secret.path);
+        let merged = match rekeyed.remove(&md5key) {
+            Some(other) => merge_duplicates(secret, other),
+            None => secret,
+        };
+        rekeyed.insert(md5key, merged);
+    }
+    rekeyed
+}
+
+/// Keeps the most recently updated secret, the value of the other one
+/// and its history become previous values when encrypted with the same key
+fn merge_duplicates(a: AES256Secret, b: AES256Secret) -> AES256Secret {
+    let (mut newest, other) = match b.updated_at > a.updated_at {
+        true => (b, a),
+        false => (a, b),
+    };
+    keep_history(&mut newest, other);
+    newest
+}
+
+/// Adds the value of `other` and its history to the previous values of
+/// `secret`, when both are encrypted with the same key
+fn keep_history(secret: &mut AES256Secret, other: AES256Secret) {
+    if other.digest != secret.digest {
+        return;
+    }
+    let previous = std::iter::once(SecretVersion {
+        value: other.value,
+        updated_at: other.updated_at,
+    });
+    for version in previous.chain(other.history) {
+        if version.value != secret.value && !secret.history.contains(&version) {
+            secret.history.push(version);
+        }
+    }
+    secret
+        .history
+        .sort_by_key(|version| std::cmp::Reverse(version.updated_at));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Problem;
+    use crate::aes256cbc::Key;
+    use crate::tomb::path_to_md5;
+    use crate::tomb::testing::new_tomb;
+    use k9::assert_equal;
+
+    #[test]
+    fn test_verify_and_repair() {
+        let key = Key::generate();
+        let (_directory, mut tomb) = new_tomb(&key);
+        tomb.add_secret("netflix", String::from("123456"), &key)
+            .unwrap();
+        tomb.add_secret("spotify", String::from("987654"), &key)
+            .unwrap();
+        assert_equal!(tomb.verify(&key), vec![]);
+
+        let mut duplicate = tomb.get("netflix").unwrap();
+        duplicate
+            .update(String::from("netflix"), b"654321".to_vec(), &key)
+            .unwrap();
+        tomb.data.insert(String::from("netflix"), duplicate);
+        let spotify = tomb.data.remove(&path_to_md5("spotify")).unwrap();
+        tomb.data.insert(String::from("spotify"), spotify);
+        tomb.add_secret_from_bytes("binary", vec![0xff, 0xfe], &key)
+            .unwrap();
+
+        let problems: Vec<Problem> = tomb.verify(&key).into_iter().map(|i| i.problem).collect();
+        assert_equal!(
+            problems,
+            vec![
+                Problem::InvalidUtf8,
+                Problem::InconsistentKey,
+                Problem::InconsistentKey,
+                Problem::DuplicatePath,
+                Problem::DuplicatePath,
+            ]
+        );
+
+        let remaining = tomb.repair(&key);
+        assert_equal!(remaining.len(), 1);
+        assert_equal!(remaining[0].problem, Problem::InvalidUtf8);
+        assert_equal!(remaining[0].is_failure(), false);
+        assert_equal!(tomb.list("*").unwrap().len(), 3);
+        assert_equal!(tomb.get_string("netflix", &key).unwrap().as_str(), "654321");
+        assert_equal!(
+            tomb.get_version_string("netflix", 1, &key)
+                .unwrap()
+                .as_str(),
+            "123456"
+        );
+        assert_equal!(tomb.get("netflix").unwrap().history.len(), 1);
+
+        let other = Key::generate();
+        let problems: Vec<Problem> = tomb.verify(&other).into_iter().map(|i| i.problem).collect();
+        assert_equal!(problems.contains(&Problem::ForeignDigest), true);
+    }
+    #[test]
+    fn test_repair_keeps_trashed_values() {
+        let key = Key::generate();
+        let (_directory, mut tomb) = new_tomb(&key);
+        tomb.add_secret("netflix", String::from("123456"), &key)
+            .unwrap();
+        tomb.delete_secret("netflix").unwrap();
+        tomb.add_secret("netflix", String::from("654321"), &key)
+            .unwrap();
+        let mut foreign = tomb.get("netflix").unwrap();
+        foreign.path = String::from("spotify");
+        foreign.digest = Key::generate().digest();
+        tomb.trash.insert(path_to_md5("spotify"), foreign.clone());
+        foreign.digest = key.digest();
+        tomb.data.insert(path_to_md5("spotify"), foreign);
+
+        tomb.repair(&key);
+        assert_equal!(tomb.get_string("netflix", &key).unwrap().as_str(), "654321");
+        assert_equal!(
+            tomb.get_version_string("netflix", 1, &key)
+                .unwrap()
+                .as_str(),
+            "123456"
+        );
+        // trashed secrets encrypted with another key are kept as they are
+        assert_equal!(tomb.trash.len(), 1);
+        assert_equal!(tomb.trash.contains_key(&path_to_md5("spotify")), true);
+    }
+}